@@ -1,47 +1,110 @@
 mod postprocess;
 
 use std::{
+    cmp::Reverse,
     collections::BTreeMap,
     error::Error,
-    ops::{Deref, DerefMut},
-    sync::{Arc, Mutex},
+    ffi::CStr,
+    ops::Deref,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
     time::Instant,
 };
 
-use cubiomes::generator::{Cache, Generator, Range, Scale};
+use cubiomes::{
+    enums::{Dimension, MCVersion},
+    generator::{Cache, Generator, Range, Scale, error::GeneratorError},
+    noise::{BiomeNoise, SurfaceNoiseRelease},
+};
 use image::GrayAlphaImage;
 use log::debug;
 use postprocess::{
-    concat_lower_zoom, draw_contours, draw_shading, generate_heightmap, get_image,
-    upsacale_blockscale,
+    concat_lower_zoom, draw_contours, draw_shading, generate_heightmap, get_image, mask_void,
+    range_y, upsacale_blockscale,
 };
+use serde::Serialize;
 
-use crate::tileprovider::{TilePos, TileProvider};
+use crate::tileprovider::{ProviderError, TilePos, TileProvider, render_scaled};
 
-pub struct CachePool<'pool> {
-    generator: &'pool Generator,
-    caches: Arc<Mutex<BTreeMap<Scale, Vec<Cache<'pool>>>>>,
+/// The y level biomes are sampled at in 2d dimensions (the overworld surface
+/// and the end).
+pub const SURFACE_Y_LEVEL: i32 = 320;
+
+/// The most zoomed out level tiles are rendered at, where a pixel is 256
+/// blocks.
+pub const MIN_ZOOM: i32 = -8;
+
+/// The zoom level at which a tile shows a single block, the most zoomed in
+/// level tiles are rendered at.
+pub const MAX_ZOOM: i32 = 8;
+
+/// The width and height of the caches of a [CachePool], in cells of their
+/// scale.
+const CACHE_SIZE: i32 = 256;
+
+/// The generator of a [CachePool].
+enum PoolGenerator<'pool> {
+    /// Outlives the pool.
+    Borrowed(&'pool Generator),
+    /// Freed with the last clone of the pool, after its caches.
+    Owned(Generator),
 }
 
-impl Clone for CachePool<'_> {
-    fn clone(&self) -> Self {
-        Self {
-            generator: self.generator,
-            caches: self.caches.clone(),
+impl Deref for PoolGenerator<'_> {
+    type Target = Generator;
+
+    fn deref(&self) -> &Generator {
+        match self {
+            PoolGenerator::Borrowed(generator) => generator,
+            PoolGenerator::Owned(generator) => generator,
         }
     }
 }
 
+/// The state shared by all clones of a [CachePool].
+struct PoolInner<'pool> {
+    // Declared before the generator, so caches borrowing an owned generator are
+    // dropped first.
+    caches: Mutex<BTreeMap<Scale, Vec<Cache<'pool>>>>,
+    generator: PoolGenerator<'pool>,
+}
+
+#[derive(Clone)]
+pub struct CachePool<'pool> {
+    inner: Arc<PoolInner<'pool>>,
+}
+
 impl<'pool> CachePool<'pool> {
     pub fn new(generator: &'pool Generator) -> Self {
+        Self::with_generator(PoolGenerator::Borrowed(generator))
+    }
+
+    fn with_generator(generator: PoolGenerator<'pool>) -> Self {
         Self {
-            generator,
-            caches: Arc::new(Mutex::new(BTreeMap::new())),
+            inner: Arc::new(PoolInner {
+                caches: Mutex::new(BTreeMap::new()),
+                generator,
+            }),
         }
     }
 
     pub fn as_generatr_ref(&self) -> &Generator {
-        self.generator
+        &self.inner.generator
+    }
+
+    /// The generator of the pool, borrowed for as long as caches are kept in
+    /// it.
+    fn pool_generator(&self) -> &'pool Generator {
+        match &self.inner.generator {
+            PoolGenerator::Borrowed(generator) => generator,
+            // SAFETY: The generator is only freed with the pool, after the
+            // caches borrowing it (see PoolInner). Caches are only handed out
+            // for the lifetime of a borrow of the pool (see CacheLock), so no
+            // borrow of the generator outlives it.
+            PoolGenerator::Owned(generator) => unsafe { &*(generator as *const Generator) },
+        }
     }
 
     pub fn get<'lock>(
@@ -50,13 +113,13 @@ impl<'pool> CachePool<'pool> {
         y: i32,
         z: i32,
         scale: Scale,
-    ) -> Result<CacheLock<'lock, 'pool>, Box<dyn Error>>
+    ) -> Result<CacheLock<'lock, 'pool>, GeneratorError>
     where
         'pool: 'lock,
     {
         let cache: Option<Cache<'pool>> = {
             let pre_lock = Instant::now();
-            let mut caches = self.caches.lock().unwrap();
+            let mut caches = self.inner.caches.lock().unwrap();
 
             caches.entry(scale).or_default();
             debug!("{:?}", pre_lock.elapsed());
@@ -64,30 +127,34 @@ impl<'pool> CachePool<'pool> {
             caches.get_mut(&scale).unwrap().pop()
         };
 
-        if let Some(mut cache) = cache {
-            cache.move_cache(x, y, z)?;
-            return Ok(CacheLock::new(cache, self));
-        }
-
-        Ok(CacheLock::new(
-            Cache::new(
-                self.generator,
+        let cache = match cache {
+            Some(mut cache) => {
+                cache.move_cache(x, y, z)?;
+                cache
+            }
+            None => Cache::new(
+                self.pool_generator(),
                 Range {
                     scale,
                     x,
                     z,
-                    size_x: 256,
-                    size_z: 256,
+                    size_x: CACHE_SIZE as u32,
+                    size_z: CACHE_SIZE as u32,
                     y,
                     size_y: 0,
                 },
             )?,
-            self,
-        ))
+        };
+
+        Ok(CacheLock {
+            cache: Some(cache),
+            pool: self,
+        })
     }
 
     fn give_back(&self, cache: Cache<'pool>, scale: Scale) {
-        self.caches
+        self.inner
+            .caches
             .lock()
             .unwrap()
             .get_mut(&scale)
@@ -96,83 +163,397 @@ impl<'pool> CachePool<'pool> {
     }
 }
 
-pub struct CacheLock<'lock, 'pool> {
-    inner: Option<Cache<'pool>>,
-    pool: &'lock CachePool<'pool>,
+impl CachePool<'static> {
+    /// Creates a pool which owns its generator, freeing it once the pool and
+    /// all its clones are dropped.
+    pub fn new_owned(generator: Generator) -> Self {
+        Self::with_generator(PoolGenerator::Owned(generator))
+    }
 }
 
-impl<'pool> Deref for CacheLock<'_, 'pool> {
-    type Target = Cache<'pool>;
-
-    fn deref(&self) -> &Self::Target {
-        self.inner.as_ref().unwrap()
-    }
+/// A cache of the pool, given back to it once unlocked.
+pub struct CacheLock<'lock, 'pool> {
+    cache: Option<Cache<'pool>>,
+    pool: &'lock CachePool<'pool>,
 }
 
-impl<'lock, 'pool> DerefMut for CacheLock<'lock, 'pool>
+impl<'lock, 'pool> Deref for CacheLock<'lock, 'pool>
 where
     'pool: 'lock,
 {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        self.inner.as_mut().unwrap()
-    }
-}
+    // Only for the lifetime of the lock, since the caches of pools owning their
+    // generator don't actually live for 'pool.
+    type Target = Cache<'lock>;
 
-impl<'lock, 'pool> CacheLock<'lock, 'pool> {
-    fn new(cache: Cache<'pool>, pool: &'lock CachePool<'pool>) -> Self
-    where
-        'pool: 'lock,
-    {
-        Self {
-            inner: (Some(cache)),
-            pool,
-        }
+    fn deref(&self) -> &Self::Target {
+        self.cache.as_ref().unwrap()
     }
 }
 
-impl<'lock, 'pool> Drop for CacheLock<'lock, 'pool> {
-    fn drop(&mut self)
-    where
-        'pool: 'lock,
-    {
-        let scale = (self.inner.as_ref()).unwrap().range().scale;
+impl Drop for CacheLock<'_, '_> {
+    fn drop(&mut self) {
+        if let Some(cache) = self.cache.take() {
+            let scale = cache.range().scale;
 
-        self.pool.give_back(self.inner.take().unwrap(), scale);
+            self.pool.give_back(cache, scale);
+        }
     }
 }
 
 impl CachePool<'_> {
+    /// Renders the biomes of a tile at y_level (in blocks).
+    ///
+    /// Shading is done from the surface height, so it is skipped in the nether
+    /// where there is no surface. In the end, the void between islands is
+    /// masked out.
     pub fn get_tile(
         &self,
         zoom: i32,
         x: i32,
         y: i32,
+        y_level: i32,
         is_shaded: bool,
-    ) -> Option<image::DynamicImage> {
+    ) -> Result<image::DynamicImage, ProviderError> {
+        let dimension = self.inner.generator.dimension();
+
+        // End biomes can't be generated at a 1:256 scale.
+        if dimension == Dimension::DIM_END && zoom == MIN_ZOOM {
+            return Err(ProviderError::ZoomOutOfRange(zoom));
+        }
+
+        let pos = TilePos::new(zoom, x, y);
+
+        if pos.is_outside_world() {
+            return Err(ProviderError::OutOfBounds(pos));
+        }
+
         let mut tile = match zoom {
-            -8 => get_image(x, y, self, Scale::HalfRegion),
-            -7 => concat_lower_zoom(x, y, self, Scale::QuadChunk),
-            -6 => get_image(x, y, self, Scale::QuadChunk),
-            -5 => concat_lower_zoom(x, y, self, Scale::Chunk),
-            -4 => get_image(x, y, self, Scale::Chunk),
-            -3 => concat_lower_zoom(x, y, self, Scale::Quad),
-            -2 => get_image(x, y, self, Scale::Quad),
-            -1 => concat_lower_zoom(x, y, self, Scale::Block),
-            0 => get_image(x, y, self, Scale::Block),
-            1..=8 => upsacale_blockscale(x, y, zoom, self),
-            _ => return None,
+            -8 => get_image(pos, y_level, self, Scale::HalfRegion),
+            -7 => concat_lower_zoom(pos, y_level, self, Scale::QuadChunk),
+            -6 => get_image(pos, y_level, self, Scale::QuadChunk),
+            -5 => concat_lower_zoom(pos, y_level, self, Scale::Chunk),
+            -4 => get_image(pos, y_level, self, Scale::Chunk),
+            -3 => concat_lower_zoom(pos, y_level, self, Scale::Quad),
+            -2 => get_image(pos, y_level, self, Scale::Quad),
+            -1 => concat_lower_zoom(pos, y_level, self, Scale::Block),
+            0 => get_image(pos, y_level, self, Scale::Block),
+            1..=MAX_ZOOM => upsacale_blockscale(x, y, zoom, y_level, self),
+            _ => return Err(ProviderError::ZoomOutOfRange(zoom)),
+        }?;
+
+        match dimension {
+            Dimension::DIM_NETHER => (),
+            Dimension::DIM_END => {
+                let heightmap = generate_heightmap(pos, self)?;
+
+                if is_shaded {
+                    draw_shading(&heightmap, &mut tile, 24);
+                }
+                mask_void(&heightmap, &mut tile);
+            }
+            _ if is_shaded => {
+                let heightmap = generate_heightmap(pos, self)?;
+
+                draw_shading(&heightmap, &mut tile, 24);
+            }
+            _ => (),
+        }
+
+        Ok(tile.into())
+    }
+}
+
+/// The biome at a block, as served by the biome api.
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct BiomeSample {
+    pub id: i32,
+    pub name: &'static str,
+    /// Approximate height of the surface at the block, None in the nether
+    /// which has no surface.
+    pub surface_height: Option<f32>,
+}
+
+impl CachePool<'_> {
+    /// Samples the biomes at the blocks (x, y, z) of points.
+    ///
+    /// Biomes are generated at scale, so the samples match the tiles of the
+    /// zoom levels generated at that scale.
+    pub fn sample_biomes(
+        &self,
+        points: &[(i32, i32, i32)],
+        scale: Scale,
+    ) -> Result<Vec<BiomeSample>, GeneratorError> {
+        let generator: &Generator = &self.inner.generator;
+
+        // Surface height approximation panics for beta versions.
+        let noise: Option<BiomeNoise> = match generator.dimension() {
+            Dimension::DIM_NETHER => None,
+            _ if generator.minecraft_version() < MCVersion::MC_1_0 => None,
+            dimension => Some(SurfaceNoiseRelease::new(dimension, generator.seed()).into()),
         };
+        let mut cache: Option<Cache> = None;
+
+        points
+            .iter()
+            .map(|&(x, y, z)| {
+                let (range_x, range_z) = (x.div_euclid(scale as i32), z.div_euclid(scale as i32));
+                let range_y = range_y(y, scale);
+
+                let cache = match &mut cache {
+                    Some(cache) => {
+                        cache.move_cache(range_x, range_y, range_z)?;
+                        cache
+                    }
+                    None => cache.insert(Cache::new(
+                        generator,
+                        Range {
+                            scale,
+                            x: range_x,
+                            z: range_z,
+                            size_x: 1,
+                            size_z: 1,
+                            y: range_y,
+                            size_y: 0,
+                        },
+                    )?),
+                };
+                let biome = cache.biome_at(0, 0, 0)?;
+
+                // Surface noise is generated at a 1:4 scale.
+                let surface_height = noise.as_ref().and_then(|noise| {
+                    generator
+                        .approx_surface_noise(x.div_euclid(4), z.div_euclid(4), 1, 1, noise)
+                        .map(|heights| heights[0])
+                });
+
+                Ok(BiomeSample {
+                    id: biome as i32,
+                    name: biome.to_mc_biome_str(generator.minecraft_version()),
+                    surface_height,
+                })
+            })
+            .collect()
+    }
+}
+
+/// The area a biome covers, as counted by [CachePool::biome_areas].
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct BiomeArea {
+    pub id: i32,
+    pub name: &'static str,
+    /// The area in blocks.
+    pub area: u64,
+    /// The share of the counted area in percent.
+    pub percentage: f64,
+}
+
+impl CachePool<'_> {
+    /// Counts the biomes in bbox at y_level, given in block coordinates as
+    /// min_x, min_z, max_x, max_z (inclusive).
+    ///
+    /// Biomes are generated at scale, and every sample counts for the whole
+    /// cell, so areas at the edges of bbox are rounded to whole cells. Sorted
+    /// by area, largest first.
+    pub fn biome_areas(
+        &self,
+        [min_x, min_z, max_x, max_z]: [i32; 4],
+        y_level: i32,
+        scale: Scale,
+    ) -> Result<Vec<BiomeArea>, Box<dyn Error>> {
+        let cell = scale as i32;
+        let (min_x, min_z, max_x, max_z) = (
+            min_x.div_euclid(cell),
+            min_z.div_euclid(cell),
+            max_x.div_euclid(cell),
+            max_z.div_euclid(cell),
+        );
+        let mut counts = BTreeMap::new();
+
+        // The caches overlapping the cells of bbox.
+        for cache_x in min_x.div_euclid(CACHE_SIZE)..=max_x.div_euclid(CACHE_SIZE) {
+            for cache_z in min_z.div_euclid(CACHE_SIZE)..=max_z.div_euclid(CACHE_SIZE) {
+                let (origin_x, origin_z) = (cache_x * CACHE_SIZE, cache_z * CACHE_SIZE);
+                let cache = self.get(origin_x, range_y(y_level, scale), origin_z, scale)?;
+
+                for x in min_x.max(origin_x)..=max_x.min(origin_x + (CACHE_SIZE - 1)) {
+                    for z in min_z.max(origin_z)..=max_z.min(origin_z + (CACHE_SIZE - 1)) {
+                        let biome =
+                            cache.biome_at((x - origin_x) as u32, 0, (z - origin_z) as u32)?;
+
+                        *counts.entry(biome).or_insert(0_u64) += 1;
+                    }
+                }
+            }
+        }
 
-        if is_shaded {
-            let heightmap = generate_heightmap(x * 256, y * 256, zoom, self);
+        let total: u64 = counts.values().sum();
+        let cell_area = (cell * cell) as u64;
+        let version = self.inner.generator.minecraft_version();
+
+        let mut areas: Vec<_> = counts
+            .into_iter()
+            .map(|(biome, count)| BiomeArea {
+                id: biome as i32,
+                name: biome.to_mc_biome_str(version),
+                area: count * cell_area,
+                percentage: count as f64 / total as f64 * 100.0,
+            })
+            .collect();
+        areas.sort_by_key(|area| Reverse(area.area));
+
+        Ok(areas)
+    }
+}
 
-            draw_shading(&heightmap, &mut tile, 24);
+/// The nearest block of a biome, as found by [CachePool::find_biome].
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct BiomeMatch {
+    pub x: i32,
+    pub z: i32,
+    /// The distance in blocks from the start of the search.
+    pub distance: f64,
+}
+
+/// How a biome search ended.
+#[derive(Debug, Clone, Copy)]
+pub enum BiomeSearch {
+    Found(BiomeMatch),
+    /// The biome isn't within the radius.
+    NotFound,
+    /// The deadline passed before the search was done.
+    OutOfTime,
+    Cancelled,
+}
+
+/// The id of the biome named name in version, if it generates in the
+/// overworld.
+pub fn overworld_biome_id(name: &str, version: MCVersion) -> Option<i32> {
+    (0..256).find(|&id| {
+        // SAFETY: biome2str returns null or a static string, and isOverworld
+        // only compares ids.
+        unsafe {
+            let chars = cubiomes_sys::biome2str(version as i32, id);
+
+            !chars.is_null()
+                && CStr::from_ptr(chars).to_bytes() == name.as_bytes()
+                && cubiomes_sys::isOverworld(version as i32, id) != 0
         }
+    })
+}
 
-        Some(tile.into())
+impl CachePool<'_> {
+    /// Searches the surface for the block of biome (an id) nearest to x, z,
+    /// up to radius blocks away.
+    ///
+    /// Caches are generated in rings around the start until no ring can have
+    /// a nearer block. Larger radii are searched at a coarser scale, which can
+    /// miss small patches of the biome. Gives up once deadline has passed or
+    /// cancelled is set.
+    pub fn find_biome(
+        &self,
+        biome: i32,
+        x: i32,
+        z: i32,
+        radius: i32,
+        deadline: Instant,
+        cancelled: &AtomicBool,
+    ) -> Result<BiomeSearch, Box<dyn Error>> {
+        let scale = search_scale(radius);
+        let cell = scale as i32;
+        // The width of a cache in blocks.
+        let window = CACHE_SIZE * cell;
+
+        // The first cache is centered on the start, in cells.
+        let (first_x, first_z) = (
+            x.div_euclid(cell) - CACHE_SIZE / 2,
+            z.div_euclid(cell) - CACHE_SIZE / 2,
+        );
+        // The distance from the start to the nearest block of a range of
+        // blocks.
+        let nearest = |start: i32, min: i32| (min - start).max(start - min - window).max(0);
+
+        let mut best: Option<BiomeMatch> = None;
+
+        for ring in 0.. {
+            // The caches of a ring are at least this far away from the start.
+            let ring_distance = ((ring as f64 - 0.5) * window as f64 - cell as f64).max(0.0);
+
+            if ring_distance > radius as f64
+                || best.is_some_and(|best| best.distance <= ring_distance)
+            {
+                break;
+            }
+
+            for (i, j) in ring_caches(ring) {
+                if cancelled.load(Ordering::Relaxed) {
+                    return Ok(BiomeSearch::Cancelled);
+                }
+                if Instant::now() >= deadline {
+                    return Ok(BiomeSearch::OutOfTime);
+                }
+
+                let (cache_x, cache_z) = (first_x + i * CACHE_SIZE, first_z + j * CACHE_SIZE);
+                let cache_distance =
+                    (nearest(x, cache_x * cell) as f64).hypot(nearest(z, cache_z * cell) as f64);
+
+                if cache_distance > radius as f64
+                    || best.is_some_and(|best| best.distance <= cache_distance)
+                {
+                    continue;
+                }
+
+                let cache = self.get(cache_x, range_y(SURFACE_Y_LEVEL, scale), cache_z, scale)?;
+
+                for (index, _) in cache
+                    .as_vec()
+                    .iter()
+                    .enumerate()
+                    .filter(|&(_, &id)| id == biome)
+                {
+                    // The center of the cell.
+                    let block_x = (cache_x + index as i32 % CACHE_SIZE) * cell + cell / 2;
+                    let block_z = (cache_z + index as i32 / CACHE_SIZE) * cell + cell / 2;
+                    let distance = ((block_x - x) as f64).hypot((block_z - z) as f64);
+
+                    if distance <= radius as f64 && best.is_none_or(|best| distance < best.distance)
+                    {
+                        best = Some(BiomeMatch {
+                            x: block_x,
+                            z: block_z,
+                            distance,
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(best.map_or(BiomeSearch::NotFound, BiomeSearch::Found))
     }
 }
 
+/// The scale a biome search within radius is done at: the finest one which
+/// takes at most 3x3 caches to cover the radius, since generating a cache can
+/// take most of a second.
+fn search_scale(radius: i32) -> Scale {
+    [Scale::Quad, Scale::Chunk, Scale::QuadChunk]
+        .into_iter()
+        .find(|&scale| CACHE_SIZE * scale as i32 >= radius)
+        .unwrap_or(Scale::HalfRegion)
+}
+
+/// The caches of a biome search in a ring, as offsets (in caches) from the
+/// first one.
+fn ring_caches(ring: i32) -> Vec<(i32, i32)> {
+    if ring == 0 {
+        return vec![(0, 0)];
+    }
+
+    (-ring..=ring)
+        .flat_map(|i| [(i, -ring), (i, ring)])
+        .chain((1 - ring..ring).flat_map(|j| [(-ring, j), (ring, j)]))
+        .collect()
+}
+
 pub struct ShadedBiomeTile<'a>(CachePool<'a>);
 
 impl<'a> ShadedBiomeTile<'a> {
@@ -182,8 +563,11 @@ impl<'a> ShadedBiomeTile<'a> {
 }
 
 impl TileProvider for ShadedBiomeTile<'_> {
-    fn get_tile(&self, pos: TilePos) -> Option<image::DynamicImage> {
-        self.0.get_tile(pos.zoom, pos.x, pos.y, true)
+    fn get_tile(&self, pos: TilePos) -> Result<image::DynamicImage, ProviderError> {
+        render_scaled(pos, MAX_ZOOM, |pos| {
+            self.0
+                .get_tile(pos.zoom, pos.x, pos.y, SURFACE_Y_LEVEL, true)
+        })
     }
 }
 
@@ -196,8 +580,11 @@ impl<'a> From<CachePool<'a>> for ShadedBiomeTile<'a> {
 pub struct UnshadedBiomeTile<'a>(CachePool<'a>);
 
 impl TileProvider for UnshadedBiomeTile<'_> {
-    fn get_tile(&self, pos: TilePos) -> Option<image::DynamicImage> {
-        self.0.get_tile(pos.zoom, pos.x, pos.y, false)
+    fn get_tile(&self, pos: TilePos) -> Result<image::DynamicImage, ProviderError> {
+        render_scaled(pos, MAX_ZOOM, |pos| {
+            self.0
+                .get_tile(pos.zoom, pos.x, pos.y, SURFACE_Y_LEVEL, false)
+        })
     }
 }
 
@@ -206,16 +593,61 @@ impl<'a> From<CachePool<'a>> for UnshadedBiomeTile<'a> {
         Self(value)
     }
 }
+/// Nether biomes are 3d, so they are rendered as a slice at a y level.
+pub struct NetherBiomeTile<'a> {
+    cache_pool: CachePool<'a>,
+    y_level: i32,
+}
+
+impl<'a> NetherBiomeTile<'a> {
+    pub fn new(cache_pool: CachePool<'a>, y_level: i32) -> Self {
+        Self {
+            cache_pool,
+            y_level,
+        }
+    }
+}
+
+impl TileProvider for NetherBiomeTile<'_> {
+    fn get_tile(&self, pos: TilePos) -> Result<image::DynamicImage, ProviderError> {
+        render_scaled(pos, MAX_ZOOM, |pos| {
+            self.cache_pool
+                .get_tile(pos.zoom, pos.x, pos.y, self.y_level, false)
+        })
+    }
+}
+
 pub struct ContourLines<'a>(pub CachePool<'a>);
 
 impl TileProvider for ContourLines<'_> {
-    fn get_tile(&self, pos: TilePos) -> Option<image::DynamicImage> {
-        let TilePos { x, y, zoom } = pos;
+    fn get_tile(&self, pos: TilePos) -> Result<image::DynamicImage, ProviderError> {
+        if !(MIN_ZOOM..=MAX_ZOOM).contains(&pos.zoom) {
+            return Err(ProviderError::ZoomOutOfRange(pos.zoom));
+        }
 
-        let heightmap = generate_heightmap(x * 256, y * 256, zoom, &self.0);
+        // Scaled tiles have the resolution of a higher zoom, but should have
+        // as many lines as their own.
+        let frequency = zoom_calc(pos.zoom, |_| 30, |scale| (15 * (scale)) as u8);
+
+        // The frequency overflows when zoomed out far, leaving no levels to
+        // draw at zoom -8.
+        if frequency / 3 == 0 {
+            return Err(ProviderError::ZoomOutOfRange(pos.zoom));
+        }
+
+        if pos.is_outside_world() {
+            return Err(ProviderError::OutOfBounds(pos));
+        }
+
+        render_scaled(pos, MAX_ZOOM, |pos| self.render(pos, frequency))
+    }
+}
+
+impl ContourLines<'_> {
+    fn render(&self, pos: TilePos, frequency: u8) -> Result<image::DynamicImage, ProviderError> {
+        let heightmap = generate_heightmap(pos, &self.0)?;
 
         let start_level: u8 = 62;
-        let frequency = zoom_calc(zoom, |_| 30, |scale| ((15 * (scale)) as u8));
 
         let mut tile = GrayAlphaImage::from_pixel(256, 256, [0, 0].into());
 
@@ -235,7 +667,7 @@ impl TileProvider for ContourLines<'_> {
             255,
         );
 
-        Some(tile.into())
+        Ok(tile.into())
     }
 }
 
@@ -251,7 +683,7 @@ impl<'a> From<CachePool<'a>> for ContourLines<'a> {
 pub fn contour_levels(start_levels: u8, frequency: u8) -> impl Iterator<Item = u8> {
     (u8::MIN..u8::MAX)
         .step_by(frequency as usize)
-        .map(move |x| x + (start_levels % frequency))
+        .filter_map(move |x| x.checked_add(start_levels % frequency))
 }
 
 pub fn zoom_calc<F1, F2, T>(zoom: i32, zoomed_in: F1, zoomed_out: F2) -> T
@@ -267,3 +699,100 @@ where
         zoomed_in(scale)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use cubiomes::generator::GeneratorFlags;
+
+    use super::*;
+    use crate::tileprovider::{TILE_SIZE, WORLD_BORDER};
+
+    #[test]
+    fn search_scale_grows_with_the_radius() {
+        assert_eq!(search_scale(1), Scale::Quad);
+        assert_eq!(search_scale(1024), Scale::Quad);
+        assert_eq!(search_scale(1025), Scale::Chunk);
+        assert_eq!(search_scale(4096), Scale::Chunk);
+        assert_eq!(search_scale(16384), Scale::QuadChunk);
+        assert_eq!(search_scale(16385), Scale::HalfRegion);
+        assert_eq!(search_scale(65536), Scale::HalfRegion);
+    }
+
+    #[test]
+    fn rings_cover_the_caches_around_the_first_once() {
+        assert_eq!(ring_caches(0), [(0, 0)]);
+
+        let mut seen = HashSet::new();
+
+        for ring in 0..=3 {
+            let caches = ring_caches(ring);
+
+            assert_eq!(caches.len(), (8 * ring).max(1) as usize);
+
+            for (i, j) in caches {
+                assert_eq!(i.abs().max(j.abs()), ring);
+                assert!(seen.insert((i, j)), "({i}, {j}) is in two rings");
+            }
+        }
+
+        // Together the rings are a 7x7 square.
+        assert_eq!(seen.len(), 49);
+    }
+
+    #[test]
+    fn renders_shaded_tiles_next_to_the_border() {
+        let generator = Generator::new(
+            MCVersion::MC_1_21_WD,
+            42,
+            Dimension::DIM_OVERWORLD,
+            GeneratorFlags::empty(),
+        );
+        let pool = CachePool::new(&generator);
+
+        // The last tiles within the border, whose pixels are far past i32 at
+        // zoom 8.
+        let last = WORLD_BORDER - 1;
+
+        for (zoom, x) in [(8, last), (7, last / 2), (8, -last - 1)] {
+            let tile = pool.get_tile(zoom, x, x, SURFACE_Y_LEVEL, true).unwrap();
+            assert_eq!((tile.width(), tile.height()), (TILE_SIZE, TILE_SIZE));
+
+            ContourLines(pool.clone())
+                .get_tile(TilePos::new(zoom, x, x))
+                .unwrap();
+        }
+
+        assert!(matches!(
+            pool.get_tile(8, WORLD_BORDER, 0, SURFACE_Y_LEVEL, true),
+            Err(ProviderError::OutOfBounds(_))
+        ));
+    }
+
+    #[test]
+    fn owned_pools_reuse_their_caches() {
+        let pool = CachePool::new_owned(Generator::new(
+            MCVersion::MC_1_21_WD,
+            42,
+            Dimension::DIM_OVERWORLD,
+            GeneratorFlags::empty(),
+        ));
+        let pooled = |pool: &CachePool| pool.inner.caches.lock().unwrap()[&Scale::Quad].len();
+
+        let first = pool.get(0, 0, 0, Scale::Quad).unwrap().biome_at(0, 0, 0);
+        assert_eq!(pooled(&pool), 1);
+
+        // Moved instead of creating another cache, while a clone of the pool
+        // keeps the generator alive.
+        let clone = pool.clone();
+        drop(pool);
+        let moved = clone.get(256, 0, 0, Scale::Quad).unwrap();
+        drop(moved);
+        assert_eq!(pooled(&clone), 1);
+        assert_eq!(
+            clone.get(0, 0, 0, Scale::Quad).unwrap().biome_at(0, 0, 0),
+            first
+        );
+    }
+}
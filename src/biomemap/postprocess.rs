@@ -11,29 +11,65 @@ use cubiomes::{
 use image::{GrayImage, ImageBuffer, Rgb, RgbImage, imageops::resize};
 
 use super::CachePool;
+use crate::tileprovider::{ProviderError, TilePos};
 
 static COLOR_MAP: LazyLock<BiomeColorMap> = std::sync::LazyLock::new(BiomeColorMap::new);
 
-pub fn get_image(x: i32, y: i32, cache_pool: &CachePool, scale: Scale) -> RgbImage {
-    cache_pool
-        .get(x * 256, 320, y * 256, scale)
-        .unwrap()
-        .to_image(*COLOR_MAP)
+/// Color of the void between the islands of the end.
+const VOID_COLOR: [u8; 3] = [10, 8, 18];
+
+/// Converts a block y level to the y coordinate of a [Range] at scale.
+///
+/// Ranges use 1:1 vertical scaling only at block scale, and 1:4 otherwise.
+pub fn range_y(y_level: i32, scale: Scale) -> i32 {
+    match scale {
+        Scale::Block => y_level,
+        _ => y_level >> 2,
+    }
+}
+
+/// The first cell of the tile at pos, with cells cells per tile.
+///
+/// Computed in i64, since tiles far out at high zoom levels start past the
+/// range of i32.
+fn tile_origin(pos: TilePos, cells: i64) -> Result<(i32, i32), ProviderError> {
+    let origin = |tile: i32| i32::try_from(tile as i64 * cells).ok();
+
+    origin(pos.x)
+        .zip(origin(pos.y))
+        .ok_or(ProviderError::OutOfBounds(pos))
+}
+
+pub fn get_image(
+    pos: TilePos,
+    y_level: i32,
+    cache_pool: &CachePool,
+    scale: Scale,
+) -> Result<RgbImage, ProviderError> {
+    let (x, y) = tile_origin(pos, 256)?;
+
+    Ok(cache_pool
+        .get(x, range_y(y_level, scale), y, scale)?
+        .to_image(*COLOR_MAP))
 }
 
-pub fn concat_lower_zoom(x: i32, y: i32, cache_pool: &CachePool, scale: Scale) -> RgbImage {
+pub fn concat_lower_zoom(
+    pos: TilePos,
+    y_level: i32,
+    cache_pool: &CachePool,
+    scale: Scale,
+) -> Result<RgbImage, ProviderError> {
+    let (x, y) = tile_origin(pos, 256 * 2)?;
     let mut img = RgbImage::new(256, 256);
 
     for img_x in 0..=1 {
         for img_y in 0..=1 {
-            let cache = cache_pool
-                .get(
-                    ((x * 256) * (2)) + (img_x * 256),
-                    320,
-                    ((y * 256) * (2)) + (img_y * 256),
-                    scale,
-                )
-                .unwrap();
+            let cache = cache_pool.get(
+                x + (img_x * 256),
+                range_y(y_level, scale),
+                y + (img_y * 256),
+                scale,
+            )?;
 
             for sub_img_x in 0..(256 / (2)) {
                 for sub_img_y in 0..(256 / (2)) {
@@ -41,18 +77,22 @@ pub fn concat_lower_zoom(x: i32, y: i32, cache_pool: &CachePool, scale: Scale) -
                         (sub_img_x + ((256 / (2)) * img_x)) as u32,
                         sub_img_y + (256 / (2) * img_y) as u32,
                     ) = Rgb::from(
-                        COLOR_MAP[cache
-                            .biome_at((sub_img_x * (2)) as u32, 0, sub_img_y * (2))
-                            .unwrap()],
+                        COLOR_MAP[cache.biome_at((sub_img_x * (2)) as u32, 0, sub_img_y * (2))?],
                     );
                 }
             }
         }
     }
-    img
+    Ok(img)
 }
 
-pub fn upsacale_blockscale(x: i32, y: i32, zoom: i32, cache_pool: &CachePool) -> RgbImage {
+pub fn upsacale_blockscale(
+    x: i32,
+    y: i32,
+    zoom: i32,
+    y_level: i32,
+    cache_pool: &CachePool,
+) -> Result<RgbImage, ProviderError> {
     let tilecount = 2_u32.pow(zoom as u32);
 
     let size = 256 / tilecount;
@@ -62,15 +102,14 @@ pub fn upsacale_blockscale(x: i32, y: i32, zoom: i32, cache_pool: &CachePool) ->
             cache_pool.as_generatr_ref(),
             Range {
                 x: x * size as i32,
-                y: 320,
+                y: y_level,
                 z: y * size as i32,
                 size_x: size,
                 size_y: 0,
                 size_z: size,
                 scale: Scale::Block,
             },
-        )
-        .unwrap()
+        )?
         .to_image(*COLOR_MAP),
         256,
         256,
@@ -86,7 +125,7 @@ pub fn upsacale_blockscale(x: i32, y: i32, zoom: i32, cache_pool: &CachePool) ->
         //});
     }
 
-    img
+    Ok(img)
 }
 
 pub fn draw_contours<Levels, Pixel, Container>(
@@ -119,8 +158,11 @@ pub fn draw_contours<Levels, Pixel, Container>(
     }
 }
 
-pub fn generate_heightmap(x: i32, y: i32, zoom: i32, cache_pool: &CachePool) -> GrayImage {
-    let rel_zoom = zoom + 2;
+pub fn generate_heightmap(
+    pos: TilePos,
+    cache_pool: &CachePool,
+) -> Result<GrayImage, ProviderError> {
+    let rel_zoom = pos.zoom + 2;
 
     let scale = 2_u32.pow((rel_zoom).unsigned_abs());
 
@@ -130,18 +172,24 @@ pub fn generate_heightmap(x: i32, y: i32, zoom: i32, cache_pool: &CachePool) ->
     )
     .into();
 
-    let scaled_x;
-    let scaled_y;
+    // The first pixel of the tile at 1:4, in i64 since the pixels of tiles far
+    // out at high zoom levels are past the range of i32.
+    let scaled = |tile: i32| {
+        let pixel = tile as i64 * 256;
 
-    if rel_zoom.is_negative() {
-        scaled_x = x * scale as i32;
-        scaled_y = y * scale as i32;
-    } else {
-        scaled_x = x / scale as i32;
-        scaled_y = y / scale as i32;
-    }
+        if rel_zoom.is_negative() {
+            i32::try_from(pixel * scale as i64).ok()
+        } else {
+            i32::try_from(pixel / scale as i64).ok()
+        }
+    };
+    let (scaled_x, scaled_y) = scaled(pos.x)
+        .zip(scaled(pos.y))
+        .ok_or(ProviderError::OutOfBounds(pos))?;
 
-    GrayImage::from_fn(256 + 2, 256 + 2, |img_x, img_y| {
+    let mut heightmap = GrayImage::new(256 + 2, 256 + 2);
+
+    for (img_x, img_y, pixel) in heightmap.enumerate_pixels_mut() {
         let offset_x;
         let offset_y;
 
@@ -152,7 +200,7 @@ pub fn generate_heightmap(x: i32, y: i32, zoom: i32, cache_pool: &CachePool) ->
             offset_x = img_x / scale;
             offset_y = img_y / scale;
         }
-        [((cache_pool
+        *pixel = [((cache_pool
             .as_generatr_ref()
             .approx_surface_noise(
                 offset_x as i32 + (scaled_x),
@@ -161,11 +209,13 @@ pub fn generate_heightmap(x: i32, y: i32, zoom: i32, cache_pool: &CachePool) ->
                 1,
                 &noise,
             )
-            .unwrap()[0]
+            .ok_or(ProviderError::SurfaceError)?[0]
             * (320.0 / 255.0))
             .clamp(0.0, 255.0) as u8)]
-        .into()
-    })
+        .into();
+    }
+
+    Ok(heightmap)
 }
 
 fn calc_2d_index(x: usize, width: usize, y: usize) -> usize {
@@ -242,6 +292,19 @@ pub fn draw_shading(heightmap: &GrayImage, tile: &mut RgbImage, strenght: i8) {
     }
 }
 
+/// Paints every pixel of the tile without any terrain below it with
+/// [VOID_COLOR].
+///
+/// Uses the same heightmap layout as [draw_shading]. Only makes sense in the
+/// end, where a surface height of 0 means there is no island.
+pub fn mask_void(heightmap: &GrayImage, tile: &mut RgbImage) {
+    tile.enumerate_pixels_mut().for_each(|(x, y, pixel)| {
+        if heightmap.get_pixel(x + 1, y + 1).0[0] == 0 {
+            *pixel = Rgb(VOID_COLOR);
+        }
+    });
+}
+
 fn height_diff_shade_calculator(stroke: i8, h_diff: i8) -> i8 {
     stroke.saturating_add(h_diff / stroke)
 }
@@ -0,0 +1,743 @@
+//! Runtime configuration for the tileserver.
+//!
+//! Values are read from (in order of precedence) command line arguments, env
+//! vars and an optional toml or json config file. Anything left unset falls
+//! back to the defaults in [Config::default].
+//!
+//! Worlds can only be defined in the config file (as `[worlds.<name>]` tables).
+//! If none are defined, a single world named [DEFAULT_WORLD] is created from
+//! the top level `seed` and `mc_version`. Setting either along with worlds is
+//! an error, since they would be ignored.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    fs::read_to_string,
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    num::NonZeroUsize,
+    path::PathBuf,
+    thread::available_parallelism,
+};
+
+use clap::{Parser, Subcommand};
+use cubiomes::enums::MCVersion;
+use image::ImageFormat;
+use serde::Deserialize;
+
+use crate::{
+    prerender::{ExportArgs, SeedArgs},
+    tileprovider::{diskindex::DiskBudget, tilestore::TileStoreKind},
+};
+
+#[derive(Debug)]
+pub enum Error {
+    ReadError(PathBuf, io::Error),
+    TomlError(PathBuf, toml::de::Error),
+    JsonError(PathBuf, serde_json::Error),
+    UnknownFileType(PathBuf),
+    InvalidMCVersion(String),
+    InvalidTileFormat(String),
+    InvalidTileStore(String),
+    ZeroCacheSize,
+    ZeroLiveSeeds,
+    ZeroRenderThreads,
+    ZeroDiskBudget,
+    InvalidWorldName(String),
+    InvalidNetherYLevel(i32),
+    InvalidCompositeName(String),
+    EmptyComposite(String),
+    DefaultWorldWithWorlds(&'static str),
+    ReservedWorldName(String),
+}
+
+/// Name of the world created when the config doesn't define any.
+pub const DEFAULT_WORLD: &str = "default";
+
+/// The first path segments of urls which aren't worlds, which world names
+/// would shadow.
+const RESERVED_WORLD_NAMES: [&str; 4] = ["api", "seed", "mbtiles", "pmtiles"];
+
+/// The y levels nether biomes are rendered at, unless configured otherwise.
+pub const DEFAULT_NETHER_Y_LEVELS: [i32; 3] = [32, 64, 100];
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::ReadError(path, _) => {
+                write!(f, "failed to read config file {}", path.display())
+            }
+            Error::TomlError(path, e) => {
+                write!(f, "config file {} is not valid: {e}", path.display())
+            }
+            Error::JsonError(path, e) => {
+                write!(f, "config file {} is not valid: {e}", path.display())
+            }
+            Error::UnknownFileType(path) => write!(
+                f,
+                "config file {} must have a .toml or .json extension",
+                path.display()
+            ),
+            Error::InvalidMCVersion(version) => {
+                write!(
+                    f,
+                    "`{version}` is not a minecraft version known to cubiomes"
+                )
+            }
+            Error::InvalidTileFormat(format) => write!(
+                f,
+                "tile format `{format}` is not supported, use one of: png, webp, jpg"
+            ),
+            Error::InvalidTileStore(store) => write!(
+                f,
+                "tile store `{store}` is not supported, use one of: fs, kv, memory"
+            ),
+            Error::ZeroCacheSize => write!(f, "memory_cache_bytes must be at least 1"),
+            Error::ZeroLiveSeeds => write!(f, "max_live_seeds must be at least 1"),
+            Error::ZeroRenderThreads => write!(f, "render_threads must be at least 1"),
+            Error::ZeroDiskBudget => write!(
+                f,
+                "disk_cache_bytes and disk_cache_tiles must be at least 1 if set"
+            ),
+            Error::InvalidNetherYLevel(y_level) => {
+                write!(
+                    f,
+                    "nether y level {y_level} is outside of the range 0..=255"
+                )
+            }
+            Error::InvalidWorldName(name) => write!(
+                f,
+                "world name `{name}` may only contain lowercase ascii letters, digits, `-` and `_`"
+            ),
+            Error::InvalidCompositeName(name) => write!(
+                f,
+                "composite name `{name}` may only contain lowercase ascii letters, digits, `-` and `_`"
+            ),
+            Error::EmptyComposite(name) => {
+                write!(f, "composite `{name}` needs at least one layer")
+            }
+            Error::DefaultWorldWithWorlds(option) => write!(
+                f,
+                "{option} only configures the default world, set it in the worlds instead"
+            ),
+            Error::ReservedWorldName(name) => {
+                write!(f, "world name `{name}` is reserved for other urls")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::ReadError(_, e) => Some(e),
+            Error::TomlError(_, e) => Some(e),
+            Error::JsonError(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Command line arguments of the tileserver.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Args {
+    /// Path to a .toml or .json config file
+    #[arg(short, long, env = "BIOMEMAP_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub overrides: PartialConfig,
+
+    /// Runs the subcommand instead of the server
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Renders every tile of an area to the tile directory ahead of time
+    Seed(SeedArgs),
+    /// Writes the tiles of a layer in an area to an MBTiles or PMTiles file
+    Export(ExportArgs),
+}
+
+/// A config where every value is optional.
+///
+/// This is both what gets deserialized from the config file and what can be
+/// given as arguments or env vars, which then override the file.
+#[derive(clap::Args, Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PartialConfig {
+    /// Address the http server binds to
+    #[arg(long, env = "BIOMEMAP_ADDRESS")]
+    pub address: Option<SocketAddr>,
+
+    /// Seed of the default world, which can't be set if worlds are configured
+    #[arg(long, env = "BIOMEMAP_SEED", allow_hyphen_values = true)]
+    pub seed: Option<i64>,
+
+    /// Minecraft version of the default world, eg. "1.21" or "1.18.2"
+    #[arg(long, env = "BIOMEMAP_MC_VERSION")]
+    pub mc_version: Option<String>,
+
+    /// Default image format of the tiles (png, webp or jpg)
+    #[arg(long, env = "BIOMEMAP_TILE_FORMAT")]
+    pub tile_format: Option<String>,
+
+    /// Image formats tiles can be requested as, in addition to the default
+    #[arg(long, env = "BIOMEMAP_TILE_FORMATS", value_delimiter = ',')]
+    pub tile_formats: Option<Vec<String>>,
+
+    /// Maximum size in bytes of the tiles held in the memory cache of each
+    /// layer
+    #[arg(long, env = "BIOMEMAP_MEMORY_CACHE_BYTES")]
+    pub memory_cache_bytes: Option<usize>,
+
+    /// Directory the rendered tiles are stored in
+    #[arg(long, env = "BIOMEMAP_TILE_DIR")]
+    pub tile_dir: Option<PathBuf>,
+
+    /// How the tiles of each layer are stored in the tile directory: fs (a
+    /// file per tile), kv (a single .redb file per layer) or memory (not
+    /// persisted)
+    #[arg(long, env = "BIOMEMAP_TILE_STORE")]
+    pub tile_store: Option<String>,
+
+    /// Amount of generators kept alive for seeds requested on demand
+    #[arg(long, env = "BIOMEMAP_MAX_LIVE_SEEDS")]
+    pub max_live_seeds: Option<usize>,
+
+    /// Maximum amount of tiles rendered at once, defaults to the amount of cpus
+    #[arg(long, env = "BIOMEMAP_RENDER_THREADS")]
+    pub render_threads: Option<usize>,
+
+    /// Maximum size in bytes of the tiles each layer keeps on disk (of every
+    /// world and seed on its own), unlimited by default
+    #[arg(long, env = "BIOMEMAP_DISK_CACHE_BYTES")]
+    pub disk_cache_bytes: Option<u64>,
+
+    /// Maximum amount of tiles each layer keeps on disk, unlimited by default
+    #[arg(long, env = "BIOMEMAP_DISK_CACHE_TILES")]
+    pub disk_cache_tiles: Option<usize>,
+
+    /// Tiles at this zoom level and below are never evicted from disk
+    #[arg(long, env = "BIOMEMAP_DISK_PINNED_ZOOM", allow_hyphen_values = true)]
+    pub disk_pinned_zoom: Option<i32>,
+
+    /// Decode every stored tile on startup and remove the ones which are
+    /// corrupt, instead of only the empty ones
+    #[arg(long, env = "BIOMEMAP_VERIFY_TILES")]
+    pub verify_tiles: Option<bool>,
+
+    #[arg(skip)]
+    pub worlds: Option<BTreeMap<String, PartialWorldConfig>>,
+
+    /// MBTiles files served at `/mbtiles/<name>/`, keyed by name
+    #[arg(skip)]
+    pub mbtiles: Option<BTreeMap<String, PathBuf>>,
+
+    /// PMTiles archives served at `/pmtiles/<name>/`, keyed by name
+    #[arg(skip)]
+    pub pmtiles: Option<BTreeMap<String, PathBuf>>,
+
+    /// Layers of every world drawn on top of each other into a single layer,
+    /// served at `/<world>/composite/<name>/`, keyed by name
+    #[arg(skip)]
+    pub composites: Option<BTreeMap<String, Vec<String>>>,
+}
+
+/// A world as written in the config file.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PartialWorldConfig {
+    pub seed: i64,
+    pub mc_version: Option<String>,
+    pub nether_y_levels: Option<Vec<i32>>,
+}
+
+impl PartialConfig {
+    /// Reads a partial config from a toml or json file, chosen by extension.
+    pub fn from_file(path: PathBuf) -> Result<Self, Error> {
+        let contents = read_to_string(&path).map_err(|e| Error::ReadError(path.clone(), e))?;
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&contents).map_err(|e| Error::TomlError(path, e)),
+            Some("json") => serde_json::from_str(&contents).map_err(|e| Error::JsonError(path, e)),
+            _ => Err(Error::UnknownFileType(path)),
+        }
+    }
+
+    /// Replaces values of self with the ones set in other.
+    pub fn merge(self, other: PartialConfig) -> Self {
+        Self {
+            address: other.address.or(self.address),
+            seed: other.seed.or(self.seed),
+            mc_version: other.mc_version.or(self.mc_version),
+            tile_format: other.tile_format.or(self.tile_format),
+            tile_formats: other.tile_formats.or(self.tile_formats),
+            memory_cache_bytes: other.memory_cache_bytes.or(self.memory_cache_bytes),
+            tile_dir: other.tile_dir.or(self.tile_dir),
+            tile_store: other.tile_store.or(self.tile_store),
+            max_live_seeds: other.max_live_seeds.or(self.max_live_seeds),
+            render_threads: other.render_threads.or(self.render_threads),
+            disk_cache_bytes: other.disk_cache_bytes.or(self.disk_cache_bytes),
+            disk_cache_tiles: other.disk_cache_tiles.or(self.disk_cache_tiles),
+            disk_pinned_zoom: other.disk_pinned_zoom.or(self.disk_pinned_zoom),
+            verify_tiles: other.verify_tiles.or(self.verify_tiles),
+            worlds: other.worlds.or(self.worlds),
+            mbtiles: other.mbtiles.or(self.mbtiles),
+            pmtiles: other.pmtiles.or(self.pmtiles),
+            composites: other.composites.or(self.composites),
+        }
+    }
+}
+
+/// The validated configuration of a single world.
+#[derive(Debug, Clone)]
+pub struct WorldConfig {
+    pub name: String,
+    pub seed: i64,
+    pub mc_version: MCVersion,
+    pub nether_y_levels: Vec<i32>,
+}
+
+impl WorldConfig {
+    fn new(name: String, world: PartialWorldConfig) -> Result<Self, Error> {
+        if !is_valid_name(&name) {
+            return Err(Error::InvalidWorldName(name));
+        }
+
+        if RESERVED_WORLD_NAMES.contains(&name.as_str()) {
+            return Err(Error::ReservedWorldName(name));
+        }
+
+        let mc_version = match world.mc_version {
+            Some(version) => version
+                .parse()
+                .map_err(|_| Error::InvalidMCVersion(version))?,
+            None => MCVersion::MC_1_21_WD,
+        };
+
+        let nether_y_levels = world
+            .nether_y_levels
+            .unwrap_or(DEFAULT_NETHER_Y_LEVELS.into());
+
+        if let Some(y_level) = nether_y_levels.iter().find(|y| !(0..=255).contains(*y)) {
+            return Err(Error::InvalidNetherYLevel(*y_level));
+        }
+
+        Ok(Self {
+            name,
+            seed: world.seed,
+            mc_version,
+            nether_y_levels,
+        })
+    }
+}
+
+/// The validated configuration of the tileserver.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub address: SocketAddr,
+    pub tile_format: ImageFormat,
+    pub tile_formats: Vec<ImageFormat>,
+    pub memory_cache_bytes: usize,
+    pub tile_dir: PathBuf,
+    pub tile_store: TileStoreKind,
+    pub max_live_seeds: usize,
+    pub render_threads: usize,
+    pub disk_budget: DiskBudget,
+    /// Whether stored tiles are decoded on startup to find corrupt ones.
+    pub verify_tiles: bool,
+    pub worlds: Vec<WorldConfig>,
+    pub mbtiles: BTreeMap<String, PathBuf>,
+    pub pmtiles: BTreeMap<String, PathBuf>,
+    /// The layers of each composite, named like in tile urls, bottom first.
+    pub composites: BTreeMap<String, Vec<String>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            address: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 3000).into(),
+            tile_format: ImageFormat::Png,
+            tile_formats: vec![ImageFormat::Png, ImageFormat::WebP, ImageFormat::Jpeg],
+            memory_cache_bytes: 64 * 1024 * 1024,
+            tile_dir: "./tiles/".into(),
+            tile_store: TileStoreKind::Fs,
+            max_live_seeds: 8,
+            render_threads: available_parallelism().map_or(4, NonZeroUsize::get),
+            disk_budget: DiskBudget {
+                max_bytes: None,
+                max_tiles: None,
+                pinned_zoom: -4,
+            },
+            verify_tiles: false,
+            worlds: vec![WorldConfig {
+                name: DEFAULT_WORLD.into(),
+                seed: 3846517875239123423,
+                mc_version: MCVersion::MC_1_21_WD,
+                nether_y_levels: DEFAULT_NETHER_Y_LEVELS.into(),
+            }],
+            mbtiles: BTreeMap::new(),
+            pmtiles: BTreeMap::new(),
+            composites: BTreeMap::new(),
+        }
+    }
+}
+
+impl Config {
+    /// The formats of opaque layers, with the default format first.
+    pub fn base_formats(&self) -> Vec<ImageFormat> {
+        let mut formats = vec![self.tile_format];
+
+        for &format in &self.tile_formats {
+            if !formats.contains(&format) {
+                formats.push(format);
+            }
+        }
+
+        formats
+    }
+
+    /// The formats of transparent layers (eg. contours), which can't be jpeg.
+    ///
+    /// Falls back to png if no format with transparency is enabled.
+    pub fn overlay_formats(&self) -> Vec<ImageFormat> {
+        let formats: Vec<_> = self
+            .base_formats()
+            .into_iter()
+            .filter(|&format| format != ImageFormat::Jpeg)
+            .collect();
+
+        if formats.is_empty() {
+            vec![ImageFormat::Png]
+        } else {
+            formats
+        }
+    }
+
+    /// Loads the config file given in args (if any) and applies the overrides
+    /// from args on top of it.
+    pub fn load(args: Args) -> Result<Self, Error> {
+        let file = match args.config {
+            Some(path) => PartialConfig::from_file(path)?,
+            None => PartialConfig::default(),
+        };
+
+        Self::try_from(file.merge(args.overrides))
+    }
+}
+
+impl TryFrom<PartialConfig> for Config {
+    type Error = Error;
+
+    fn try_from(value: PartialConfig) -> Result<Self, Self::Error> {
+        let default = Config::default();
+
+        let worlds = match value.worlds {
+            Some(worlds) if !worlds.is_empty() && value.seed.is_some() => {
+                return Err(Error::DefaultWorldWithWorlds("seed"));
+            }
+            Some(worlds) if !worlds.is_empty() && value.mc_version.is_some() => {
+                return Err(Error::DefaultWorldWithWorlds("mc_version"));
+            }
+            Some(worlds) if !worlds.is_empty() => worlds
+                .into_iter()
+                .map(|(name, world)| WorldConfig::new(name, world))
+                .collect::<Result<_, _>>()?,
+            _ => vec![WorldConfig::new(
+                DEFAULT_WORLD.into(),
+                PartialWorldConfig {
+                    seed: value.seed.unwrap_or(default.worlds[0].seed),
+                    mc_version: value.mc_version,
+                    nether_y_levels: None,
+                },
+            )?],
+        };
+
+        let tile_format = match value.tile_format {
+            Some(format) => parse_tile_format(&format).ok_or(Error::InvalidTileFormat(format))?,
+            None => default.tile_format,
+        };
+
+        let tile_formats = match value.tile_formats {
+            Some(formats) => formats
+                .into_iter()
+                .map(|format| parse_tile_format(&format).ok_or(Error::InvalidTileFormat(format)))
+                .collect::<Result<_, _>>()?,
+            None => default.tile_formats,
+        };
+
+        let tile_store = match value.tile_store {
+            Some(store) => {
+                TileStoreKind::from_name(&store).ok_or(Error::InvalidTileStore(store))?
+            }
+            None => default.tile_store,
+        };
+
+        let memory_cache_bytes = value
+            .memory_cache_bytes
+            .unwrap_or(default.memory_cache_bytes);
+
+        if memory_cache_bytes == 0 {
+            return Err(Error::ZeroCacheSize);
+        }
+
+        let max_live_seeds = value.max_live_seeds.unwrap_or(default.max_live_seeds);
+
+        if max_live_seeds == 0 {
+            return Err(Error::ZeroLiveSeeds);
+        }
+
+        let render_threads = value.render_threads.unwrap_or(default.render_threads);
+
+        if render_threads == 0 {
+            return Err(Error::ZeroRenderThreads);
+        }
+
+        if value.disk_cache_bytes == Some(0) || value.disk_cache_tiles == Some(0) {
+            return Err(Error::ZeroDiskBudget);
+        }
+
+        let disk_budget = DiskBudget {
+            max_bytes: value.disk_cache_bytes,
+            max_tiles: value.disk_cache_tiles,
+            pinned_zoom: value
+                .disk_pinned_zoom
+                .unwrap_or(default.disk_budget.pinned_zoom),
+        };
+
+        let composites = value.composites.unwrap_or_default();
+
+        for (name, layers) in &composites {
+            if !is_valid_name(name) {
+                return Err(Error::InvalidCompositeName(name.clone()));
+            }
+
+            if layers.is_empty() {
+                return Err(Error::EmptyComposite(name.clone()));
+            }
+        }
+
+        Ok(Self {
+            address: value.address.unwrap_or(default.address),
+            tile_format,
+            tile_formats,
+            memory_cache_bytes,
+            tile_dir: value.tile_dir.unwrap_or(default.tile_dir),
+            tile_store,
+            max_live_seeds,
+            render_threads,
+            disk_budget,
+            verify_tiles: value.verify_tiles.unwrap_or(default.verify_tiles),
+            worlds,
+            mbtiles: value.mbtiles.unwrap_or_default(),
+            pmtiles: value.pmtiles.unwrap_or_default(),
+            composites,
+        })
+    }
+}
+
+/// Whether name can be used in urls and as a directory name.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+fn parse_tile_format(format: &str) -> Option<ImageFormat> {
+    match ImageFormat::from_extension(format)? {
+        format @ (ImageFormat::Png | ImageFormat::WebP | ImageFormat::Jpeg) => Some(format),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tileprovider::TestDir;
+
+    fn world(seed: i64) -> PartialWorldConfig {
+        PartialWorldConfig {
+            seed,
+            ..Default::default()
+        }
+    }
+
+    fn with_worlds(names: &[&str]) -> PartialConfig {
+        PartialConfig {
+            worlds: Some(
+                names
+                    .iter()
+                    .map(|&name| (name.to_owned(), world(1)))
+                    .collect(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn default_world_options_are_rejected_with_worlds() {
+        let config = PartialConfig {
+            seed: Some(1),
+            ..with_worlds(&["survival"])
+        };
+        assert!(matches!(
+            Config::try_from(config),
+            Err(Error::DefaultWorldWithWorlds("seed"))
+        ));
+
+        let config = PartialConfig {
+            mc_version: Some("1.20".into()),
+            ..with_worlds(&["survival"])
+        };
+        assert!(matches!(
+            Config::try_from(config),
+            Err(Error::DefaultWorldWithWorlds("mc_version"))
+        ));
+
+        let config = Config::try_from(PartialConfig {
+            seed: Some(1),
+            mc_version: Some("1.20".into()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(config.worlds[0].name, DEFAULT_WORLD);
+        assert_eq!(config.worlds[0].seed, 1);
+    }
+
+    #[test]
+    fn world_names_are_validated() {
+        for name in RESERVED_WORLD_NAMES {
+            assert!(matches!(
+                Config::try_from(with_worlds(&[name])),
+                Err(Error::ReservedWorldName(_))
+            ));
+        }
+
+        for name in ["", "Survival", "a/b", "a.b"] {
+            assert!(matches!(
+                Config::try_from(with_worlds(&[name])),
+                Err(Error::InvalidWorldName(_))
+            ));
+        }
+
+        let config = Config::try_from(with_worlds(&["survival", "creative-2"])).unwrap();
+        assert_eq!(config.worlds.len(), 2);
+    }
+
+    #[test]
+    fn arguments_override_env_vars_which_override_the_file() {
+        let dir = TestDir::new("config-precedence");
+        let path = dir.0.join("config.toml");
+        std::fs::write(
+            &path,
+            "memory_cache_bytes = 1\nmax_live_seeds = 1\nrender_threads = 1\n",
+        )
+        .unwrap();
+
+        // SAFETY: No other test reads these vars, or sets any.
+        unsafe {
+            std::env::set_var("BIOMEMAP_MAX_LIVE_SEEDS", "2");
+            std::env::set_var("BIOMEMAP_RENDER_THREADS", "2");
+        }
+        let args = Args::try_parse_from([
+            "biomemap-tileserver",
+            "--config",
+            path.to_str().unwrap(),
+            "--render-threads",
+            "3",
+        ]);
+        unsafe {
+            std::env::remove_var("BIOMEMAP_MAX_LIVE_SEEDS");
+            std::env::remove_var("BIOMEMAP_RENDER_THREADS");
+        }
+
+        let config = Config::load(args.unwrap()).unwrap();
+        assert_eq!(config.memory_cache_bytes, 1);
+        assert_eq!(config.max_live_seeds, 2);
+        assert_eq!(config.render_threads, 3);
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        assert!(toml::from_str::<PartialConfig>("tile_formt = \"png\"").is_err());
+        assert!(toml::from_str::<PartialConfig>("[worlds.survival]\nseed = 1\nsed = 2").is_err());
+        assert!(serde_json::from_str::<PartialConfig>(r#"{"tile_formt": "png"}"#).is_err());
+
+        let config: PartialConfig =
+            toml::from_str("tile_format = \"png\"\n[worlds.survival]\nseed = 1").unwrap();
+        assert_eq!(config.worlds.unwrap()["survival"].seed, 1);
+    }
+
+    #[test]
+    fn invalid_formats_and_stores_are_rejected() {
+        let config = |config: PartialConfig| Config::try_from(config);
+
+        assert!(matches!(
+            config(PartialConfig {
+                tile_format: Some("gif".into()),
+                ..Default::default()
+            }),
+            Err(Error::InvalidTileFormat(format)) if format == "gif"
+        ));
+        assert!(matches!(
+            config(PartialConfig {
+                tile_formats: Some(vec!["png".into(), "tiff".into()]),
+                ..Default::default()
+            }),
+            Err(Error::InvalidTileFormat(format)) if format == "tiff"
+        ));
+        assert!(matches!(
+            config(PartialConfig {
+                tile_store: Some("s3".into()),
+                ..Default::default()
+            }),
+            Err(Error::InvalidTileStore(store)) if store == "s3"
+        ));
+
+        let config = config(PartialConfig {
+            tile_format: Some("webp".into()),
+            tile_formats: Some(vec!["jpg".into()]),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            config.base_formats(),
+            [ImageFormat::WebP, ImageFormat::Jpeg]
+        );
+        assert_eq!(config.overlay_formats(), [ImageFormat::WebP]);
+    }
+
+    #[test]
+    fn nether_y_levels_are_within_the_nether() {
+        let nether = |y_levels: Vec<i32>| {
+            let mut config = with_worlds(&["survival"]);
+            config
+                .worlds
+                .as_mut()
+                .unwrap()
+                .get_mut("survival")
+                .unwrap()
+                .nether_y_levels = Some(y_levels);
+
+            Config::try_from(config)
+        };
+
+        assert!(matches!(
+            nether(vec![64, 256]),
+            Err(Error::InvalidNetherYLevel(256))
+        ));
+        assert!(matches!(
+            nether(vec![-1]),
+            Err(Error::InvalidNetherYLevel(-1))
+        ));
+        assert_eq!(
+            nether(vec![0, 255]).unwrap().worlds[0].nether_y_levels,
+            [0, 255]
+        );
+    }
+}
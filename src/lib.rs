@@ -1,2 +1,7 @@
 pub mod biomemap;
+pub mod config;
+pub mod prerender;
+pub mod staticmap;
+pub mod structures;
 pub mod tileprovider;
+pub mod world;
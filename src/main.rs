@@ -1,30 +1,64 @@
-use std::{error::Error, net::SocketAddrV4};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fs,
+    io::Cursor,
+    iter,
+    path::Path,
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
+};
 
+use actix_files::NamedFile;
 use actix_web::{
-    App, HttpResponse, HttpServer, Responder, get,
-    http::header::ContentType,
+    App, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder,
+    error::{
+        ErrorBadRequest, ErrorInternalServerError, ErrorNotAcceptable, ErrorNotFound,
+        ErrorServiceUnavailable,
+    },
+    get,
+    http::header::{
+        Accept, CacheControl, CacheDirective, ContentType, ETag, EntityTag, HttpDate,
+        IfModifiedSince, IfNoneMatch, LastModified, Quality, VARY,
+    },
+    post,
     web::{self, Data},
 };
 use biomemap_tileserver::{
-    biomemap::{CachePool, ContourLines, ShadedBiomeTile, UnshadedBiomeTile},
+    biomemap::{BiomeSample, BiomeSearch, CachePool, SURFACE_Y_LEVEL, overworld_biome_id},
+    config::{Args, Command, Config, DEFAULT_WORLD},
+    prerender, staticmap,
+    structures::{Landmarks, Structure, structure_type},
     tileprovider::{
-        TilePos,
-        tilecache::{self, TileCache},
+        TilePos, TileProvider, WORLD_BORDER,
+        tilecache::{RenderPool, TileCache},
+        tilestore::{TileStoreKind, remove_corrupt_tiles},
+    },
+    world::{
+        MbTilesRegistry, PmTilesRegistry, SeedWorlds, SurfaceLayers, World, WorldInfo,
+        WorldRegistry, parse_seed,
     },
 };
+use clap::Parser;
 use cubiomes::{
-    enums::MCVersion,
-    generator::{Generator, GeneratorFlags},
+    enums::{Dimension, MCVersion},
+    generator::Scale,
 };
 use image::ImageFormat;
+use log::{error, info};
+use serde::Deserialize;
+use serde_json::json;
 
-const SEED: i64 = 3846517875239123423;
-//const NOTILEPNG: &[u8] = include_bytes!("notile.png").as_slice();
+/// How long clients may use tiles without revalidating them, in seconds.
+const TILE_MAX_AGE: u32 = 7 * 24 * 60 * 60;
 
-// Note change urls if you change this
-const TILE_IMAGE_FORMAT: ImageFormat = image::ImageFormat::Png;
+/// When the tiles were last modified, see [tiles_modified].
+static TILES_MODIFIED: OnceLock<HttpDate> = OnceLock::new();
 
-const CACHED_TILE_AMOUNT: usize = 50000;
+//const NOTILEPNG: &[u8] = include_bytes!("notile.png").as_slice();
 
 #[actix_web::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -35,51 +69,95 @@ async fn main() -> Result<(), Box<dyn Error>> {
     }
     env_logger::init();
 
-    let address = SocketAddrV4::new("0.0.0.0".parse()?, 3000);
-    let g = Box::leak(Box::new(Generator::new(
-        MCVersion::MC_1_21_WD,
-        SEED,
-        cubiomes::enums::Dimension::DIM_OVERWORLD,
-        GeneratorFlags::empty(),
-    )));
-
-    let cache_pool = CachePool::new(g);
-
-    let shade_tile_cache = web::Data::new(TileCache::new(
-        ShadedBiomeTile::from(cache_pool.clone()),
-        CACHED_TILE_AMOUNT,
-        TILE_IMAGE_FORMAT,
-        "./tiles/shaded/",
-    )?);
-
-    let unsahded_tile_cahce = web::Data::new(TileCache::new(
-        UnshadedBiomeTile::from(cache_pool.clone()),
-        CACHED_TILE_AMOUNT,
-        TILE_IMAGE_FORMAT,
-        "./tiles/unshaded/",
-    )?);
-
-    let contour_line_cache = web::Data::new(TileCache::new(
-        ContourLines::from(cache_pool),
-        CACHED_TILE_AMOUNT,
-        TILE_IMAGE_FORMAT,
-        "./tiles/contour/",
-    )?);
+    let mut args = Args::parse();
+    let command = args.command.take();
+    TILES_MODIFIED.get_or_init(|| tiles_modified(args.config.as_deref()));
+
+    let config = match Config::load(args) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("invalid configuration: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(command) = command {
+        let result = match command {
+            Command::Seed(args) => prerender::seed(&config, args).await,
+            Command::Export(args) => prerender::export(&config, args).await,
+        };
+
+        if let Err(e) = result {
+            error!("{e}");
+            std::process::exit(1);
+        }
+
+        return Ok(());
+    }
+
+    let render_pool = RenderPool::new(config.render_threads);
+    let worlds = web::Data::new(WorldRegistry::new(&config, &render_pool)?);
+    let seeds = web::Data::new(SeedWorlds::new(&config, &render_pool));
+    let mbtiles = web::Data::new(MbTilesRegistry::new(&config, &render_pool)?);
+    let pmtiles = web::Data::new(PmTilesRegistry::new(&config, &render_pool)?);
+    let info = web::Data::new(config.clone());
+    let render_pool = web::Data::new(render_pool);
+
+    // Tiles are written atomically, so this can run while the server is
+    // already serving them.
+    if config.tile_store == TileStoreKind::Fs {
+        let tile_dir = config.tile_dir.clone();
+        let decode = config.verify_tiles;
+        actix_web::rt::task::spawn_blocking(move || {
+            match remove_corrupt_tiles(&tile_dir, decode) {
+                Ok(removed) => info!("integrity check done, removed {removed} corrupt tiles"),
+                Err(e) => error!("integrity check of {} failed: {e}", tile_dir.display()),
+            }
+        });
+    }
 
     HttpServer::new(move || {
         App::new()
-            .app_data(shade_tile_cache.clone())
-            .app_data(unsahded_tile_cahce.clone())
-            .app_data(contour_line_cache.clone())
+            .app_data(info.clone())
+            .app_data(worlds.clone())
+            .app_data(seeds.clone())
+            .app_data(mbtiles.clone())
+            .app_data(pmtiles.clone())
+            .app_data(render_pool.clone())
+            // Room for MAX_BIOME_POINTS points.
+            .app_data(web::JsonConfig::default().limit(256 * 1024))
             .service((
                 get_biome_tile,
                 get_biome_tile_shaded,
                 get_contour_tile,
+                get_structure_tile,
+                get_landmark_tile,
+                get_composite_tile,
+                get_nether_tile,
+                get_end_tile,
+                get_seed_tile,
+                get_mbtiles_tile,
+                get_pmtiles_archive,
+                get_pmtiles_tile,
+            ))
+            .service((
+                get_info,
+                get_stats,
+                get_biome,
+                post_biomes,
+                get_structures,
+                get_landmarks,
+                find_biome,
+                get_biome_areas,
+                get_render,
+            ))
+            // Registered last, since it matches every path.
+            .service(
                 actix_files::Files::new("/", concat!(env!("OUT_DIR"), "/pages"))
                     .index_file("index.html"),
-            ))
+            )
     })
-    .bind(address)?
+    .bind(config.address)?
     .run()
     .await?;
 
@@ -93,43 +171,1224 @@ async fn index() -> impl Responder {
         .body(include_str!("pages/index.html"))
 }
 
-#[get("/biomemap_shaded/{zoom}/{x}/{y}.png")]
-async fn get_biome_tile(
-    path: web::Path<(i32, i32, i32)>,
-    cache_pool: Data<TileCache<ShadedBiomeTile<'_>>>,
-) -> Result<impl Responder, tilecache::Error> {
-    let (zoom, x, y) = path.into_inner();
+/// Information the frontend needs to build tile urls.
+#[get("/api/info")]
+async fn get_info(
+    config: Data<Config>,
+    worlds: Data<WorldRegistry>,
+    mbtiles: Data<MbTilesRegistry>,
+    pmtiles: Data<PmTilesRegistry>,
+) -> impl Responder {
+    HttpResponse::Ok().json(json!({
+        "tile_extension": config.tile_format.extensions_str()[0],
+        "overlay_extension": config.overlay_formats()[0].extensions_str()[0],
+        "worlds": worlds.iter().map(World::info).collect::<Vec<WorldInfo>>(),
+        "mbtiles": mbtiles.names().collect::<Vec<_>>(),
+        "pmtiles": pmtiles.names().collect::<Vec<_>>(),
+    }))
+}
+
+/// Current memory cache usage in bytes, per world, for all seed worlds and in
+/// total.
+#[get("/api/stats")]
+async fn get_stats(worlds: Data<WorldRegistry>, seeds: Data<SeedWorlds>) -> impl Responder {
+    let world_usage = worlds
+        .iter()
+        .map(|world| (world.name(), world.memory_usage()))
+        .collect::<BTreeMap<_, _>>();
+    let seed_usage = seeds.memory_usage();
+
+    HttpResponse::Ok().json(json!({
+        "memory_usage": {
+            "total": world_usage.values().sum::<usize>() + seed_usage,
+            "worlds": world_usage,
+            "seeds": seed_usage,
+        },
+    }))
+}
+
+/// Most points a single request to the biome api may sample.
+const MAX_BIOME_POINTS: usize = 4096;
+
+/// Which world, dimension and scale the biome api samples.
+#[derive(Deserialize)]
+struct BiomeSource {
+    /// Defaults to [DEFAULT_WORLD].
+    world: Option<String>,
+    /// `overworld` (the default), `the_nether` or `the_end`.
+    dimension: Option<String>,
+    /// The blocks per sample, 1 (the default), 4, 16, 64 or 256.
+    scale: Option<u32>,
+}
+
+/// A block of the biome api. y defaults to [SURFACE_Y_LEVEL].
+#[derive(Deserialize)]
+struct BiomePoint {
+    x: i32,
+    y: Option<i32>,
+    z: i32,
+}
+
+#[derive(Deserialize)]
+struct BiomeQuery {
+    world: Option<String>,
+    dimension: Option<String>,
+    scale: Option<u32>,
+    x: i32,
+    y: Option<i32>,
+    z: i32,
+}
+
+#[derive(Deserialize)]
+struct BiomeBatch {
+    #[serde(flatten)]
+    source: BiomeSource,
+    points: Vec<BiomePoint>,
+}
+
+/// The biome at a block, eg. `/api/biome?x=100&z=-200&world=default`.
+#[get("/api/biome")]
+async fn get_biome(
+    query: web::Query<BiomeQuery>,
+    worlds: Data<WorldRegistry>,
+) -> Result<HttpResponse, actix_web::Error> {
+    // Flattening doesn't work with numbers in query strings, so the fields are
+    // copied instead.
+    let BiomeQuery {
+        world,
+        dimension,
+        scale,
+        x,
+        y,
+        z,
+    } = query.into_inner();
+    let source = BiomeSource {
+        world,
+        dimension,
+        scale,
+    };
+
+    let mut samples = sample_biomes(&worlds, source, vec![BiomePoint { x, y, z }]).await?;
+
+    Ok(HttpResponse::Ok().json(samples.pop()))
+}
+
+/// The biomes at many blocks at once, in the order of the points.
+#[post("/api/biome")]
+async fn post_biomes(
+    batch: web::Json<BiomeBatch>,
+    worlds: Data<WorldRegistry>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let BiomeBatch { source, points } = batch.into_inner();
+
+    if points.len() > MAX_BIOME_POINTS {
+        return Err(ErrorBadRequest(format!(
+            "at most {MAX_BIOME_POINTS} points can be sampled at once"
+        )));
+    }
+
+    Ok(HttpResponse::Ok().json(sample_biomes(&worlds, source, points).await?))
+}
+
+async fn sample_biomes(
+    worlds: &WorldRegistry,
+    source: BiomeSource,
+    points: Vec<BiomePoint>,
+) -> Result<Vec<BiomeSample>, actix_web::Error> {
+    let world = get_world(worlds, source.world.as_deref().unwrap_or(DEFAULT_WORLD))?;
+    let cache_pool = dimension_pool(world, source.dimension.as_deref())?;
+    let scale = parse_scale(source.scale.unwrap_or(1))?;
+
+    let points: Vec<_> = points
+        .into_iter()
+        .map(|point| (point.x, point.y.unwrap_or(SURFACE_Y_LEVEL), point.z))
+        .collect();
+
+    web::block(move || cache_pool.sample_biomes(&points, scale))
+        .await?
+        .map_err(ErrorInternalServerError)
+}
+
+/// The generator of a dimension of world, named `overworld` (the default),
+/// `the_nether` or `the_end`.
+fn dimension_pool(
+    world: &World,
+    dimension: Option<&str>,
+) -> Result<CachePool<'static>, actix_web::Error> {
+    let dimension = match dimension.unwrap_or("overworld") {
+        "overworld" => Dimension::DIM_OVERWORLD,
+        "the_nether" => Dimension::DIM_NETHER,
+        "the_end" => Dimension::DIM_END,
+        other => return Err(ErrorBadRequest(format!("no dimension named {other}"))),
+    };
+
+    world
+        .cache_pool(dimension)
+        .cloned()
+        .ok_or_else(|| ErrorNotFound(format!("{} has no {dimension:?}", world.name())))
+}
+
+/// The scale of blocks per sample.
+fn parse_scale(scale: u32) -> Result<Scale, actix_web::Error> {
+    match scale {
+        1 => Ok(Scale::Block),
+        4 => Ok(Scale::Quad),
+        16 => Ok(Scale::Chunk),
+        64 => Ok(Scale::QuadChunk),
+        256 => Ok(Scale::HalfRegion),
+        other => Err(ErrorBadRequest(format!(
+            "scale {other} is not one of 1, 4, 16, 64 or 256"
+        ))),
+    }
+}
+
+/// Parses a bbox in block coordinates, given as `min_x,min_z,max_x,max_z`.
+fn parse_bbox(bbox: &str) -> Result<[i32; 4], actix_web::Error> {
+    match bbox
+        .split(',')
+        .map(|coord| coord.trim().parse())
+        .collect::<Result<Vec<i32>, _>>()
+        .as_deref()
+    {
+        Ok(&[min_x, min_z, max_x, max_z]) if min_x <= max_x && min_z <= max_z => {
+            // Cubiomes doesn't generate sensible biomes (or finish) far beyond
+            // the border.
+            if [min_x, min_z, max_x, max_z]
+                .iter()
+                .any(|coord| coord.abs_diff(0) > WORLD_BORDER as u32)
+            {
+                return Err(ErrorBadRequest(format!(
+                    "bbox must be within the world border at {WORLD_BORDER} blocks"
+                )));
+            }
+
+            Ok([min_x, min_z, max_x, max_z])
+        }
+        _ => Err(ErrorBadRequest(
+            "bbox must be given as min_x,min_z,max_x,max_z with min <= max",
+        )),
+    }
+}
+
+/// Most samples a single request for biome areas may count.
+const MAX_AREA_SAMPLES: u64 = 1 << 20;
+
+#[derive(Deserialize)]
+struct BiomeAreaQuery {
+    /// Defaults to [DEFAULT_WORLD].
+    world: Option<String>,
+    dimension: Option<String>,
+    /// The blocks per sample, defaults to 16.
+    scale: Option<u32>,
+    /// Defaults to [SURFACE_Y_LEVEL].
+    y: Option<i32>,
+    /// The area to count in block coordinates, as `min_x,min_z,max_x,max_z`.
+    bbox: String,
+    /// `json` (the default) or `csv`.
+    format: Option<String>,
+}
+
+/// The area of every biome in a bbox, eg.
+/// `/api/biome/areas?bbox=-5000,-5000,5000,5000&scale=16&format=csv`.
+#[get("/api/biome/areas")]
+async fn get_biome_areas(
+    query: web::Query<BiomeAreaQuery>,
+    worlds: Data<WorldRegistry>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let BiomeAreaQuery {
+        world,
+        dimension,
+        scale,
+        y,
+        bbox,
+        format,
+    } = query.into_inner();
+    let world = get_world(&worlds, world.as_deref().unwrap_or(DEFAULT_WORLD))?;
+    let cache_pool = dimension_pool(world, dimension.as_deref())?;
+    let scale = parse_scale(scale.unwrap_or(16))?;
+    let bbox = parse_bbox(&bbox)?;
 
-    let tile = cache_pool.get_cached_tile(TilePos { zoom, x, y }).await?;
+    let is_csv = match format.as_deref().unwrap_or("json") {
+        "json" => false,
+        "csv" => true,
+        other => return Err(ErrorBadRequest(format!("no format named {other}"))),
+    };
+
+    // In i64, since the bbox can span the whole i32 range.
+    let cells = |min: i32, max: i32| {
+        let scale = scale as i64;
+        ((max as i64).div_euclid(scale) - (min as i64).div_euclid(scale) + 1) as u64
+    };
+    let [min_x, min_z, max_x, max_z] = bbox;
+    let samples = cells(min_x, max_x) * cells(min_z, max_z);
+
+    if samples > MAX_AREA_SAMPLES {
+        return Err(ErrorBadRequest(format!(
+            "the bbox takes {samples} samples at scale {}, at most {MAX_AREA_SAMPLES} can be \
+             counted at once",
+            scale as i32
+        )));
+    }
+
+    let y_level = y.unwrap_or(SURFACE_Y_LEVEL);
+    let areas = web::block(move || {
+        cache_pool
+            .biome_areas(bbox, y_level, scale)
+            .map_err(|e| e.to_string())
+    })
+    .await?
+    .map_err(ErrorInternalServerError)?;
+
+    if !is_csv {
+        return Ok(HttpResponse::Ok().json(areas));
+    }
+
+    let mut csv = String::from("id,name,area,percentage\n");
+    for area in areas {
+        csv += &format!(
+            "{},{},{},{:.4}\n",
+            area.id, area.name, area.area, area.percentage
+        );
+    }
 
     Ok(HttpResponse::Ok()
-        .content_type(cache_pool.format().to_mime_type())
-        .body(tile))
+        .content_type("text/csv; charset=utf-8")
+        .body(csv))
 }
 
-#[get("/biomemap/{zoom}/{x}/{y}.png")]
-async fn get_biome_tile_shaded(
-    path: web::Path<(i32, i32, i32)>,
-    cache_pool: Data<TileCache<UnshadedBiomeTile<'_>>>,
-) -> Result<impl Responder, tilecache::Error> {
-    let (zoom, x, y) = path.into_inner();
-    let tile = cache_pool.get_cached_tile(TilePos::new(zoom, x, y)).await?;
+/// Most pixels a single static map may have.
+const MAX_RENDER_PIXELS: u64 = 1 << 22;
+
+#[derive(Deserialize)]
+struct RenderQuery {
+    /// Defaults to [DEFAULT_WORLD].
+    world: Option<String>,
+    x0: i32,
+    z0: i32,
+    x1: i32,
+    z1: i32,
+    /// The width of the image in pixels, the height follows from the area.
+    width: u32,
+    /// Comma separated layers, named like in tile urls (or `shaded` for
+    /// `biomemap_shaded`), drawn in order. Defaults to `shaded`.
+    layers: Option<String>,
+}
+
+/// A png of the blocks from x0, z0 to x1, z1 with the layers drawn on top of
+/// each other, eg.
+/// `/api/render?x0=-1000&z0=-1000&x1=1000&z1=1000&width=512&layers=shaded,
+/// contours`.
+#[get("/api/render")]
+async fn get_render(
+    query: web::Query<RenderQuery>,
+    worlds: Data<WorldRegistry>,
+    render_pool: Data<RenderPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let RenderQuery {
+        world,
+        x0,
+        z0,
+        x1,
+        z1,
+        width,
+        layers,
+    } = query.into_inner();
+    let world = get_world(&worlds, world.as_deref().unwrap_or(DEFAULT_WORLD))?;
+
+    if x0 >= x1 || z0 >= z1 {
+        return Err(ErrorBadRequest("x0 and z0 must be less than x1 and z1"));
+    }
+
+    let height =
+        (width as u64 * (z1 as i64 - z0 as i64) as u64 / (x1 as i64 - x0 as i64) as u64).max(1);
+    if width == 0 || width as u64 * height > MAX_RENDER_PIXELS {
+        return Err(ErrorBadRequest(format!(
+            "the image would have {width}x{height} pixels, at most {MAX_RENDER_PIXELS} can be \
+             rendered at once"
+        )));
+    }
+
+    let layers = layers
+        .as_deref()
+        .unwrap_or("shaded")
+        .split(',')
+        .map(|name| {
+            let name = if name == "shaded" {
+                "biomemap_shaded"
+            } else {
+                name
+            };
+            world
+                .layer(name)
+                .ok_or_else(|| ErrorNotFound(format!("{} has no layer named {name}", world.name())))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let image = staticmap::render_area(
+        &layers,
+        [x0, z0, x1, z1],
+        width,
+        height as u32,
+        &render_pool,
+    )
+    .await?;
+
+    let mut png = Cursor::new(Vec::new());
+    image
+        .write_to(&mut png, ImageFormat::Png)
+        .map_err(ErrorInternalServerError)?;
 
     Ok(HttpResponse::Ok()
-        .content_type(cache_pool.format().to_mime_type())
-        .body(tile))
+        .content_type(ContentType::png())
+        .body(png.into_inner()))
+}
+
+/// Most structure regions a single request to the structure api may check.
+const MAX_STRUCTURE_REGIONS: u64 = 16384;
+
+#[derive(Deserialize)]
+struct StructureQuery {
+    /// Defaults to [DEFAULT_WORLD].
+    world: Option<String>,
+    /// The area to search in block coordinates, as `min_x,min_z,max_x,max_z`.
+    bbox: String,
+    /// Comma separated structure names, defaults to every structure.
+    types: Option<String>,
+}
+
+/// The structures in an area of the overworld, eg.
+/// `/api/structures?bbox=-1000,-1000,1000,1000&types=village,monument`.
+#[get("/api/structures")]
+async fn get_structures(
+    query: web::Query<StructureQuery>,
+    worlds: Data<WorldRegistry>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let StructureQuery { world, bbox, types } = query.into_inner();
+    let world = get_world(&worlds, world.as_deref().unwrap_or(DEFAULT_WORLD))?;
+    let finder = world.structure_finder().clone();
+
+    let bbox = parse_bbox(&bbox)?;
+
+    let types = match types {
+        Some(types) => types
+            .split(',')
+            .map(|name| {
+                structure_type(name)
+                    .filter(|structure_type| finder.types().contains(structure_type))
+                    .ok_or_else(|| {
+                        ErrorBadRequest(format!("{} has no structures named {name}", world.name()))
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        None => finder.types().to_vec(),
+    };
+
+    if finder.region_count(bbox, &types) > MAX_STRUCTURE_REGIONS {
+        return Err(ErrorBadRequest(format!(
+            "at most {MAX_STRUCTURE_REGIONS} structure regions can be searched at once"
+        )));
+    }
+
+    let structures: Vec<Structure> = web::block(move || finder.find(bbox, &types)).await?;
+
+    Ok(HttpResponse::Ok().json(structures))
+}
+
+#[derive(Deserialize)]
+struct WorldQuery {
+    /// Defaults to [DEFAULT_WORLD].
+    world: Option<String>,
+}
+
+/// The world spawn and the strongholds of the overworld, as a GeoJSON feature
+/// collection of points with block x, z coordinates.
+#[get("/api/landmarks")]
+async fn get_landmarks(
+    query: web::Query<WorldQuery>,
+    worlds: Data<WorldRegistry>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let world = get_world(&worlds, query.world.as_deref().unwrap_or(DEFAULT_WORLD))?;
+    let finder = world.structure_finder().clone();
+
+    // Finding them takes a while the first time.
+    let Landmarks { spawn, strongholds } = web::block(move || finder.landmarks().clone()).await?;
+
+    let point = |x: i32, z: i32, properties: serde_json::Value| {
+        json!({
+            "type": "Feature",
+            "geometry": {"type": "Point", "coordinates": [x, z]},
+            "properties": properties,
+        })
+    };
+
+    let features: Vec<_> = iter::once(point(spawn.x, spawn.z, json!({"type": "spawn"})))
+        .chain(strongholds.iter().map(|stronghold| {
+            point(
+                stronghold.x,
+                stronghold.z,
+                json!({"type": "stronghold", "ring": stronghold.ring}),
+            )
+        }))
+        .collect();
+
+    Ok(HttpResponse::Ok().json(json!({
+        "type": "FeatureCollection",
+        "features": features,
+    })))
+}
+
+/// The radius in blocks the biome search covers by default.
+const DEFAULT_FIND_RADIUS: i32 = 4096;
+
+/// The largest radius in blocks the biome search may cover.
+const MAX_FIND_RADIUS: i32 = 65536;
+
+/// How long a biome search may take.
+const FIND_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+struct FindBiomeQuery {
+    /// Defaults to [DEFAULT_WORLD].
+    world: Option<String>,
+    biome: String,
+    /// The block to search from, defaults to 0, 0.
+    x: Option<i32>,
+    z: Option<i32>,
+    /// Defaults to [DEFAULT_FIND_RADIUS].
+    radius: Option<i32>,
+}
+
+/// Sets its flag when dropped, eg. when actix drops the future of a request
+/// whose client disconnected.
+#[derive(Default)]
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// The nearest block of a biome on the surface of the overworld, eg.
+/// `/api/find/biome?biome=cherry_grove&x=100&z=-200&radius=8192`.
+#[get("/api/find/biome")]
+async fn find_biome(
+    query: web::Query<FindBiomeQuery>,
+    worlds: Data<WorldRegistry>,
+    render_pool: Data<RenderPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let FindBiomeQuery {
+        world,
+        biome,
+        x,
+        z,
+        radius,
+    } = query.into_inner();
+    let world = get_world(&worlds, world.as_deref().unwrap_or(DEFAULT_WORLD))?;
+    let (x, z) = (x.unwrap_or(0), z.unwrap_or(0));
+    let radius = radius.unwrap_or(DEFAULT_FIND_RADIUS);
+
+    let biome_id = overworld_biome_id(&biome, world.mc_version()).ok_or_else(|| {
+        ErrorBadRequest(format!(
+            "no biome named {biome} generates in the overworld of {}",
+            world.mc_version()
+        ))
+    })?;
+
+    if !(1..=MAX_FIND_RADIUS).contains(&radius) {
+        return Err(ErrorBadRequest(format!(
+            "radius must be between 1 and {MAX_FIND_RADIUS}"
+        )));
+    }
+
+    if x.abs_diff(0) > WORLD_BORDER as u32 || z.abs_diff(0) > WORLD_BORDER as u32 {
+        return Err(ErrorBadRequest(format!(
+            "x and z must be within the world border at {WORLD_BORDER} blocks"
+        )));
+    }
+
+    let cache_pool = world
+        .cache_pool(Dimension::DIM_OVERWORLD)
+        .expect("every world has an overworld")
+        .clone();
+
+    // The search stops early if the request is dropped.
+    let cancel = CancelOnDrop::default();
+    let cancelled = cancel.0.clone();
+    let deadline = Instant::now() + FIND_TIMEOUT;
+
+    // Searches run on the render pool, so they count against the tiles
+    // rendered at once.
+    let search = render_pool
+        .run(move || {
+            cache_pool
+                .find_biome(biome_id, x, z, radius, deadline, &cancelled)
+                .map_err(|e| e.to_string())
+        })
+        .await
+        .map_err(ErrorInternalServerError)?
+        .map_err(ErrorInternalServerError)?;
+
+    match search {
+        BiomeSearch::Found(found) => Ok(HttpResponse::Ok().json(json!({
+            "biome": biome,
+            "x": found.x,
+            "z": found.z,
+            "distance": found.distance,
+        }))),
+        BiomeSearch::NotFound => Err(ErrorNotFound(format!(
+            "no {biome} within {radius} blocks of {x}, {z}"
+        ))),
+        BiomeSearch::OutOfTime => Err(ErrorServiceUnavailable(format!(
+            "searching took longer than {}s, try a smaller radius",
+            FIND_TIMEOUT.as_secs()
+        ))),
+        BiomeSearch::Cancelled => Err(ErrorServiceUnavailable("the search was cancelled")),
+    }
+}
+
+/// The part of a tile url after the layer,
+/// `{zoom}/{x}/{y}[@{scale}x][.{ext}]`.
+#[derive(Deserialize)]
+struct TileCoords {
+    zoom: i32,
+    x: i32,
+    y: i32,
+    /// Empty or `@2x`/`@4x`, as matched by the route.
+    scale: String,
+    /// Empty or the extension with a leading dot.
+    ext: String,
+}
+
+impl TileCoords {
+    fn pos(&self) -> TilePos {
+        let scale = self
+            .scale
+            .trim_start_matches('@')
+            .trim_end_matches('x')
+            .parse()
+            .unwrap_or(1);
+
+        TilePos::new(self.zoom, self.x, self.y).with_scale(scale)
+    }
+}
+
+#[derive(Deserialize)]
+struct WorldPath {
+    world: String,
+}
+
+#[get(r"/{world}/biomemap_shaded/{zoom}/{x}/{y:-?\d+}{scale:(@[24]x)?}{ext:(\.[a-z]+)?}")]
+async fn get_biome_tile_shaded(
+    req: HttpRequest,
+    path: web::Path<WorldPath>,
+    coords: web::Path<TileCoords>,
+    worlds: Data<WorldRegistry>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let world = get_world(&worlds, &path.world)?;
+
+    serve_tile(
+        &req,
+        &world.overworld.shaded,
+        &world_tag(world, "biomemap_shaded"),
+        &coords,
+    )
+    .await
+}
+
+#[get(r"/{world}/biomemap/{zoom}/{x}/{y:-?\d+}{scale:(@[24]x)?}{ext:(\.[a-z]+)?}")]
+async fn get_biome_tile(
+    req: HttpRequest,
+    path: web::Path<WorldPath>,
+    coords: web::Path<TileCoords>,
+    worlds: Data<WorldRegistry>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let world = get_world(&worlds, &path.world)?;
+
+    serve_tile(
+        &req,
+        &world.overworld.unshaded,
+        &world_tag(world, "biomemap"),
+        &coords,
+    )
+    .await
 }
 
-#[get("/contours/{zoom}/{x}/{y}.png")]
+#[get(r"/{world}/contours/{zoom}/{x}/{y:-?\d+}{scale:(@[24]x)?}{ext:(\.[a-z]+)?}")]
 async fn get_contour_tile(
-    path: web::Path<(i32, i32, i32)>,
-    cache_pool: Data<TileCache<ContourLines<'_>>>,
-) -> Result<impl Responder, tilecache::Error> {
-    let (zoom, x, y) = path.into_inner();
+    req: HttpRequest,
+    path: web::Path<WorldPath>,
+    coords: web::Path<TileCoords>,
+    worlds: Data<WorldRegistry>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let world = get_world(&worlds, &path.world)?;
 
-    let tile = cache_pool.get_cached_tile(TilePos::new(zoom, x, y)).await?;
+    serve_tile(
+        &req,
+        &world.overworld.contours,
+        &world_tag(world, "contours"),
+        &coords,
+    )
+    .await
+}
 
-    Ok(HttpResponse::Ok()
-        .content_type(cache_pool.format().to_mime_type())
-        .body(tile))
+#[get(r"/{world}/structures/{zoom}/{x}/{y:-?\d+}{scale:(@[24]x)?}{ext:(\.[a-z]+)?}")]
+async fn get_structure_tile(
+    req: HttpRequest,
+    path: web::Path<WorldPath>,
+    coords: web::Path<TileCoords>,
+    worlds: Data<WorldRegistry>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let world = get_world(&worlds, &path.world)?;
+
+    serve_tile(
+        &req,
+        &world.structures,
+        &world_tag(world, "structures"),
+        &coords,
+    )
+    .await
+}
+
+#[get(r"/{world}/landmarks/{zoom}/{x}/{y:-?\d+}{scale:(@[24]x)?}{ext:(\.[a-z]+)?}")]
+async fn get_landmark_tile(
+    req: HttpRequest,
+    path: web::Path<WorldPath>,
+    coords: web::Path<TileCoords>,
+    worlds: Data<WorldRegistry>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let world = get_world(&worlds, &path.world)?;
+
+    serve_tile(
+        &req,
+        &world.landmarks,
+        &world_tag(world, "landmarks"),
+        &coords,
+    )
+    .await
+}
+
+#[derive(Deserialize)]
+struct CompositePath {
+    world: String,
+    name: String,
+}
+
+#[get(r"/{world}/composite/{name}/{zoom}/{x}/{y:-?\d+}{scale:(@[24]x)?}{ext:(\.[a-z]+)?}")]
+async fn get_composite_tile(
+    req: HttpRequest,
+    path: web::Path<CompositePath>,
+    coords: web::Path<TileCoords>,
+    worlds: Data<WorldRegistry>,
+    config: Data<Config>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let CompositePath { world, name } = path.into_inner();
+    let world = get_world(&worlds, &world)?;
+    let cache = world
+        .composites
+        .get(&name)
+        .ok_or_else(|| ErrorNotFound(format!("{} has no composite named {name}", world.name())))?;
+
+    serve_tile(
+        &req,
+        cache,
+        // The layers are part of the tag, so tiles change with them.
+        &world_tag(
+            world,
+            &format!("composite-{name}-{}", config.composites[&name].join("+")),
+        ),
+        &coords,
+    )
+    .await
+}
+
+#[derive(Deserialize)]
+struct NetherPath {
+    world: String,
+    y_level: i32,
+}
+
+#[get(r"/{world}/the_nether/{y_level}/biomemap/{zoom}/{x}/{y:-?\d+}{scale:(@[24]x)?}{ext:(\.[a-z]+)?}")]
+async fn get_nether_tile(
+    req: HttpRequest,
+    path: web::Path<NetherPath>,
+    coords: web::Path<TileCoords>,
+    worlds: Data<WorldRegistry>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let NetherPath { world, y_level } = path.into_inner();
+    let world = get_world(&worlds, &world)?;
+    let cache = world
+        .nether
+        .get(&y_level)
+        .ok_or_else(|| ErrorNotFound(format!("nether is not rendered at y level {y_level}")))?;
+
+    serve_tile(
+        &req,
+        cache,
+        &world_tag(world, &format!("the_nether-{y_level}")),
+        &coords,
+    )
+    .await
+}
+
+#[derive(Deserialize)]
+struct EndPath {
+    world: String,
+    layer: String,
+}
+
+#[get(r"/{world}/the_end/{layer}/{zoom}/{x}/{y:-?\d+}{scale:(@[24]x)?}{ext:(\.[a-z]+)?}")]
+async fn get_end_tile(
+    req: HttpRequest,
+    path: web::Path<EndPath>,
+    coords: web::Path<TileCoords>,
+    worlds: Data<WorldRegistry>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let world = get_world(&worlds, &path.world)?;
+    let end = world
+        .end
+        .as_ref()
+        .ok_or_else(|| ErrorNotFound(format!("world {} has no end", path.world)))?;
+
+    serve_surface_tile(
+        &req,
+        end,
+        &world_tag(world, "the_end"),
+        &path.layer,
+        &coords,
+    )
+    .await
+}
+
+#[derive(Deserialize)]
+struct SeedPath {
+    seed: String,
+    version: String,
+    layer: String,
+}
+
+/// Tiles for any seed, without it needing to be configured.
+#[get(r"/seed/{seed}/{version}/{layer}/{zoom}/{x}/{y:-?\d+}{scale:(@[24]x)?}{ext:(\.[a-z]+)?}")]
+async fn get_seed_tile(
+    req: HttpRequest,
+    path: web::Path<SeedPath>,
+    coords: web::Path<TileCoords>,
+    seeds: Data<SeedWorlds>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let SeedPath {
+        seed,
+        version,
+        layer,
+    } = path.into_inner();
+
+    let mc_version: MCVersion = version
+        .parse()
+        .map_err(|_| ErrorBadRequest(format!("{version} is not a valid minecraft version")))?;
+
+    // Surface height approximation (used for shading) panics for beta
+    if mc_version < MCVersion::MC_1_0 {
+        return Err(ErrorBadRequest("beta versions are not supported"));
+    }
+
+    let seed = parse_seed(&seed);
+    let world = seeds.get(seed, mc_version).await?;
+
+    serve_surface_tile(
+        &req,
+        world.layers(),
+        &format!("{seed}-{mc_version:?}"),
+        &layer,
+        &coords,
+    )
+    .await
+}
+
+#[derive(Deserialize)]
+struct MbTilesPath {
+    name: String,
+}
+
+/// Tiles of the MBTiles files in the config.
+#[get(r"/mbtiles/{name}/{zoom}/{x}/{y:-?\d+}{scale:(@[24]x)?}{ext:(\.[a-z]+)?}")]
+async fn get_mbtiles_tile(
+    req: HttpRequest,
+    path: web::Path<MbTilesPath>,
+    coords: web::Path<TileCoords>,
+    mbtiles: Data<MbTilesRegistry>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let cache = mbtiles
+        .get(&path.name)
+        .ok_or_else(|| ErrorNotFound(format!("no mbtiles file named {}", path.name)))?;
+
+    serve_tile(&req, cache, &format!("mbtiles-{}", path.name), &coords).await
+}
+
+#[derive(Deserialize)]
+struct PmTilesPath {
+    name: String,
+}
+
+/// The whole PMTiles archive, for clients which read tiles out of it with range
+/// requests themselves.
+#[get(r"/pmtiles/{name:[^/.]+}.pmtiles")]
+async fn get_pmtiles_archive(
+    path: web::Path<PmTilesPath>,
+    pmtiles: Data<PmTilesRegistry>,
+) -> Result<NamedFile, actix_web::Error> {
+    let archive = pmtiles
+        .get(&path.name)
+        .ok_or_else(|| ErrorNotFound(format!("no pmtiles archive named {}", path.name)))?;
+
+    // Range requests are handled by NamedFile.
+    Ok(NamedFile::open_async(&archive.path).await?)
+}
+
+/// Tiles read out of the PMTiles archives in the config.
+#[get(r"/pmtiles/{name}/{zoom}/{x}/{y:-?\d+}{scale:(@[24]x)?}{ext:(\.[a-z]+)?}")]
+async fn get_pmtiles_tile(
+    req: HttpRequest,
+    path: web::Path<PmTilesPath>,
+    coords: web::Path<TileCoords>,
+    pmtiles: Data<PmTilesRegistry>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let archive = pmtiles
+        .get(&path.name)
+        .ok_or_else(|| ErrorNotFound(format!("no pmtiles archive named {}", path.name)))?;
+
+    serve_tile(
+        &req,
+        &archive.tiles,
+        &format!("pmtiles-{}", path.name),
+        &coords,
+    )
+    .await
+}
+
+async fn serve_surface_tile(
+    req: &HttpRequest,
+    layers: &SurfaceLayers,
+    tag: &str,
+    layer: &str,
+    coords: &TileCoords,
+) -> Result<HttpResponse, actix_web::Error> {
+    let tag = format!("{tag}-{layer}");
+
+    match layer {
+        "biomemap" => serve_tile(req, &layers.unshaded, &tag, coords).await,
+        "biomemap_shaded" => serve_tile(req, &layers.shaded, &tag, coords).await,
+        "contours" => serve_tile(req, &layers.contours, &tag, coords).await,
+        _ => Err(ErrorNotFound(format!("no layer named {layer}"))),
+    }
+}
+
+fn get_world<'a>(worlds: &'a WorldRegistry, name: &str) -> Result<&'a World, actix_web::Error> {
+    worlds
+        .get(name)
+        .ok_or_else(|| ErrorNotFound(format!("no world named {name}")))
+}
+
+/// Identifies the tiles of a layer of a world, for etags.
+///
+/// Uses the seed and version instead of the name, so etags change if the world
+/// is reconfigured.
+fn world_tag(world: &World, layer: &str) -> String {
+    format!("{}-{:?}-{layer}", world.seed(), world.mc_version())
+}
+
+/// Serves a tile of cache with caching headers, or 304 if the client already
+/// has it.
+///
+/// Tiles are deterministic, so the etag is built from tag (which must identify
+/// the world and layer), the position and when the tiles were last modified.
+///
+/// Only tiles which are already cached get a 304, others are rendered and sent
+/// in full, so a 304 never needs a render and is only sent for existing tiles.
+async fn serve_tile<S: TileProvider + Send + Sync + 'static>(
+    req: &HttpRequest,
+    cache: &TileCache<S>,
+    tag: &str,
+    coords: &TileCoords,
+) -> Result<HttpResponse, actix_web::Error> {
+    let pos = coords.pos();
+    let format = negotiate_format(req, cache, &coords.ext)?;
+
+    let last_modified = *TILES_MODIFIED
+        .get()
+        .expect("set before the server is started");
+    // Changes along with Last-Modified, when the binary or config does.
+    let modified_secs = SystemTime::from(last_modified)
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let etag = EntityTag::new_strong(format!(
+        "{tag}-{}-{}-{}@{}x-{}-{}-{modified_secs:x}",
+        pos.zoom,
+        pos.x,
+        pos.y,
+        pos.scale,
+        format.extensions_str()[0],
+        env!("CARGO_PKG_VERSION")
+    ));
+
+    let is_not_modified = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|other| other.weak_eq(&etag)),
+        // If-Modified-Since is ignored when If-None-Match is sent.
+        None => req
+            .get_header::<IfModifiedSince>()
+            .is_some_and(|since| since.0 >= last_modified),
+    } && cache.is_cached(pos, format).await?;
+
+    let mut response = if is_not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    response
+        .insert_header(ETag(etag))
+        .insert_header(LastModified(last_modified))
+        .insert_header(CacheControl(vec![
+            CacheDirective::Public,
+            CacheDirective::MaxAge(TILE_MAX_AGE),
+        ]));
+
+    if coords.ext.is_empty() {
+        response.insert_header((VARY, "Accept"));
+    }
+
+    if is_not_modified {
+        return Ok(response.finish());
+    }
+
+    let tile = cache.get_cached_tile(pos, format).await?;
+
+    Ok(response.content_type(format.to_mime_type()).body(tile))
+}
+
+/// Tiles only change when the tileserver or its config file is updated, so
+/// they were last modified when the newer of the two was, which stays the same
+/// across restarts. Falls back to now if neither can be checked.
+fn tiles_modified(config: Option<&Path>) -> HttpDate {
+    let exe = std::env::current_exe().ok();
+
+    exe.as_deref()
+        .into_iter()
+        .chain(config)
+        .filter_map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+        .max()
+        .unwrap_or_else(SystemTime::now)
+        .into()
+}
+
+/// Picks the format of a tile from the extension in the url (eg. `.webp`), or
+/// the Accept header if the url has none.
+fn negotiate_format<S: TileProvider + Send + Sync + 'static>(
+    req: &HttpRequest,
+    cache: &TileCache<S>,
+    ext: &str,
+) -> Result<ImageFormat, actix_web::Error> {
+    if let Some(ext) = ext.strip_prefix('.') {
+        return ImageFormat::from_extension(ext)
+            .filter(|&format| cache.supports(format))
+            .ok_or_else(|| {
+                ErrorNotFound(format!("tiles of this layer can't be served as .{ext}"))
+            });
+    }
+
+    let Some(accept) = req.get_header::<Accept>() else {
+        return Ok(cache.default_format());
+    };
+
+    // Formats listed with q=0 are refused, even if a wildcard matches them.
+    let refused = accept
+        .iter()
+        .filter(|item| item.quality == Quality::ZERO)
+        .map(|item| item.item.essence_str())
+        .collect::<Vec<_>>();
+    let accepted = |format: &ImageFormat| !refused.contains(&format.to_mime_type());
+
+    for mime in accept.ranked() {
+        if refused.contains(&mime.essence_str()) {
+            continue;
+        }
+
+        match (mime.type_().as_str(), mime.subtype().as_str()) {
+            ("*", "*") | ("image", "*") => {
+                if let Some(&format) = cache.formats().iter().find(|format| accepted(format)) {
+                    return Ok(format);
+                }
+            }
+            _ => {
+                if let Some(format) = ImageFormat::from_mime_type(mime.essence_str())
+                    && cache.supports(format)
+                {
+                    return Ok(format);
+                }
+            }
+        }
+    }
+
+    Err(ErrorNotAcceptable(format!(
+        "tiles of this layer can only be served as {}",
+        cache
+            .formats()
+            .iter()
+            .map(|format| format.to_mime_type())
+            .collect::<Vec<_>>()
+            .join(", ")
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    use actix_web::{
+        http::{StatusCode, header},
+        test,
+    };
+    use biomemap_tileserver::tileprovider::{
+        Blacktile, ProviderError, diskindex::DiskBudget, tilestore::MemoryStore,
+    };
+    use image::DynamicImage;
+
+    use super::*;
+
+    /// Black tiles at zoom 0 and up, counting how many were rendered.
+    #[derive(Clone, Default)]
+    struct Renders(Arc<AtomicUsize>);
+
+    impl TileProvider for Renders {
+        fn get_tile(&self, pos: TilePos) -> Result<DynamicImage, ProviderError> {
+            if pos.zoom < 0 {
+                return Err(ProviderError::ZoomOutOfRange(pos.zoom));
+            }
+
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Blacktile.get_tile(pos)
+        }
+    }
+
+    async fn test_tile(
+        req: HttpRequest,
+        coords: web::Path<TileCoords>,
+        cache: Data<TileCache<Renders>>,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        serve_tile(&req, &cache, "test", &coords).await
+    }
+
+    /// The route of the test tiles.
+    const TILE_ROUTE: &str = r"/{zoom}/{x}/{y:-?\d+}{scale:(@[24]x)?}{ext:(\.[a-z]+)?}";
+
+    fn cache(source: Renders) -> Data<TileCache<Renders>> {
+        TILES_MODIFIED.get_or_init(|| SystemTime::UNIX_EPOCH.into());
+
+        Data::new(
+            TileCache::new(
+                source,
+                1024 * 1024,
+                vec![ImageFormat::Png],
+                Arc::new(MemoryStore::new()),
+                RenderPool::new(1),
+                DiskBudget::UNLIMITED,
+            )
+            .unwrap(),
+        )
+    }
+
+    #[actix_web::test]
+    async fn answers_304_for_cached_tiles() {
+        let source = Renders::default();
+        let app = test::init_service(
+            App::new()
+                .app_data(cache(source.clone()))
+                .route(TILE_ROUTE, web::get().to(test_tile)),
+        )
+        .await;
+
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri("/0/1/2").to_request()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers().get(header::ETAG).unwrap().clone();
+        let last_modified = response
+            .headers()
+            .get(header::LAST_MODIFIED)
+            .unwrap()
+            .clone();
+
+        for (name, value) in [
+            (header::IF_NONE_MATCH, etag.clone()),
+            (header::IF_MODIFIED_SINCE, last_modified),
+        ] {
+            let request = test::TestRequest::get()
+                .uri("/0/1/2")
+                .insert_header((name, value))
+                .to_request();
+            let response = test::call_service(&app, request).await;
+
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+            assert_eq!(response.headers().get(header::ETAG), Some(&etag));
+        }
+
+        assert_eq!(source.0.load(Ordering::Relaxed), 1);
+    }
+
+    #[actix_web::test]
+    async fn etags_differ_per_position() {
+        let app = test::init_service(
+            App::new()
+                .app_data(cache(Renders::default()))
+                .route(TILE_ROUTE, web::get().to(test_tile)),
+        )
+        .await;
+
+        let etag = |uri| {
+            let request = test::TestRequest::get().uri(uri).to_request();
+            let app = &app;
+            async move {
+                test::call_service(app, request)
+                    .await
+                    .headers()
+                    .get(header::ETAG)
+                    .unwrap()
+                    .clone()
+            }
+        };
+
+        assert_ne!(etag("/0/1/2").await, etag("/0/2/1").await);
+        assert_ne!(etag("/0/1/2").await, etag("/0/1/2@2x").await);
+    }
+
+    #[actix_web::test]
+    async fn conditional_requests_for_uncached_tiles_get_the_tile() {
+        let source = Renders::default();
+        let app = test::init_service(
+            App::new()
+                .app_data(cache(source.clone()))
+                .route(TILE_ROUTE, web::get().to(test_tile)),
+        )
+        .await;
+        let last_modified = HttpDate::from(SystemTime::now());
+
+        let request = test::TestRequest::get()
+            .uri("/0/1/2")
+            .insert_header(IfModifiedSince(last_modified))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+
+        // Never cached, so it is rendered and sent instead of a 304.
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(source.0.load(Ordering::Relaxed), 1);
+
+        let request = test::TestRequest::get()
+            .uri("/-1/0/0")
+            .insert_header(IfModifiedSince(last_modified))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, request).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
 }
@@ -4,6 +4,8 @@ const origin = window.location.origin
 
 class MousePositionControl extends leaflet.Control {
     element: HTMLElement;
+    position: string = "";
+    biome: string = "";
 
     constructor() {
         super({ position: "bottomleft" });
@@ -15,51 +17,270 @@ class MousePositionControl extends leaflet.Control {
         return latlng;
     }
 
-    update(latlng: leaflet.LatLng, zoom: number) {
-        this.element.innerHTML = `x: ${Math.round(latlng.lng)} z: ${Math.round(latlng.lat)} zoom: ${zoom}`;
+    update(x: number, z: number, zoom: number) {
+        this.position = `x: ${x} z: ${z} zoom: ${zoom}`;
+        this.render();
+    }
+
+    setBiome(biome: string) {
+        this.biome = biome;
+        this.render();
+    }
+
+    render() {
+        this.element.textContent = this.biome ? `${this.position} biome: ${this.biome}` : this.position;
     }
 }
 
+interface BiomeSample {
+    id: number;
+    name: string;
+    surface_height: number | null;
+}
+
+
+class WorldSelectControl extends leaflet.Control {
+    worlds: WorldInfo[];
+    current: string;
+
+    constructor(worlds: WorldInfo[], current: string) {
+        super({ position: "topleft" });
+        this.worlds = worlds;
+        this.current = current;
+    }
 
-let base_layer = leaflet.tileLayer(`${origin}/biomemap/{z}/{x}/{y}.png`, {
-    minNativeZoom: -8,
-    maxZoom: 17,
-    minZoom: -10,
-});
+    onAdd(map: leaflet.Map): HTMLElement {
+        let select = leaflet.DomUtil.create("select", "worldselect leaflet-bar") as HTMLSelectElement;
 
-let shaded_base_layer = leaflet.tileLayer(`${origin}/biomemap_shaded/{z}/{x}/{y}.png`, {
-    minNativeZoom: -8,
-    maxZoom: 17,
-    minZoom: -10,
-});
+        for (let world of this.worlds) {
+            let option = document.createElement("option");
+            option.value = world.name;
+            option.text = `${world.name} (${world.mc_version}, ${world.seed})`;
+            option.selected = world.name == this.current;
+            select.add(option);
+        }
 
-let contour_layer = leaflet.tileLayer(`${origin}/contours/{z}/{x}/{y}.png`, {
-    minNativeZoom: -8,
-    maxZoom: 17,
-    minZoom: -10,
-});
+        select.onchange = () => {
+            window.location.search = `?world=${encodeURIComponent(select.value)}`;
+        };
 
-let base_maps = {
-    "Normal": base_layer,
-    "Shaded": shaded_base_layer,
-};
+        leaflet.DomEvent.disableClickPropagation(select);
+        return select;
+    }
+}
 
-let overlays = {
-    "contours": contour_layer,
-};
+// Moves the map to the world spawn.
+class SpawnControl extends leaflet.Control {
+    world: string;
 
-let map = leaflet.map('map', {
-    crs: leaflet.CRS.Simple,
-    layers: [base_layer]
-}).setView([0.0, 0.0], 0);
+    constructor(world: string) {
+        super({ position: "topleft" });
+        this.world = world;
+    }
 
-let layer_control = leaflet.control.layers(base_maps, overlays).addTo(map);
+    onAdd(map: leaflet.Map): HTMLElement {
+        let container = leaflet.DomUtil.create("div", "leaflet-bar");
+        let button = leaflet.DomUtil.create("a", "spawn", container) as HTMLAnchorElement;
+        button.href = "#";
+        button.title = "Go to spawn";
+        button.textContent = "S";
 
-let mousePosControl = new MousePositionControl;
-map.addControl(mousePosControl);
+        leaflet.DomEvent.disableClickPropagation(container);
+        leaflet.DomEvent.on(button, "click", (e: Event) => {
+            leaflet.DomEvent.preventDefault(e);
 
-map.on("mousemove", (e) => {
-    let zoom = map.getZoom();
-    mousePosControl.update(e.latlng, zoom);
-});
+            fetch(`${origin}/api/landmarks?world=${encodeURIComponent(this.world)}`)
+                .then((response) => response.json())
+                .then((landmarks: Landmarks) => {
+                    let spawn = landmarks.features.filter((f) => f.properties.type == "spawn")[0];
+                    let [x, z] = spawn.geometry.coordinates;
+                    map.setView([-z, x], Math.max(map.getZoom(), 0));
+                });
+        });
 
+        return container;
+    }
+}
+
+// Flies to the nearest block of the biome typed in, from the center of the
+// map.
+class BiomeSearchControl extends leaflet.Control {
+    world: string;
+
+    constructor(world: string) {
+        super({ position: "topleft" });
+        this.world = world;
+    }
+
+    onAdd(map: leaflet.Map): HTMLElement {
+        let input = leaflet.DomUtil.create("input", "biomesearch leaflet-bar") as HTMLInputElement;
+        input.placeholder = "find biome, eg. cherry_grove";
+
+        input.onkeydown = (e: KeyboardEvent) => {
+            if (e.key != "Enter" || !input.value) {
+                return;
+            }
+
+            let center = map.getCenter();
+            let x = Math.floor(center.lng);
+            let z = Math.floor(-center.lat);
+            input.title = "searching...";
+
+            fetch(`${origin}/api/find/biome?world=${encodeURIComponent(this.world)}&biome=${encodeURIComponent(input.value)}&x=${x}&z=${z}`)
+                .then((response) => response.ok ? response.json() : response.text().then((error) => Promise.reject(error)))
+                .then((found: FoundBiome) => {
+                    input.title = `${found.biome} is ${Math.round(found.distance)} blocks away`;
+                    map.flyTo([-found.z, found.x], Math.max(map.getZoom(), -2));
+                })
+                .catch((error: string) => {
+                    input.title = error;
+                });
+        };
+
+        leaflet.DomEvent.disableClickPropagation(input);
+        return input;
+    }
+}
+
+interface FoundBiome {
+    biome: string;
+    x: number;
+    z: number;
+    distance: number;
+}
+
+interface Landmarks {
+    features: {
+        geometry: { coordinates: [number, number] };
+        properties: { type: string; ring?: number };
+    }[];
+}
+
+interface WorldInfo {
+    name: string;
+    // A string, since numbers can't hold every 64 bit seed.
+    seed: string;
+    mc_version: string;
+    nether_y_levels: number[];
+    has_end: boolean;
+    composites: string[];
+}
+
+interface ServerInfo {
+    tile_extension: string;
+    overlay_extension: string;
+    worlds: WorldInfo[];
+}
+
+fetch(`${origin}/api/info`)
+    .then((response) => response.json())
+    .then((info: ServerInfo) => setupMap(info));
+
+function setupMap(info: ServerInfo) {
+
+    let requested = new URLSearchParams(window.location.search).get("world");
+    let world_info = info.worlds.filter((w) => w.name == requested)[0] || info.worlds[0];
+    let world = world_info.name;
+
+    // Overlays are transparent, so they may use a different format than the
+    // base maps (eg. if those are jpg). {r} requests @2x tiles on high dpi
+    // screens. Past zoom 8 a tile is a single block, so it is upscaled.
+    let tile_layer = (path: string, ext: string = info.tile_extension) => leaflet.tileLayer(`${origin}/${world}/${path}/{z}/{x}/{y}{r}.${ext}`, {
+        minNativeZoom: -8,
+        maxNativeZoom: 8,
+        maxZoom: 17,
+        minZoom: -10,
+    });
+
+    let base_layer = tile_layer("biomemap");
+
+    let base_maps: { [name: string]: leaflet.TileLayer } = {
+        "Normal": base_layer,
+        "Shaded": tile_layer("biomemap_shaded"),
+    };
+
+    // The biome api query of the dimension (and y level) shown by each base map.
+    let biome_queries = new Map<leaflet.Layer, string>();
+    biome_queries.set(base_maps["Normal"], "dimension=overworld");
+    biome_queries.set(base_maps["Shaded"], "dimension=overworld");
+
+    let overlays: { [name: string]: leaflet.TileLayer } = {
+        "contours": tile_layer("contours", info.overlay_extension),
+        // Structures are only drawn from zoom -4 on, further out there are too
+        // many of them.
+        "structures": leaflet.tileLayer(`${origin}/${world}/structures/{z}/{x}/{y}{r}.${info.overlay_extension}`, {
+            minNativeZoom: -4,
+            maxNativeZoom: 8,
+            maxZoom: 17,
+            minZoom: -4,
+        }),
+        "spawn and strongholds": tile_layer("landmarks", info.overlay_extension),
+    };
+
+    for (let y_level of world_info.nether_y_levels) {
+        base_maps[`Nether (y ${y_level})`] = tile_layer(`the_nether/${y_level}/biomemap`);
+        biome_queries.set(base_maps[`Nether (y ${y_level})`], `dimension=the_nether&y=${y_level}`);
+    }
+
+    // Composites may be opaque, but as overlays they don't change which
+    // dimension the biome lookup uses.
+    for (let name of world_info.composites) {
+        overlays[`composite ${name}`] = tile_layer(`composite/${name}`, info.overlay_extension);
+    }
+
+    if (world_info.has_end) {
+        base_maps["End"] = tile_layer("the_end/biomemap");
+        base_maps["End shaded"] = tile_layer("the_end/biomemap_shaded");
+        biome_queries.set(base_maps["End"], "dimension=the_end");
+        biome_queries.set(base_maps["End shaded"], "dimension=the_end");
+        overlays["End contours"] = tile_layer("the_end/contours", info.overlay_extension);
+    }
+
+    let map = leaflet.map('map', {
+        crs: leaflet.CRS.Simple,
+        layers: [base_layer]
+    }).setView([0.0, 0.0], 0);
+
+    let layer_control = leaflet.control.layers(base_maps, overlays).addTo(map);
+
+    if (info.worlds.length > 1) {
+        map.addControl(new WorldSelectControl(info.worlds, world));
+    }
+
+    map.addControl(new SpawnControl(world));
+    map.addControl(new BiomeSearchControl(world));
+
+    let mousePosControl = new MousePositionControl;
+    map.addControl(mousePosControl);
+
+    let biome_query = biome_queries.get(base_layer);
+    map.on("baselayerchange", (e) => {
+        biome_query = biome_queries.get(e.layer);
+    });
+
+    // Biomes are looked up once the mouse stops, and answers to older lookups
+    // are dropped.
+    let biome_timeout: number | undefined;
+    let biome_request = 0;
+
+    map.on("mousemove", (e) => {
+        let zoom = map.getZoom();
+        // Latitudes grow to the north, while z grows to the south.
+        let x = Math.floor(e.latlng.lng);
+        let z = Math.floor(-e.latlng.lat);
+        mousePosControl.update(x, z, zoom);
+
+        window.clearTimeout(biome_timeout);
+        biome_timeout = window.setTimeout(() => {
+            let request = ++biome_request;
+
+            fetch(`${origin}/api/biome?world=${encodeURIComponent(world)}&x=${x}&z=${z}&${biome_query}`)
+                .then((response) => response.ok ? response.json() : null)
+                .then((sample: BiomeSample | null) => {
+                    if (request == biome_request) {
+                        mousePosControl.setBiome(sample ? sample.name : "");
+                    }
+                });
+        }, 100);
+    });
+}
@@ -0,0 +1,610 @@
+//! The `seed` and `export` subcommands, which render every tile of an area
+//! ahead of time.
+//!
+//! `seed` renders into the same directories the server caches tiles in. Tiles
+//! which are already on disk are skipped, so an interrupted run can be resumed
+//! by running the same command again. `export` writes the tiles of a single
+//! layer into an MBTiles file or PMTiles archive, rendering the ones missing
+//! from the cache.
+
+use std::{
+    fmt::Display,
+    io,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use image::ImageFormat;
+use log::{info, warn};
+use tokio::task::{JoinError, JoinSet};
+
+use crate::{
+    biomemap::{MAX_ZOOM, MIN_ZOOM},
+    config::{Config, DEFAULT_WORLD},
+    tileprovider::{
+        ArchiveMetadata, TilePos,
+        mbtiles::{self, MbTiles},
+        pmtiles::PmTilesWriter,
+        tilecache::{self, RenderPool},
+    },
+    world::WorldRegistry,
+};
+
+/// Amount of exported tiles written to the archive at once.
+const EXPORT_BATCH_SIZE: usize = 256;
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidBbox,
+    InvalidZoomRange(i32, i32),
+    UnknownWorld(String),
+    UnknownLayer(String, String),
+    InvalidTileFormat(String),
+    UnsupportedFormat(String, ImageFormat),
+    InvalidScale(u32),
+    CacheError(tilecache::Error),
+    DatabaseError(mbtiles::Error),
+    ArchiveError(io::Error),
+    UnknownArchiveType(PathBuf),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidBbox => write!(
+                f,
+                "bbox must be given as min_x,min_z,max_x,max_z with min <= max"
+            ),
+            Error::InvalidZoomRange(min, max) => write!(
+                f,
+                "zoom range {min}..={max} is not within {MIN_ZOOM}..={MAX_ZOOM}"
+            ),
+            Error::UnknownWorld(world) => write!(f, "no world named {world}"),
+            Error::UnknownLayer(world, layer) => {
+                write!(f, "world {world} has no layer named {layer}")
+            }
+            Error::InvalidTileFormat(format) => {
+                write!(f, "tile format `{format}` is not enabled")
+            }
+            Error::UnsupportedFormat(layer, format) => write!(
+                f,
+                "layer {layer} can't be rendered as {}",
+                format.extensions_str()[0]
+            ),
+            Error::InvalidScale(scale) => write!(f, "scale {scale} is not one of 1, 2, 4"),
+            Error::CacheError(e) => write!(f, "failed to create tile caches: {e}"),
+            Error::DatabaseError(e) => write!(f, "failed to write the mbtiles file: {e}"),
+            Error::ArchiveError(e) => write!(f, "failed to write the pmtiles archive: {e}"),
+            Error::UnknownArchiveType(path) => write!(
+                f,
+                "can't export to {}, only .mbtiles and .pmtiles files are supported",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::CacheError(e) => Some(e),
+            Error::DatabaseError(e) => Some(e),
+            Error::ArchiveError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// The area and zoom levels to render.
+#[derive(clap::Args, Debug, Clone)]
+pub struct Area {
+    /// Area to render in block coordinates, as min_x,min_z,max_x,max_z
+    #[arg(
+        long,
+        value_delimiter = ',',
+        allow_hyphen_values = true,
+        required = true
+    )]
+    pub bbox: Vec<i32>,
+
+    /// Lowest zoom level to render
+    #[arg(long, default_value_t = MIN_ZOOM, allow_hyphen_values = true)]
+    pub min_zoom: i32,
+
+    /// Highest zoom level to render
+    #[arg(long, default_value_t = 0, allow_hyphen_values = true)]
+    pub max_zoom: i32,
+}
+
+impl Area {
+    /// The bbox as min_x, min_z, max_x, max_z.
+    fn bounds(&self) -> Result<[i32; 4], Error> {
+        let [min_x, min_z, max_x, max_z] = self.bbox[..] else {
+            return Err(Error::InvalidBbox);
+        };
+
+        if min_x > max_x || min_z > max_z {
+            return Err(Error::InvalidBbox);
+        }
+
+        if self.min_zoom > self.max_zoom || self.min_zoom < MIN_ZOOM || self.max_zoom > MAX_ZOOM {
+            return Err(Error::InvalidZoomRange(self.min_zoom, self.max_zoom));
+        }
+
+        Ok([min_x, min_z, max_x, max_z])
+    }
+
+    /// Every tile of the area, with lower zoom levels first, since they have
+    /// the fewest tiles.
+    fn positions(&self) -> Result<impl Iterator<Item = TilePos>, Error> {
+        let bounds = self.bounds()?;
+
+        Ok((self.min_zoom..=self.max_zoom).flat_map(move |zoom| {
+            let (xs, ys) = tile_range(bounds, zoom);
+
+            xs.flat_map(move |x| ys.clone().map(move |y| TilePos::new(zoom, x, y)))
+        }))
+    }
+
+    /// The amount of tiles in the area.
+    fn tile_count(&self) -> Result<u64, Error> {
+        let bounds = self.bounds()?;
+
+        Ok((self.min_zoom..=self.max_zoom)
+            .map(|zoom| {
+                let (xs, ys) = tile_range(bounds, zoom);
+                (xs.end() - xs.start() + 1) as u64 * (ys.end() - ys.start() + 1) as u64
+            })
+            .sum())
+    }
+}
+
+/// Arguments of the `seed` subcommand.
+#[derive(clap::Args, Debug, Clone)]
+pub struct SeedArgs {
+    #[command(flatten)]
+    pub area: Area,
+
+    /// Layers to render, named like in tile urls (eg. biomemap,
+    /// the_end/contours or the_nether/64/biomemap)
+    #[arg(long, value_delimiter = ',', default_value = "biomemap")]
+    pub layers: Vec<String>,
+
+    /// Worlds to render, defaults to every configured world
+    #[arg(long, value_delimiter = ',')]
+    pub worlds: Option<Vec<String>>,
+
+    /// Image formats to render, defaults to the default tile format
+    #[arg(long, value_delimiter = ',')]
+    pub formats: Option<Vec<String>>,
+
+    /// Pixel ratios to render (1, 2 or 4)
+    #[arg(long, value_delimiter = ',', default_value = "1")]
+    pub scales: Vec<u32>,
+
+    /// Only count the tiles which would be rendered
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// Arguments of the `export` subcommand.
+///
+/// There is no scale option, tiles are always exported at a scale of 1.
+#[derive(clap::Args, Debug, Clone)]
+pub struct ExportArgs {
+    #[command(flatten)]
+    pub area: Area,
+
+    /// The .mbtiles or .pmtiles file to write, tiles already in it are
+    /// replaced
+    #[arg(long, short)]
+    pub output: PathBuf,
+
+    /// World to export
+    #[arg(long, default_value = DEFAULT_WORLD)]
+    pub world: String,
+
+    /// Layer to export, named like in tile urls
+    #[arg(long, default_value = "biomemap")]
+    pub layer: String,
+
+    /// Image format of the exported tiles, defaults to the default tile format
+    #[arg(long)]
+    pub format: Option<String>,
+
+    /// Name of the map stored in the file, defaults to the world and layer
+    #[arg(long)]
+    pub name: Option<String>,
+}
+
+/// A layer of a world in one of the variants its tiles are rendered in.
+#[derive(Clone)]
+struct Target {
+    world: Arc<str>,
+    layer: Arc<str>,
+    scale: u32,
+    format: ImageFormat,
+}
+
+/// Renders every tile selected by args, with as many tiles rendered at once as
+/// the render pool allows.
+pub async fn seed(config: &Config, args: SeedArgs) -> Result<(), Error> {
+    if let Some(&scale) = args.scales.iter().find(|scale| ![1, 2, 4].contains(*scale)) {
+        return Err(Error::InvalidScale(scale));
+    }
+
+    let formats: Vec<ImageFormat> = match &args.formats {
+        Some(formats) => formats
+            .iter()
+            .map(|format| parse_format(config, format))
+            .collect::<Result<_, _>>()?,
+        None => vec![config.tile_format],
+    };
+
+    let render_pool = RenderPool::new(config.render_threads);
+    let worlds = Arc::new(WorldRegistry::new(config, &render_pool).map_err(Error::CacheError)?);
+
+    let world_names: Vec<String> = match args.worlds {
+        Some(names) => names,
+        None => worlds.iter().map(|world| world.name().to_owned()).collect(),
+    };
+
+    // Check every layer up front, instead of failing halfway through.
+    for name in &world_names {
+        let world = worlds
+            .get(name)
+            .ok_or_else(|| Error::UnknownWorld(name.clone()))?;
+
+        for layer_name in &args.layers {
+            let layer = world
+                .layer(layer_name)
+                .ok_or_else(|| Error::UnknownLayer(name.clone(), layer_name.clone()))?;
+
+            // Overlays can't be rendered as jpeg.
+            if let Some(&format) = formats.iter().find(|&&format| !layer.supports(format)) {
+                return Err(Error::UnsupportedFormat(layer_name.clone(), format));
+            }
+        }
+    }
+
+    let total = args.area.tile_count()?
+        * (world_names.len() * args.layers.len() * formats.len() * args.scales.len()) as u64;
+
+    if args.dry_run {
+        info!("would render up to {total} tiles");
+        return Ok(());
+    }
+
+    info!("rendering up to {total} tiles");
+
+    // Every layer in every variant a tile is rendered in.
+    let (scales, formats) = (&args.scales, &formats);
+    let targets: Vec<Target> = world_names
+        .iter()
+        .flat_map(|world| args.layers.iter().map(move |layer| (world, layer)))
+        .flat_map(|(world, layer)| {
+            scales.iter().flat_map(move |&scale| {
+                formats.iter().map(move |&format| Target {
+                    world: world.as_str().into(),
+                    layer: layer.as_str().into(),
+                    scale,
+                    format,
+                })
+            })
+        })
+        .collect();
+
+    let mut progress = Progress::new(total, "rendered");
+    let mut running = JoinSet::new();
+
+    for pos in args.area.positions()? {
+        for target in &targets {
+            while running.len() >= concurrency(config) {
+                if let Some(result) = running.join_next().await {
+                    progress.record(result);
+                }
+            }
+
+            let worlds = worlds.clone();
+            let target = target.clone();
+
+            running.spawn(async move {
+                let world = worlds.get(&target.world).expect("worlds are checked");
+                let layer = world.layer(&target.layer).expect("layers are checked");
+
+                layer
+                    .prerender(pos.with_scale(target.scale), target.format)
+                    .await
+            });
+        }
+    }
+
+    while let Some(result) = running.join_next().await {
+        progress.record(result);
+    }
+
+    Ok(())
+}
+
+/// Writes every tile of a layer in the area selected by args into an MBTiles
+/// file or PMTiles archive, rendering the tiles which aren't cached yet.
+pub async fn export(config: &Config, args: ExportArgs) -> Result<(), Error> {
+    let format = match &args.format {
+        Some(format) => parse_format(config, format)?,
+        None => config.tile_format,
+    };
+    let bounds = args.area.bounds()?;
+
+    let render_pool = RenderPool::new(config.render_threads);
+    let worlds = Arc::new(WorldRegistry::new(config, &render_pool).map_err(Error::CacheError)?);
+
+    let layer = worlds
+        .get(&args.world)
+        .ok_or_else(|| Error::UnknownWorld(args.world.clone()))?
+        .layer(&args.layer)
+        .ok_or_else(|| Error::UnknownLayer(args.world.clone(), args.layer.clone()))?;
+
+    if !layer.supports(format) {
+        return Err(Error::UnsupportedFormat(args.layer.clone(), format));
+    }
+
+    let metadata = ArchiveMetadata {
+        name: args
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("{} {}", args.world, args.layer)),
+        format,
+        bounds,
+        overlay: layer.is_overlay(),
+        min_zoom: args.area.min_zoom,
+        max_zoom: args.area.max_zoom,
+    };
+    let mut archive = Archive::create(&args.output, &metadata)?;
+
+    let total = args.area.tile_count()?;
+    info!("exporting {total} tiles to {}", args.output.display());
+
+    let mut progress = Progress::new(total, "exported");
+    let mut running = JoinSet::new();
+    let mut batch = Vec::with_capacity(EXPORT_BATCH_SIZE);
+
+    for pos in args.area.positions()? {
+        while running.len() >= concurrency(config) {
+            if let Some(result) = running.join_next().await {
+                progress.record(add_to_batch(result, &mut batch));
+            }
+        }
+
+        if batch.len() >= EXPORT_BATCH_SIZE {
+            archive.insert(&batch)?;
+            batch.clear();
+        }
+
+        let worlds = worlds.clone();
+        let (world, layer) = (args.world.clone(), args.layer.clone());
+
+        running.spawn(async move {
+            let world = worlds.get(&world).expect("world is checked");
+            let layer = world.layer(&layer).expect("layer is checked");
+
+            (pos, layer.get_stored_tile(pos, format).await)
+        });
+    }
+
+    while let Some(result) = running.join_next().await {
+        progress.record(add_to_batch(result, &mut batch));
+    }
+
+    archive.insert(&batch)?;
+    archive.finish(&metadata)
+}
+
+/// The result of exporting a single tile.
+type ExportResult = Result<(TilePos, Result<Vec<u8>, tilecache::Error>), JoinError>;
+
+/// Adds the tile of result to batch, returning the result for [Progress].
+fn add_to_batch(
+    result: ExportResult,
+    batch: &mut Vec<(TilePos, Vec<u8>)>,
+) -> Result<Result<bool, tilecache::Error>, JoinError> {
+    let (pos, tile) = result?;
+
+    Ok(tile.map(|tile| {
+        batch.push((pos, tile));
+        true
+    }))
+}
+
+/// The file tiles are exported to, chosen by its extension.
+///
+/// Writing to it blocks, which is fine since the export is the only thing
+/// running and rendering happens on the render pool.
+enum Archive {
+    MbTiles(MbTiles),
+    PmTiles(PmTilesWriter),
+}
+
+impl Archive {
+    fn create(path: &Path, metadata: &ArchiveMetadata) -> Result<Self, Error> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("mbtiles") => MbTiles::create(path, metadata)
+                .map(Archive::MbTiles)
+                .map_err(Error::DatabaseError),
+            Some("pmtiles") => PmTilesWriter::create(path)
+                .map(Archive::PmTiles)
+                .map_err(Error::ArchiveError),
+            _ => Err(Error::UnknownArchiveType(path.to_owned())),
+        }
+    }
+
+    fn insert(&mut self, tiles: &[(TilePos, Vec<u8>)]) -> Result<(), Error> {
+        match self {
+            Archive::MbTiles(mbtiles) => mbtiles.insert_many(tiles).map_err(Error::DatabaseError),
+            Archive::PmTiles(writer) => tiles
+                .iter()
+                .try_for_each(|(pos, tile)| writer.add(*pos, tile))
+                .map_err(Error::ArchiveError),
+        }
+    }
+
+    fn finish(self, metadata: &ArchiveMetadata) -> Result<(), Error> {
+        match self {
+            Archive::MbTiles(_) => Ok(()),
+            Archive::PmTiles(writer) => writer.finish(metadata).map_err(Error::ArchiveError),
+        }
+    }
+}
+
+/// Counts finished tiles and logs the progress every percent.
+struct Progress {
+    total: u64,
+    step: u64,
+    done: u64,
+    processed: u64,
+    // Tiles which were already stored.
+    stored: u64,
+    // Tiles the layer doesn't have, eg. structures when zoomed out.
+    missing: u64,
+    failed: u64,
+    // What was done to processed tiles, for the log.
+    verb: &'static str,
+}
+
+impl Progress {
+    fn new(total: u64, verb: &'static str) -> Self {
+        Self {
+            total,
+            step: (total / 100).max(1),
+            done: 0,
+            processed: 0,
+            stored: 0,
+            missing: 0,
+            failed: 0,
+            verb,
+        }
+    }
+
+    /// Records a finished tile, which was processed if the result is true and
+    /// already stored otherwise.
+    fn record(&mut self, result: Result<Result<bool, tilecache::Error>, JoinError>) {
+        self.done += 1;
+
+        match result {
+            Ok(Ok(true)) => self.processed += 1,
+            Ok(Ok(false)) => self.stored += 1,
+            Ok(Err(tilecache::Error::ProviderError(e))) if e.is_missing() => self.missing += 1,
+            Ok(Err(e)) => {
+                self.failed += 1;
+                warn!("failed to render tile: {e}");
+            }
+            Err(e) => {
+                self.failed += 1;
+                warn!("failed to render tile: {e}");
+            }
+        }
+
+        if self.done.is_multiple_of(self.step) || self.done == self.total {
+            info!(
+                "{}/{} tiles done ({} {}, {} already stored, {} skipped as missing, {} failed)",
+                self.done,
+                self.total,
+                self.processed,
+                self.verb,
+                self.stored,
+                self.missing,
+                self.failed
+            );
+        }
+    }
+}
+
+/// Enough tiles at once to keep the render pool busy, without queueing every
+/// tile.
+fn concurrency(config: &Config) -> usize {
+    config.render_threads * 2
+}
+
+/// Parses the extension of one of the enabled tile formats.
+fn parse_format(config: &Config, format: &str) -> Result<ImageFormat, Error> {
+    ImageFormat::from_extension(format)
+        .filter(|format| config.base_formats().contains(format))
+        .ok_or_else(|| Error::InvalidTileFormat(format.to_owned()))
+}
+
+/// The range of tile x and y coordinates covering bounds at zoom.
+fn tile_range(
+    [min_x, min_z, max_x, max_z]: [i32; 4],
+    zoom: i32,
+) -> (RangeInclusive<i32>, RangeInclusive<i32>) {
+    // 256 blocks per tile at zoom 0, halving with every zoom level.
+    let blocks_per_tile = 2_i64.pow((MAX_ZOOM - zoom) as u32);
+    let tile = |block: i32| (block as i64).div_euclid(blocks_per_tile) as i32;
+
+    (tile(min_x)..=tile(max_x), tile(min_z)..=tile(max_z))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn area(bbox: [i32; 4], min_zoom: i32, max_zoom: i32) -> Area {
+        Area {
+            bbox: bbox.into(),
+            min_zoom,
+            max_zoom,
+        }
+    }
+
+    #[test]
+    fn tile_ranges_cover_the_bbox() {
+        assert_eq!(tile_range([0, 0, 255, 255], 0), (0..=0, 0..=0));
+        assert_eq!(tile_range([-1, -256, 256, 0], 0), (-1..=1, -1..=0));
+        // A block per tile at the highest zoom level.
+        assert_eq!(tile_range([-3, 5, 2, 5], MAX_ZOOM), (-3..=2, 5..=5));
+        assert_eq!(tile_range([-1, 0, 65536, 0], MIN_ZOOM), (-1..=1, 0..=0));
+    }
+
+    #[test]
+    fn counts_every_tile_of_every_zoom_level() {
+        // A single block is in one tile of every zoom level.
+        assert_eq!(
+            area([0, 0, 0, 0], MIN_ZOOM, MAX_ZOOM).tile_count().unwrap(),
+            17
+        );
+        assert_eq!(area([-5, -5, -5, -5], 0, 0).tile_count().unwrap(), 1);
+
+        // 2x1 tiles at zoom 0, 4x2 at zoom 1.
+        let area = area([-256, 0, 255, 255], 0, 1);
+        assert_eq!(area.tile_count().unwrap(), 10);
+
+        let positions: Vec<_> = area.positions().unwrap().collect();
+        assert_eq!(positions.len(), 10);
+        assert_eq!(
+            positions[..2],
+            [TilePos::new(0, -1, 0), TilePos::new(0, 0, 0)]
+        );
+        assert!(positions[2..].iter().all(|pos| pos.zoom == 1));
+        assert!(positions.contains(&TilePos::new(1, -2, 1)));
+    }
+
+    #[test]
+    fn areas_are_validated() {
+        let invalid_bbox = |area: Area| matches!(area.tile_count(), Err(Error::InvalidBbox));
+
+        assert!(invalid_bbox(Area {
+            bbox: vec![0, 0, 1],
+            min_zoom: 0,
+            max_zoom: 0,
+        }));
+        assert!(invalid_bbox(area([1, 0, 0, 0], 0, 0)));
+        assert!(invalid_bbox(area([0, 1, 0, 0], 0, 0)));
+
+        for (min_zoom, max_zoom) in [(1, 0), (MIN_ZOOM - 1, 0), (0, MAX_ZOOM + 1)] {
+            assert!(matches!(
+                area([0, 0, 0, 0], min_zoom, max_zoom).positions(),
+                Err(Error::InvalidZoomRange(min, max)) if (min, max) == (min_zoom, max_zoom)
+            ));
+        }
+    }
+}
@@ -0,0 +1,354 @@
+//! Static maps of an arbitrary area, composited from the tiles of layers.
+
+use std::fmt::Display;
+
+use actix_web::{ResponseError, http::StatusCode, web::Bytes};
+use image::{
+    ImageFormat, RgbaImage,
+    imageops::{self, FilterType},
+};
+use tokio::{
+    io,
+    task::{JoinError, JoinSet, spawn_blocking},
+};
+
+use crate::{
+    biomemap::{MAX_ZOOM, MIN_ZOOM},
+    tileprovider::{
+        TILE_SIZE, TilePos,
+        tilecache::{self, RenderPool},
+    },
+    world::Layer,
+};
+
+/// Most tiles (summed over the layers) a single static map may be made of.
+pub const MAX_RENDER_TILES: u64 = 512;
+
+/// Most tiles a single layer of a static map may span, which bounds the size
+/// of the canvas they are drawn onto.
+const MAX_CANVAS_TILES: u64 = 256;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The area needs this many tiles (summed over the layers).
+    TooManyTiles(u64),
+    TileError(tilecache::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::TooManyTiles(tiles) => write!(
+                f,
+                "the area needs {tiles} tiles, at most {MAX_RENDER_TILES} (or {MAX_CANVAS_TILES} \
+                 per layer) can be rendered at once, try a wider image or a smaller area"
+            ),
+            Error::TileError(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::TooManyTiles(_) => None,
+            Error::TileError(e) => Some(e),
+        }
+    }
+}
+
+impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Error::TooManyTiles(_) => StatusCode::BAD_REQUEST,
+            Error::TileError(e) => e.status_code(),
+        }
+    }
+}
+
+impl From<tilecache::Error> for Error {
+    fn from(value: tilecache::Error) -> Self {
+        Self::TileError(value)
+    }
+}
+
+/// The tiles of a layer covering an area, at the zoom level closest to the
+/// resolution of the image it is rendered to.
+#[derive(Debug, PartialEq)]
+struct Tiles {
+    zoom: i32,
+    min_x: i32,
+    min_y: i32,
+    max_x: i32,
+    max_y: i32,
+    /// The area within the tiles, in pixels.
+    left: u32,
+    top: u32,
+    area_width: u32,
+    area_height: u32,
+}
+
+impl Tiles {
+    /// The tiles covering the blocks from x0, z0 up to (excluding) x1, z1 on
+    /// an image which is width pixels wide.
+    fn covering([x0, z0, x1, z1]: [i32; 4], width: u32) -> Self {
+        // In i64, since the area may be wider than i32::MAX blocks.
+        let (area_x, area_z) = (x1 as i64 - x0 as i64, z1 as i64 - z0 as i64);
+        let zoom = ((width as f64 / area_x as f64).log2().round() as i32).clamp(MIN_ZOOM, MAX_ZOOM);
+
+        // One block per pixel at zoom 0, doubling with every zoom level.
+        let pixels_per_block = 2_f64.powi(zoom);
+        let blocks_per_tile = TILE_SIZE as f64 / pixels_per_block;
+        let tile = |block: i32| (block as f64 / blocks_per_tile).floor() as i32;
+        let (min_x, min_y) = (tile(x0), tile(z0));
+
+        Self {
+            zoom,
+            min_x,
+            min_y,
+            max_x: tile(x1 - 1),
+            max_y: tile(z1 - 1),
+            left: ((x0 as f64 - min_x as f64 * blocks_per_tile) * pixels_per_block) as u32,
+            top: ((z0 as f64 - min_y as f64 * blocks_per_tile) * pixels_per_block) as u32,
+            area_width: ((area_x as f64 * pixels_per_block).round() as u32).max(1),
+            area_height: ((area_z as f64 * pixels_per_block).round() as u32).max(1),
+        }
+    }
+
+    /// Fails if rendering the tiles for layers layers would exceed
+    /// [MAX_CANVAS_TILES] or [MAX_RENDER_TILES].
+    fn check_limits(&self, layers: usize) -> Result<(), Error> {
+        let layer_tiles = (self.max_x as i64 - self.min_x as i64 + 1) as u64
+            * (self.max_y as i64 - self.min_y as i64 + 1) as u64;
+        let total_tiles = layer_tiles * layers as u64;
+
+        if layer_tiles > MAX_CANVAS_TILES || total_tiles > MAX_RENDER_TILES {
+            return Err(Error::TooManyTiles(total_tiles));
+        }
+
+        Ok(())
+    }
+
+    fn positions(&self) -> impl Iterator<Item = TilePos> {
+        let (zoom, ys) = (self.zoom, self.min_y..=self.max_y);
+
+        (self.min_x..=self.max_x)
+            .flat_map(move |x| ys.clone().map(move |y| TilePos::new(zoom, x, y)))
+    }
+}
+
+/// An encoded tile of the layer at an index, to be drawn onto the canvas.
+struct FetchedTile {
+    layer: usize,
+    pos: TilePos,
+    format: ImageFormat,
+    tile: Bytes,
+}
+
+/// Renders the blocks from x0, z0 up to (excluding) x1, z1 as a width x height
+/// image, drawing layers on top of each other in order.
+///
+/// Tiles are taken from the zoom level closest to the resolution of the image,
+/// and resized to fit. Tiles a layer doesn't have (eg. structures when zoomed
+/// out) are left transparent. They are fetched concurrently, up to twice as
+/// many at once as render_pool renders, like by the `seed` subcommand.
+///
+/// Fails without rendering anything if the area needs more tiles than
+/// [MAX_RENDER_TILES], eg. a huge area on a tiny image.
+pub async fn render_area(
+    layers: &[Layer<'_>],
+    area: [i32; 4],
+    width: u32,
+    height: u32,
+    render_pool: &RenderPool,
+) -> Result<RgbaImage, Error> {
+    let tiles = Tiles::covering(area, width);
+    tiles.check_limits(layers.len())?;
+
+    let concurrency = render_pool.concurrency() * 2;
+    let mut running = JoinSet::new();
+    let mut fetched = Vec::new();
+
+    for (index, layer) in layers.iter().enumerate() {
+        let format = layer.default_format();
+
+        for pos in tiles.positions() {
+            while running.len() >= concurrency {
+                if let Some(result) = running.join_next().await {
+                    fetched.extend(fetch_result(result)?);
+                }
+            }
+
+            let tile = layer.fetch_tile(pos, format);
+
+            running.spawn(async move {
+                tile.await.map(|tile| FetchedTile {
+                    layer: index,
+                    pos,
+                    format,
+                    tile,
+                })
+            });
+        }
+    }
+
+    while let Some(result) = running.join_next().await {
+        fetched.extend(fetch_result(result)?);
+    }
+
+    // Tiles finish in any order, but the layers have to be drawn in order.
+    fetched.sort_by_key(|tile| tile.layer);
+
+    spawn_blocking(move || draw(&tiles, fetched, width, height))
+        .await
+        .map_err(tilecache::Error::RenderError)?
+        .map_err(Error::TileError)
+}
+
+/// The tile of a finished fetch, or None if the layer has no tile there.
+fn fetch_result(
+    result: Result<Result<FetchedTile, tilecache::Error>, JoinError>,
+) -> Result<Option<FetchedTile>, Error> {
+    match result.map_err(tilecache::Error::RenderError)? {
+        Ok(tile) => Ok(Some(tile)),
+        Err(tilecache::Error::ProviderError(e)) if e.is_missing() => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Draws fetched, in order, onto a canvas of tiles, and crops and resizes it
+/// to the area.
+fn draw(
+    tiles: &Tiles,
+    fetched: Vec<FetchedTile>,
+    width: u32,
+    height: u32,
+) -> Result<RgbaImage, tilecache::Error> {
+    let mut canvas = RgbaImage::new(
+        (tiles.max_x - tiles.min_x + 1) as u32 * TILE_SIZE,
+        (tiles.max_y - tiles.min_y + 1) as u32 * TILE_SIZE,
+    );
+
+    for FetchedTile {
+        pos, format, tile, ..
+    } in fetched
+    {
+        let tile = image::load_from_memory_with_format(&tile, format)
+            .map_err(|e| tilecache::Error::ReadError(io::Error::other(e)))?;
+
+        imageops::overlay(
+            &mut canvas,
+            &tile.to_rgba8(),
+            ((pos.x - tiles.min_x) as u32 * TILE_SIZE).into(),
+            ((pos.y - tiles.min_y) as u32 * TILE_SIZE).into(),
+        );
+    }
+
+    let area = imageops::crop_imm(
+        &canvas,
+        tiles.left,
+        tiles.top,
+        tiles.area_width,
+        tiles.area_height,
+    )
+    .to_image();
+
+    Ok(imageops::resize(&area, width, height, FilterType::Triangle))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::Rgba;
+
+    use super::*;
+
+    #[test]
+    fn zoom_matches_the_resolution() {
+        assert_eq!(
+            Tiles::covering([0, 0, 256, 256], 256),
+            Tiles {
+                zoom: 0,
+                min_x: 0,
+                min_y: 0,
+                max_x: 0,
+                max_y: 0,
+                left: 0,
+                top: 0,
+                area_width: 256,
+                area_height: 256,
+            }
+        );
+        assert_eq!(Tiles::covering([0, 0, 256, 256], 512).zoom, 1);
+        assert_eq!(Tiles::covering([0, 0, 1024, 1024], 256).zoom, -2);
+        assert_eq!(Tiles::covering([0, 0, 1, 1], 4096).zoom, MAX_ZOOM);
+        assert_eq!(
+            Tiles::covering([i32::MIN, 0, i32::MAX, 1], 256).zoom,
+            MIN_ZOOM
+        );
+    }
+
+    #[test]
+    fn tile_limits_are_enforced() {
+        // 16x16 tiles per layer.
+        let tiles = Tiles::covering([0, 0, 16 * 256, 16 * 256], 16 * 256);
+        assert!(tiles.check_limits(2).is_ok());
+        assert!(matches!(
+            tiles.check_limits(3),
+            Err(Error::TooManyTiles(768))
+        ));
+
+        // 17x16 tiles don't fit on the canvas, even for a single layer.
+        let tiles = Tiles::covering([0, 0, 17 * 256, 16 * 256], 17 * 256);
+        assert!(matches!(
+            tiles.check_limits(1),
+            Err(Error::TooManyTiles(272))
+        ));
+    }
+
+    fn tile(layer: usize, x: i32, y: i32, color: [u8; 4]) -> FetchedTile {
+        let mut png = Cursor::new(Vec::new());
+        RgbaImage::from_pixel(TILE_SIZE, TILE_SIZE, Rgba(color))
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+
+        FetchedTile {
+            layer,
+            pos: TilePos::new(0, x, y),
+            format: ImageFormat::Png,
+            tile: png.into_inner().into(),
+        }
+    }
+
+    #[test]
+    fn draws_the_layers_cropped_to_the_area() {
+        const RED: [u8; 4] = [255, 0, 0, 255];
+        const BLUE: [u8; 4] = [0, 0, 255, 255];
+        const GREEN: [u8; 4] = [0, 255, 0, 255];
+
+        // Half of each of 2x2 tiles.
+        let tiles = Tiles::covering([128, -64, 384, 192], 256);
+        assert_eq!(
+            (tiles.min_x, tiles.min_y, tiles.max_x, tiles.max_y),
+            (0, -1, 1, 0)
+        );
+        assert_eq!((tiles.left, tiles.top), (128, 192));
+
+        let fetched = vec![
+            tile(0, 0, -1, RED),
+            tile(0, 1, -1, BLUE),
+            tile(0, 0, 0, RED),
+            tile(0, 1, 0, BLUE),
+            // Drawn on top of the first layer.
+            tile(1, 0, 0, GREEN),
+        ];
+        let image = draw(&tiles, fetched, 128, 128).unwrap();
+
+        assert_eq!(image.dimensions(), (128, 128));
+        assert_eq!(image.get_pixel(5, 5).0, RED);
+        assert_eq!(image.get_pixel(100, 5).0, BLUE);
+        assert_eq!(image.get_pixel(5, 100).0, GREEN);
+        assert_eq!(image.get_pixel(100, 100).0, BLUE);
+    }
+}
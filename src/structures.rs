@@ -0,0 +1,579 @@
+//! Structure locations, found with cubiomes' structure position and viability
+//! checks, as listed by the structure api and drawn by [StructureOverlay].
+//!
+//! The world spawn and the strongholds are found once per world, and drawn by
+//! [LandmarkOverlay].
+
+use std::{
+    mem::MaybeUninit,
+    sync::{Arc, OnceLock},
+};
+
+use cubiomes::{
+    enums::{Dimension, MCVersion, StructureType},
+    generator::{BlockPosition, Generator, GeneratorFlags},
+    structures::StructureRegion,
+};
+use image::{DynamicImage, Rgba, RgbaImage};
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::tileprovider::{ProviderError, TILE_SIZE, TilePos, TileProvider};
+
+/// The structures which can be looked up, with their name in the api and the
+/// color of their icon.
+const STRUCTURES: [(StructureType, &str, [u8; 3]); 5] = [
+    (StructureType::Village, "village", [230, 160, 40]),
+    (StructureType::Monument, "monument", [40, 200, 200]),
+    (StructureType::Mansion, "mansion", [150, 90, 40]),
+    (StructureType::Ancient_City, "ancient_city", [40, 60, 120]),
+    (
+        StructureType::Trial_Chambers,
+        "trial_chambers",
+        [200, 60, 60],
+    ),
+];
+
+/// Structures are only drawn from this zoom level on, further out there are
+/// too many regions to check.
+pub const STRUCTURE_MIN_ZOOM: i32 = -4;
+
+/// The radius of structure icons in pixels, at scale 1.
+const ICON_RADIUS: i32 = 6;
+
+/// The width of the outline of structure icons in pixels, at scale 1.
+const ICON_OUTLINE: i32 = 2;
+
+/// The radius of the spawn icon in pixels, at scale 1.
+const SPAWN_ICON_RADIUS: i32 = 9;
+
+const SPAWN_COLOR: [u8; 3] = [250, 250, 250];
+
+const STRONGHOLD_COLOR: [u8; 3] = [140, 60, 200];
+
+/// The structure type named name in the api, eg. `ancient_city`.
+pub fn structure_type(name: &str) -> Option<StructureType> {
+    STRUCTURES
+        .iter()
+        .find(|(_, other, _)| *other == name)
+        .map(|&(structure_type, ..)| structure_type)
+}
+
+/// The name of structure_type in the api.
+pub fn structure_name(structure_type: StructureType) -> &'static str {
+    STRUCTURES
+        .iter()
+        .find(|(other, ..)| *other == structure_type)
+        .map_or("unknown", |&(_, name, _)| name)
+}
+
+/// A structure which generates in the world.
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct Structure {
+    #[serde(rename = "type")]
+    pub name: &'static str,
+    pub x: i32,
+    pub z: i32,
+    #[serde(skip)]
+    pub structure_type: StructureType,
+}
+
+/// Finds the structures of an overworld.
+pub struct StructureFinder {
+    mc_version: MCVersion,
+    seed: i64,
+    types: Vec<StructureType>,
+    // Viability checks need a mutable generator, so every search takes one out
+    // of the pool.
+    generators: Mutex<Vec<Generator>>,
+    landmarks: OnceLock<Landmarks>,
+}
+
+impl StructureFinder {
+    pub fn new(mc_version: MCVersion, seed: i64) -> Self {
+        Self {
+            mc_version,
+            seed,
+            types: STRUCTURES
+                .iter()
+                .map(|&(structure_type, ..)| structure_type)
+                .filter(|&structure_type| {
+                    StructureRegion::new(0, 0, mc_version, structure_type).is_ok()
+                })
+                .collect(),
+            generators: Mutex::new(Vec::new()),
+            landmarks: OnceLock::new(),
+        }
+    }
+
+    /// The structure types which generate in the version of the world.
+    pub fn types(&self) -> &[StructureType] {
+        &self.types
+    }
+
+    /// The amount of regions which have to be checked to find the structures
+    /// of types in bbox, to limit the cost of a search.
+    pub fn region_count(&self, bbox: [i32; 4], types: &[StructureType]) -> u64 {
+        types
+            .iter()
+            .filter_map(|&structure_type| self.region_range(bbox, structure_type))
+            .map(|[min_x, min_z, max_x, max_z]| {
+                (max_x - min_x + 1) as u64 * (max_z - min_z + 1) as u64
+            })
+            .sum()
+    }
+
+    /// Finds the structures of types in bbox, given in block coordinates as
+    /// min_x, min_z, max_x, max_z (inclusive).
+    ///
+    /// Types which don't generate in the version of the world are skipped.
+    pub fn find(&self, bbox: [i32; 4], types: &[StructureType]) -> Vec<Structure> {
+        self.with_generator(|generator| self.find_with(generator, bbox, types))
+    }
+
+    /// The world spawn and the strongholds, which are found on the first call.
+    pub fn landmarks(&self) -> &Landmarks {
+        self.landmarks
+            .get_or_init(|| self.with_generator(|generator| Landmarks::find(generator)))
+    }
+
+    /// Runs f with a generator of the pool.
+    fn with_generator<T>(&self, f: impl FnOnce(&mut Generator) -> T) -> T {
+        let mut generator = self.generators.lock().pop().unwrap_or_else(|| {
+            Generator::new(
+                self.mc_version,
+                self.seed,
+                Dimension::DIM_OVERWORLD,
+                GeneratorFlags::empty(),
+            )
+        });
+
+        let result = f(&mut generator);
+        self.generators.lock().push(generator);
+
+        result
+    }
+
+    fn find_with(
+        &self,
+        generator: &mut Generator,
+        bbox: [i32; 4],
+        types: &[StructureType],
+    ) -> Vec<Structure> {
+        let [min_x, min_z, max_x, max_z] = bbox;
+        let mut found = Vec::new();
+
+        for &structure_type in types {
+            let Some([region_min_x, region_min_z, region_max_x, region_max_z]) =
+                self.region_range(bbox, structure_type)
+            else {
+                continue;
+            };
+
+            for region_x in region_min_x..=region_max_x {
+                for region_z in region_min_z..=region_max_z {
+                    // Every region has a single generation attempt, which is
+                    // only a structure if the biome there allows it.
+                    let Some(pos) =
+                        StructureRegion::new(region_x, region_z, self.mc_version, structure_type)
+                            .ok()
+                            .and_then(|region| region.get_structure_generation_attempt(self.seed))
+                    else {
+                        continue;
+                    };
+
+                    if !(min_x..=max_x).contains(&pos.x) || !(min_z..=max_z).contains(&pos.z) {
+                        continue;
+                    }
+
+                    // For some types cubiomes returns the biome of viable
+                    // positions instead of 1, which the bindings report as an
+                    // error.
+                    if !matches!(
+                        generator.verify_structure_generation_attempt(pos, structure_type),
+                        Ok(false)
+                    ) {
+                        found.push(Structure {
+                            name: structure_name(structure_type),
+                            x: pos.x,
+                            z: pos.z,
+                            structure_type,
+                        });
+                    }
+                }
+            }
+        }
+
+        found
+    }
+
+    /// The regions of structure_type overlapping bbox, as min_x, min_z, max_x,
+    /// max_z. None if the structure doesn't generate in the version of the
+    /// world.
+    fn region_range(
+        &self,
+        [min_x, min_z, max_x, max_z]: [i32; 4],
+        structure_type: StructureType,
+    ) -> Option<[i32; 4]> {
+        if !self.types.contains(&structure_type) {
+            return None;
+        }
+
+        let size = StructureRegion::new(0, 0, self.mc_version, structure_type)
+            .ok()?
+            .region_size_blocks();
+
+        Some([
+            min_x.div_euclid(size),
+            min_z.div_euclid(size),
+            max_x.div_euclid(size),
+            max_z.div_euclid(size),
+        ])
+    }
+}
+
+/// The world spawn and the strongholds of an overworld.
+#[derive(Debug, Clone)]
+pub struct Landmarks {
+    pub spawn: BlockPosition,
+    pub strongholds: Vec<Stronghold>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Stronghold {
+    pub x: i32,
+    pub z: i32,
+    /// The ring the stronghold is in, counted from 0 for the innermost one.
+    pub ring: i32,
+}
+
+impl Landmarks {
+    fn find(generator: &Generator) -> Self {
+        // SAFETY: the generator is initialized, and only read by cubiomes.
+        let spawn = unsafe { cubiomes_sys::getSpawn(generator.as_ptr()) }.into();
+
+        // The stronghold iterator of the cubiomes crate skips the first and
+        // last stronghold, so cubiomes is used directly.
+        let count = if generator.minecraft_version() >= MCVersion::MC_1_9 {
+            128
+        } else {
+            3
+        };
+        let mut iter = MaybeUninit::<cubiomes_sys::StrongholdIter>::uninit();
+
+        // SAFETY: initFirstStronghold initializes every field of the iterator.
+        let mut iter = unsafe {
+            cubiomes_sys::initFirstStronghold(
+                iter.as_mut_ptr(),
+                generator.minecraft_version() as i32,
+                generator.seed() as u64,
+            );
+            iter.assume_init()
+        };
+        let mut strongholds = Vec::with_capacity(count);
+
+        for _ in 0..count {
+            // The ring is advanced along with the position of the next
+            // stronghold.
+            let ring = iter.ringnum;
+
+            // SAFETY: the iterator was initialized above, and the generator is
+            // only read by cubiomes. Returns 0 for versions without
+            // strongholds.
+            if unsafe { cubiomes_sys::nextStronghold(&mut iter, generator.as_ptr()) } == 0 {
+                break;
+            }
+
+            strongholds.push(Stronghold {
+                x: iter.pos.x,
+                z: iter.pos.z,
+                ring,
+            });
+        }
+
+        Self { spawn, strongholds }
+    }
+}
+
+/// Transparent tiles with an icon on every structure.
+///
+/// Unlike the biome layers, scaled tiles are drawn at their own resolution, so
+/// icons aren't cut at the borders of the stitched tiles.
+pub struct StructureOverlay(pub Arc<StructureFinder>);
+
+impl TileProvider for StructureOverlay {
+    fn get_tile(&self, pos: TilePos) -> Result<DynamicImage, ProviderError> {
+        if pos.zoom < STRUCTURE_MIN_ZOOM {
+            return Err(ProviderError::ZoomOutOfRange(pos.zoom));
+        }
+
+        if pos.is_outside_world() {
+            return Err(ProviderError::OutOfBounds(pos));
+        }
+
+        let mut tile = IconTile::new(pos);
+
+        // Structures just outside the tile are included, since their icons
+        // reach into it.
+        for structure in self.0.find(tile.bbox(ICON_RADIUS), self.0.types()) {
+            let color = STRUCTURES
+                .iter()
+                .find(|(structure_type, ..)| *structure_type == structure.structure_type)
+                .map_or([255, 255, 255], |&(.., color)| color);
+
+            tile.draw_icon(structure.x, structure.z, ICON_RADIUS, color);
+        }
+
+        Ok(tile.image.into())
+    }
+}
+
+/// Transparent tiles with an icon on the world spawn and every stronghold.
+pub struct LandmarkOverlay(pub Arc<StructureFinder>);
+
+impl TileProvider for LandmarkOverlay {
+    fn get_tile(&self, pos: TilePos) -> Result<DynamicImage, ProviderError> {
+        if pos.is_outside_world() {
+            return Err(ProviderError::OutOfBounds(pos));
+        }
+
+        let Landmarks { spawn, strongholds } = self.0.landmarks();
+        let mut tile = IconTile::new(pos);
+
+        for stronghold in strongholds {
+            tile.draw_icon(stronghold.x, stronghold.z, ICON_RADIUS, STRONGHOLD_COLOR);
+        }
+        tile.draw_icon(spawn.x, spawn.z, SPAWN_ICON_RADIUS, SPAWN_COLOR);
+
+        Ok(tile.image.into())
+    }
+}
+
+/// A transparent tile with icons drawn at block positions.
+struct IconTile {
+    image: RgbaImage,
+    scale: i32,
+    origin_x: f64,
+    origin_z: f64,
+    blocks_per_tile: f64,
+    blocks_per_pixel: f64,
+}
+
+impl IconTile {
+    fn new(pos: TilePos) -> Self {
+        // 256 blocks per tile at zoom 0, halving with every zoom level.
+        let blocks_per_tile = TILE_SIZE as f64 * 2_f64.powi(-pos.zoom);
+
+        Self {
+            image: RgbaImage::new(pos.size(), pos.size()),
+            scale: pos.scale as i32,
+            origin_x: pos.x as f64 * blocks_per_tile,
+            origin_z: pos.y as f64 * blocks_per_tile,
+            blocks_per_tile,
+            blocks_per_pixel: blocks_per_tile / pos.size() as f64,
+        }
+    }
+
+    /// The blocks of the tile as min_x, min_z, max_x, max_z, extended by
+    /// margin pixels (at scale 1) on every side.
+    fn bbox(&self, margin: i32) -> [i32; 4] {
+        let margin = (margin as f64 * self.blocks_per_pixel * self.scale as f64).ceil();
+
+        [
+            (self.origin_x - margin).floor() as i32,
+            (self.origin_z - margin).floor() as i32,
+            (self.origin_x + self.blocks_per_tile + margin).ceil() as i32,
+            (self.origin_z + self.blocks_per_tile + margin).ceil() as i32,
+        ]
+    }
+
+    /// Draws a filled circle with a dark outline and a radius of radius pixels
+    /// (at scale 1), centered on the block x, z.
+    fn draw_icon(&mut self, x: i32, z: i32, radius: i32, color: [u8; 3]) {
+        let radius = radius * self.scale;
+        let inner_radius = radius - ICON_OUTLINE * self.scale;
+        let size = self.image.width() as i32;
+
+        let (center_x, center_y) = (
+            ((x as f64 - self.origin_x) / self.blocks_per_pixel).floor(),
+            ((z as f64 - self.origin_z) / self.blocks_per_pixel).floor(),
+        );
+
+        // Also keeps the center in the range of i32 when zoomed in.
+        let reach = (-radius as f64)..(size + radius) as f64;
+        if !reach.contains(&center_x) || !reach.contains(&center_y) {
+            return;
+        }
+        let (center_x, center_y) = (center_x as i32, center_y as i32);
+
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                let distance = dx * dx + dy * dy;
+                let (px, py) = (center_x + dx, center_y + dy);
+
+                if distance > radius * radius
+                    || !(0..size).contains(&px)
+                    || !(0..size).contains(&py)
+                {
+                    continue;
+                }
+
+                let [r, g, b] = if distance > inner_radius * inner_radius {
+                    [20, 20, 20]
+                } else {
+                    color
+                };
+
+                self.image
+                    .put_pixel(px as u32, py as u32, Rgba([r, g, b, 255]));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finder() -> StructureFinder {
+        StructureFinder::new(MCVersion::MC_1_21_WD, 42)
+    }
+
+    #[test]
+    fn regions_cover_the_bbox() {
+        let finder = finder();
+
+        // Villages are placed once per 34 chunk region, and monuments once
+        // per 32 chunk region.
+        assert_eq!(
+            finder.region_range([0, 0, 543, 543], StructureType::Village),
+            Some([0, 0, 0, 0])
+        );
+        assert_eq!(
+            finder.region_range([-1, -544, 544, 0], StructureType::Village),
+            Some([-1, -1, 1, 0])
+        );
+        assert_eq!(
+            finder.region_range([0, 0, 543, 543], StructureType::Monument),
+            Some([0, 0, 1, 1])
+        );
+
+        assert_eq!(
+            finder.region_count([0, 0, 543, 543], &[StructureType::Village]),
+            1
+        );
+        assert_eq!(
+            finder.region_count([-1, -1, 544, 544], &[StructureType::Village]),
+            9
+        );
+        assert_eq!(
+            finder.region_count(
+                [0, 0, 543, 543],
+                &[StructureType::Village, StructureType::Monument]
+            ),
+            5
+        );
+    }
+
+    #[test]
+    fn types_follow_the_version() {
+        let old = StructureFinder::new(MCVersion::MC_1_18, 42);
+
+        assert!(finder().types().contains(&StructureType::Trial_Chambers));
+        assert!(!old.types().contains(&StructureType::Trial_Chambers));
+        assert_eq!(
+            old.region_count([0, 0, 10_000, 10_000], &[StructureType::Trial_Chambers]),
+            0
+        );
+        assert!(
+            old.find([0, 0, 10_000, 10_000], &[StructureType::Trial_Chambers])
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn finds_the_structures_in_the_bbox() {
+        let bbox = [-1000, -1000, 1000, 1000];
+        let found = finder().find(bbox, &[StructureType::Village]);
+
+        assert!(found.iter().all(|structure| structure.name == "village"
+            && (bbox[0]..=bbox[2]).contains(&structure.x)
+            && (bbox[1]..=bbox[3]).contains(&structure.z)));
+        assert!(
+            found
+                .iter()
+                .any(|structure| (structure.x, structure.z) == (848, -880))
+        );
+    }
+
+    #[test]
+    fn finds_the_spawn_and_strongholds() {
+        let Landmarks { spawn, strongholds } = finder().landmarks().clone();
+
+        assert_eq!((spawn.x, spawn.z), (-32, 0));
+        assert_eq!(strongholds.len(), 128);
+        assert_eq!(
+            strongholds
+                .iter()
+                .take(3)
+                .map(|stronghold| (stronghold.x, stronghold.z, stronghold.ring))
+                .collect::<Vec<_>>(),
+            [(-396, -2252, 0), (1716, 772, 0), (-1932, 1540, 0)]
+        );
+
+        // The innermost ring is 1280 to 2816 blocks from the origin.
+        assert!(strongholds.iter().take(3).all(|stronghold| {
+            let distance = (stronghold.x as f64).hypot(stronghold.z as f64);
+            (1280.0..=2816.0 + 112.0).contains(&distance)
+        }));
+        assert_eq!(strongholds[3].ring, 1);
+    }
+
+    /// Whether the pixel at x, y of tile is drawn.
+    fn is_drawn(tile: &IconTile, x: u32, y: u32) -> bool {
+        tile.image.get_pixel(x, y).0[3] != 0
+    }
+
+    #[test]
+    fn icons_are_clipped_at_the_tile_edges() {
+        // A pixel per block at zoom 0.
+        let mut tile = IconTile::new(TilePos::new(0, 0, 0));
+
+        tile.draw_icon(-1, 128, ICON_RADIUS, SPAWN_COLOR);
+        assert!(is_drawn(&tile, 0, 128));
+        assert!(is_drawn(&tile, 5, 128));
+        assert!(!is_drawn(&tile, 6, 128));
+
+        tile.draw_icon(
+            256 + ICON_RADIUS,
+            256 + ICON_RADIUS,
+            ICON_RADIUS,
+            SPAWN_COLOR,
+        );
+        assert!(!is_drawn(&tile, 255, 255));
+
+        tile.draw_icon(258, -2, ICON_RADIUS, SPAWN_COLOR);
+        assert!(is_drawn(&tile, 255, 0));
+    }
+
+    #[test]
+    fn icons_far_outside_the_tile_are_skipped() {
+        let mut tile = IconTile::new(TilePos::new(8, 0, 0));
+
+        // Would overflow as pixels of a zoom 8 tile.
+        tile.draw_icon(i32::MAX, i32::MIN, ICON_RADIUS, SPAWN_COLOR);
+        tile.draw_icon(-100, 0, ICON_RADIUS, SPAWN_COLOR);
+
+        assert!(tile.image.pixels().all(|pixel| pixel.0[3] == 0));
+    }
+
+    #[test]
+    fn icons_grow_with_the_scale() {
+        let mut tile = IconTile::new(TilePos::new(0, 0, 0).with_scale(2));
+
+        tile.draw_icon(128, 128, ICON_RADIUS, SPAWN_COLOR);
+        assert!(is_drawn(&tile, 256 + 2 * ICON_RADIUS as u32, 256));
+        assert!(!is_drawn(&tile, 256 + 2 * ICON_RADIUS as u32 + 1, 256));
+        assert!(tile.bbox(ICON_RADIUS)[0] < 0);
+    }
+}
@@ -0,0 +1,236 @@
+use std::sync::Arc;
+
+use image::{DynamicImage, RgbaImage, imageops};
+use log::error;
+
+use super::{
+    ProviderError, TilePos, TileProvider,
+    tilecache::{self, CachedLayer},
+};
+
+/// Draws the tiles of several layers on top of each other, in order, eg.
+/// shaded biomes with contours and structures.
+///
+/// The tiles are taken from the caches of the layers, so tiles they already
+/// have aren't rendered again. Layers without a tile at a position (eg.
+/// structures when zoomed out) are left out, there is only no tile if none of
+/// them has one. Any other error fails the whole tile.
+pub struct CompositeTile {
+    layers: Vec<Arc<dyn CachedLayer>>,
+    overlay: bool,
+}
+
+impl CompositeTile {
+    /// The first layer is drawn at the bottom, overlay is whether it is
+    /// transparent.
+    pub fn new(layers: Vec<Arc<dyn CachedLayer>>, overlay: bool) -> Self {
+        Self { layers, overlay }
+    }
+
+    /// Whether the tiles are transparent, since the bottom layer is.
+    pub fn is_overlay(&self) -> bool {
+        self.overlay
+    }
+}
+
+impl TileProvider for CompositeTile {
+    fn get_tile(&self, pos: TilePos) -> Result<DynamicImage, ProviderError> {
+        let mut tile: Option<RgbaImage> = None;
+        let mut missing = None;
+
+        for layer in &self.layers {
+            let layer = match layer.get_tile_blocking(pos) {
+                Ok(layer) => layer,
+                Err(tilecache::Error::ProviderError(e)) if e.is_missing() => {
+                    missing.get_or_insert(e);
+                    continue;
+                }
+                Err(tilecache::Error::ProviderError(e)) => return Err(e),
+                Err(e) => {
+                    error!("getting a layer of composite tile {pos:?} failed: {e}");
+                    return Err(ProviderError::LayerError);
+                }
+            };
+
+            match &mut tile {
+                Some(tile) => imageops::overlay(tile, &layer.to_rgba8(), 0, 0),
+                None => tile = Some(layer.to_rgba8()),
+            }
+        }
+
+        tile.map(DynamicImage::from)
+            .ok_or_else(|| missing.unwrap_or(ProviderError::NoTile))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cubiomes::generator::error::GeneratorError;
+    use image::{GenericImageView, ImageFormat, Rgba};
+
+    use super::*;
+    use crate::tileprovider::{
+        Counting,
+        diskindex::DiskBudget,
+        tilecache::{RenderPool, TileCache},
+        tilestore::MemoryStore,
+    };
+
+    struct Solid(Option<Rgba<u8>>);
+
+    impl CachedLayer for Solid {
+        fn get_tile_blocking(&self, pos: TilePos) -> Result<DynamicImage, tilecache::Error> {
+            self.0
+                .map(|color| RgbaImage::from_pixel(pos.size(), pos.size(), color).into())
+                .ok_or(tilecache::Error::ProviderError(
+                    ProviderError::ZoomOutOfRange(pos.zoom),
+                ))
+        }
+    }
+
+    struct Broken;
+
+    impl CachedLayer for Broken {
+        fn get_tile_blocking(&self, _pos: TilePos) -> Result<DynamicImage, tilecache::Error> {
+            Err(tilecache::Error::ProviderError(
+                ProviderError::GeneratorError(GeneratorError::GetBiomeAtFailure),
+            ))
+        }
+    }
+
+    fn composite(colors: &[Option<[u8; 4]>]) -> CompositeTile {
+        CompositeTile::new(
+            colors
+                .iter()
+                .map(|&color| Arc::new(Solid(color.map(Rgba))) as Arc<_>)
+                .collect(),
+            false,
+        )
+    }
+
+    #[test]
+    fn draws_layers_in_order() {
+        let tile = composite(&[Some([0, 0, 255, 255]), Some([255, 0, 0, 255])])
+            .get_tile(TilePos::new(0, 0, 0))
+            .unwrap();
+
+        assert_eq!(tile.get_pixel(10, 10), Rgba([255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn blends_transparent_layers() {
+        let tile = composite(&[Some([0, 0, 0, 255]), Some([255, 255, 255, 0])])
+            .get_tile(TilePos::new(0, 0, 0))
+            .unwrap();
+
+        assert_eq!(tile.get_pixel(0, 0), Rgba([0, 0, 0, 255]));
+
+        let tile = composite(&[Some([0, 0, 0, 255]), Some([255, 255, 255, 128])])
+            .get_tile(TilePos::new(0, 0, 0))
+            .unwrap();
+        let Rgba([r, g, b, a]) = tile.get_pixel(0, 0);
+
+        assert!((120..=136).contains(&r) && r == g && g == b);
+        // The blending of the image crate rounds down.
+        assert!(a >= 254);
+    }
+
+    #[test]
+    fn skips_missing_tiles() {
+        let tile = composite(&[None, Some([0, 255, 0, 255]), None])
+            .get_tile(TilePos::new(0, 0, 0).with_scale(2))
+            .unwrap();
+
+        assert_eq!(tile.dimensions(), (512, 512));
+        assert_eq!(tile.get_pixel(0, 0), Rgba([0, 255, 0, 255]));
+
+        assert_eq!(
+            composite(&[None, None]).get_tile(TilePos::new(-9, 0, 0)),
+            Err(ProviderError::ZoomOutOfRange(-9))
+        );
+    }
+
+    #[test]
+    fn fails_on_generator_errors() {
+        let solid = Arc::new(Solid(Some(Rgba([0, 0, 0, 255]))));
+        let composite = CompositeTile::new(vec![solid, Arc::new(Broken)], false);
+
+        assert!(matches!(
+            composite.get_tile(TilePos::new(0, 0, 0)),
+            Err(ProviderError::GeneratorError(_))
+        ));
+    }
+
+    fn cache<S>(source: S, render_pool: &RenderPool) -> TileCache<S>
+    where
+        S: TileProvider + Send + Sync + 'static,
+    {
+        TileCache::new(
+            source,
+            1024 * 1024,
+            vec![ImageFormat::Png],
+            Arc::new(MemoryStore::new()),
+            render_pool.clone(),
+            DiskBudget::UNLIMITED,
+        )
+        .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn reuses_the_tiles_of_the_layers() {
+        let source = Counting::default();
+        let render_pool = RenderPool::new(1);
+        let layer = Arc::new(cache(source.clone(), &render_pool));
+        let composite = cache(CompositeTile::new(vec![layer.clone()], false), &render_pool);
+        let pos = TilePos::new(0, 0, 0);
+
+        layer.get_cached_tile(pos, ImageFormat::Png).await.unwrap();
+        composite
+            .get_cached_tile(pos, ImageFormat::Png)
+            .await
+            .unwrap();
+        assert_eq!(source.renders(), 1);
+
+        // Tiles rendered for the composite are cached for the layer too.
+        let pos = TilePos::new(0, 1, 0);
+        composite
+            .get_cached_tile(pos, ImageFormat::Png)
+            .await
+            .unwrap();
+        layer.get_cached_tile(pos, ImageFormat::Png).await.unwrap();
+        assert_eq!(source.renders(), 2);
+    }
+
+    #[actix_web::test]
+    async fn layers_are_rendered_once_for_concurrent_composites() {
+        let source = Counting::default();
+        let render_pool = RenderPool::new(4);
+        let layer = Arc::new(cache(source.clone(), &render_pool));
+        let composite = Arc::new(cache(
+            CompositeTile::new(vec![layer.clone()], false),
+            &render_pool,
+        ));
+        let pos = TilePos::new(0, 0, 0);
+
+        let requests: Vec<_> = (0..8)
+            .map(|i| {
+                let layer = layer.clone();
+                let composite = composite.clone();
+
+                actix_web::rt::spawn(async move {
+                    if i % 2 == 0 {
+                        layer.get_cached_tile(pos, ImageFormat::Png).await
+                    } else {
+                        composite.get_cached_tile(pos, ImageFormat::Png).await
+                    }
+                })
+            })
+            .collect();
+
+        for request in requests {
+            request.await.unwrap().unwrap();
+        }
+
+        assert_eq!(source.renders(), 1);
+    }
+}
@@ -0,0 +1,220 @@
+//! Tracks the tiles a [TileCache](super::tilecache::TileCache) has stored, so
+//! the least recently used ones can be evicted once it grows over its budget.
+
+use std::collections::{BTreeMap, HashMap};
+
+use super::{TileKey, tilestore::StoredTile};
+
+/// Limits for the tiles a single tile cache keeps on disk.
+///
+/// Every cache has its own index, there is no limit shared between caches.
+#[derive(Debug, Clone, Copy)]
+pub struct DiskBudget {
+    pub max_bytes: Option<u64>,
+    pub max_tiles: Option<usize>,
+    /// Tiles at this zoom level and below are never evicted and don't count
+    /// against the budget, since they are the slowest to render.
+    pub pinned_zoom: i32,
+}
+
+impl DiskBudget {
+    /// A budget which never evicts any tiles.
+    pub const UNLIMITED: Self = Self {
+        max_bytes: None,
+        max_tiles: None,
+        pinned_zoom: i32::MIN,
+    };
+
+    pub fn is_unlimited(&self) -> bool {
+        self.max_bytes.is_none() && self.max_tiles.is_none()
+    }
+}
+
+struct IndexedTile {
+    last_access: u64,
+    size: u64,
+}
+
+/// An lru index of the stored tiles.
+///
+/// Accesses are counted with a tick instead of a timestamp, so the order stays
+/// exact even when many tiles are accessed within the same second.
+pub(crate) struct DiskIndex {
+    budget: DiskBudget,
+    tiles: HashMap<TileKey, IndexedTile>,
+    lru: BTreeMap<u64, TileKey>,
+    next_tick: u64,
+    total_bytes: u64,
+}
+
+impl DiskIndex {
+    pub(crate) fn new(budget: DiskBudget) -> Self {
+        Self {
+            budget,
+            tiles: HashMap::new(),
+            lru: BTreeMap::new(),
+            next_tick: 0,
+            total_bytes: 0,
+        }
+    }
+
+    /// Indexes tiles already in the store, ordered by their modification
+    /// time.
+    ///
+    /// Reads aren't recorded in the store, so after a restart tiles are
+    /// evicted in the order they were written, until they are used again.
+    ///
+    /// Returns the tiles which have to be evicted to fit the budget.
+    pub(crate) fn load(&mut self, mut tiles: Vec<StoredTile>) -> Vec<TileKey> {
+        tiles.sort_unstable_by_key(|tile| tile.modified);
+
+        tiles
+            .into_iter()
+            .flat_map(|tile| self.touch(tile.key, tile.size))
+            .collect()
+    }
+
+    /// Marks the tile at key as the most recently used one, with size bytes
+    /// stored.
+    ///
+    /// Returns the tiles which have to be evicted to fit the budget, which is
+    /// never key itself.
+    pub(crate) fn touch(&mut self, key: TileKey, size: u64) -> Vec<TileKey> {
+        if key.0.zoom <= self.budget.pinned_zoom {
+            return Vec::new();
+        }
+
+        let tick = self.next_tick;
+        self.next_tick += 1;
+
+        if let Some(old) = self.tiles.insert(
+            key,
+            IndexedTile {
+                last_access: tick,
+                size,
+            },
+        ) {
+            self.lru.remove(&old.last_access);
+            self.total_bytes -= old.size;
+        }
+        self.lru.insert(tick, key);
+        self.total_bytes += size;
+
+        let mut evicted = Vec::new();
+
+        while self.is_over_budget() && self.lru.len() > 1 {
+            let (_, oldest) = self.lru.pop_first().expect("lru has more than one tile");
+            let tile = self
+                .tiles
+                .remove(&oldest)
+                .expect("lru and tiles are in sync");
+
+            self.total_bytes -= tile.size;
+            evicted.push(oldest);
+        }
+
+        evicted
+    }
+
+    fn is_over_budget(&self) -> bool {
+        self.budget
+            .max_bytes
+            .is_some_and(|max| self.total_bytes > max)
+            || self
+                .budget
+                .max_tiles
+                .is_some_and(|max| self.tiles.len() > max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use image::ImageFormat;
+
+    use super::*;
+    use crate::tileprovider::TilePos;
+
+    fn key(zoom: i32, x: i32) -> TileKey {
+        (TilePos::new(zoom, x, 0), ImageFormat::Png)
+    }
+
+    fn budget(max_bytes: Option<u64>, max_tiles: Option<usize>) -> DiskBudget {
+        DiskBudget {
+            max_bytes,
+            max_tiles,
+            pinned_zoom: i32::MIN,
+        }
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut index = DiskIndex::new(budget(None, Some(2)));
+
+        assert!(index.touch(key(0, 0), 10).is_empty());
+        assert!(index.touch(key(0, 1), 10).is_empty());
+        assert!(index.touch(key(0, 0), 10).is_empty());
+
+        assert_eq!(index.touch(key(0, 2), 10), vec![key(0, 1)]);
+        assert_eq!(index.touch(key(0, 3), 10), vec![key(0, 0)]);
+    }
+
+    #[test]
+    fn counts_bytes_against_the_budget() {
+        let mut index = DiskIndex::new(budget(Some(25), None));
+
+        index.touch(key(0, 0), 10);
+        index.touch(key(0, 1), 10);
+        assert_eq!(index.total_bytes, 20);
+
+        // Touching a tile again replaces its size instead of adding to it.
+        assert!(index.touch(key(0, 1), 15).is_empty());
+        assert_eq!(index.total_bytes, 25);
+
+        assert_eq!(index.touch(key(0, 2), 5), vec![key(0, 0)]);
+        assert_eq!(index.total_bytes, 20);
+    }
+
+    #[test]
+    fn keeps_the_touched_tile_even_if_over_budget() {
+        let mut index = DiskIndex::new(budget(Some(10), None));
+
+        index.touch(key(0, 0), 5);
+        assert_eq!(index.touch(key(0, 1), 50), vec![key(0, 0)]);
+        assert_eq!(index.total_bytes, 50);
+    }
+
+    #[test]
+    fn pinned_tiles_are_never_evicted_or_counted() {
+        let mut index = DiskIndex::new(DiskBudget {
+            pinned_zoom: -4,
+            ..budget(Some(10), Some(1))
+        });
+
+        for x in 0..4 {
+            assert!(index.touch(key(-4, x), 100).is_empty());
+            assert!(index.touch(key(-8, x), 100).is_empty());
+        }
+        assert_eq!(index.total_bytes, 0);
+
+        assert!(index.touch(key(-3, 0), 10).is_empty());
+        assert_eq!(index.touch(key(-3, 1), 10), vec![key(-3, 0)]);
+    }
+
+    #[test]
+    fn load_orders_by_modification_time() {
+        let mut index = DiskIndex::new(budget(None, Some(2)));
+        let tile = |x, secs| StoredTile {
+            key: key(0, x),
+            size: 10,
+            modified: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)),
+        };
+
+        assert_eq!(
+            index.load(vec![tile(0, 30), tile(1, 10), tile(2, 20)]),
+            vec![key(0, 1)]
+        );
+        assert_eq!(index.touch(key(0, 3), 10), vec![key(0, 2)]);
+    }
+}
@@ -0,0 +1,225 @@
+//! A [TileStore] keeping every tile of a layer in a single
+//! [redb](https://www.redb.org) database file.
+//!
+//! Tiles are keyed by their path in a [FsStore](super::tilestore::FsStore)
+//! without the extension, eg. `png/0/1/-2` or `png/0/1/-2@2x`.
+//!
+//! redb locks the database file, so it can only be opened by one process at a
+//! time. Stores opened for the same file within a process share the database.
+
+use std::{
+    collections::HashMap,
+    fs::create_dir_all,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Weak},
+};
+
+use image::ImageFormat;
+use parking_lot::Mutex;
+use redb::{Database, DatabaseError, ReadableTable, TableDefinition};
+
+use super::{
+    TileKey, TilePos,
+    tilestore::{StoredTile, TileStore, parse_tile_name},
+};
+
+const TILES: TableDefinition<&str, &[u8]> = TableDefinition::new("tiles");
+
+/// The databases open in this process, keyed by their absolute path.
+static OPEN_DATABASES: LazyLock<Mutex<HashMap<PathBuf, Weak<Database>>>> =
+    LazyLock::new(Default::default);
+
+/// An open tile database.
+pub struct KvStore {
+    database: Arc<Database>,
+}
+
+impl KvStore {
+    /// Opens the database at path, creating it if it doesn't exist.
+    ///
+    /// Fails with [io::ErrorKind::ResourceBusy] if another process has it
+    /// open.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir)?;
+        }
+
+        let path = std::path::absolute(path)?;
+        let mut open = OPEN_DATABASES.lock();
+
+        if let Some(database) = open.get(&path).and_then(Weak::upgrade) {
+            return Ok(Self { database });
+        }
+
+        let database = Database::create(&path).map_err(|e| match e {
+            DatabaseError::DatabaseAlreadyOpen => io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                format!("{} is in use by another process", path.display()),
+            ),
+            e => io::Error::other(e),
+        })?;
+
+        // Creates the table, so reads don't fail before the first write.
+        let transaction = database.begin_write().map_err(io::Error::other)?;
+        transaction.open_table(TILES).map_err(io::Error::other)?;
+        transaction.commit().map_err(io::Error::other)?;
+
+        let database = Arc::new(database);
+        open.retain(|_, database| database.strong_count() > 0);
+        open.insert(path, Arc::downgrade(&database));
+
+        Ok(Self { database })
+    }
+}
+
+impl TileStore for KvStore {
+    fn get(&self, key: TileKey) -> io::Result<Option<Vec<u8>>> {
+        let transaction = self.database.begin_read().map_err(io::Error::other)?;
+        let table = transaction.open_table(TILES).map_err(io::Error::other)?;
+
+        Ok(table
+            .get(tile_key(key).as_str())
+            .map_err(io::Error::other)?
+            .map(|tile| tile.value().to_vec()))
+    }
+
+    fn put(&self, key: TileKey, tile: &[u8]) -> io::Result<()> {
+        let transaction = self.database.begin_write().map_err(io::Error::other)?;
+
+        transaction
+            .open_table(TILES)
+            .map_err(io::Error::other)?
+            .insert(tile_key(key).as_str(), tile)
+            .map_err(io::Error::other)?;
+
+        transaction.commit().map_err(io::Error::other)
+    }
+
+    fn delete(&self, key: TileKey) -> io::Result<()> {
+        let transaction = self.database.begin_write().map_err(io::Error::other)?;
+
+        transaction
+            .open_table(TILES)
+            .map_err(io::Error::other)?
+            .remove(tile_key(key).as_str())
+            .map_err(io::Error::other)?;
+
+        transaction.commit().map_err(io::Error::other)
+    }
+
+    fn list(&self) -> io::Result<Vec<StoredTile>> {
+        let transaction = self.database.begin_read().map_err(io::Error::other)?;
+        let table = transaction.open_table(TILES).map_err(io::Error::other)?;
+        let mut found = Vec::new();
+
+        for entry in table.iter().map_err(io::Error::other)? {
+            let (key, tile) = entry.map_err(io::Error::other)?;
+
+            if let Some(key) = parse_key(key.value()) {
+                found.push(StoredTile {
+                    key,
+                    size: tile.value().len() as u64,
+                    modified: None,
+                });
+            }
+        }
+
+        Ok(found)
+    }
+}
+
+/// The key of a tile in the database.
+fn tile_key((pos, format): TileKey) -> String {
+    let scale = match pos.scale {
+        1 => String::new(),
+        scale => format!("@{scale}x"),
+    };
+
+    format!(
+        "{}/{}/{}/{}{scale}",
+        format.extensions_str()[0],
+        pos.zoom,
+        pos.x,
+        pos.y
+    )
+}
+
+/// The inverse of [tile_key].
+fn parse_key(key: &str) -> Option<TileKey> {
+    let mut parts = key.split('/');
+    let format = ImageFormat::from_extension(parts.next()?)?;
+    let zoom = parts.next()?.parse().ok()?;
+    let x = parts.next()?.parse().ok()?;
+    let (y, scale) = parse_tile_name(parts.next()?)?;
+
+    if parts.next().is_some() {
+        return None;
+    }
+
+    Some((TilePos::new(zoom, x, y).with_scale(scale), format))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tileprovider::TestDir;
+
+    #[test]
+    fn keys_round_trip() {
+        for key in [
+            (TilePos::new(0, 1, -2), ImageFormat::Png),
+            (TilePos::new(-8, -3, 4).with_scale(2), ImageFormat::WebP),
+            (TilePos::new(8, 0, 0).with_scale(4), ImageFormat::Jpeg),
+        ] {
+            assert_eq!(parse_key(&tile_key(key)), Some(key));
+        }
+
+        assert_eq!(
+            tile_key((TilePos::new(0, 1, -2).with_scale(2), ImageFormat::Png)),
+            "png/0/1/-2@2x"
+        );
+        assert_eq!(parse_key("png/0/1"), None);
+        assert_eq!(parse_key("png/0/1/2/3"), None);
+        assert_eq!(parse_key("txt/0/1/2"), None);
+    }
+
+    #[test]
+    fn stores_tiles() {
+        let dir = TestDir::new("kvstore");
+        let store = KvStore::open(&dir.0.join("layer.redb")).unwrap();
+        let key = (TilePos::new(0, 1, -2), ImageFormat::Png);
+
+        assert_eq!(store.get(key).unwrap(), None);
+
+        store.put(key, b"tile").unwrap();
+        assert_eq!(store.get(key).unwrap().as_deref(), Some(&b"tile"[..]));
+
+        let listed = store.list().unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].key, key);
+        assert_eq!(listed[0].size, 4);
+
+        store.delete(key).unwrap();
+        assert_eq!(store.get(key).unwrap(), None);
+    }
+
+    #[test]
+    fn stores_of_a_file_share_the_database() {
+        let dir = TestDir::new("kvstore-shared");
+        let path = dir.0.join("layer.redb");
+        let key = (TilePos::new(0, 0, 0), ImageFormat::Png);
+
+        let first = KvStore::open(&path).unwrap();
+        first.put(key, b"tile").unwrap();
+
+        // Would fail on redb's file lock without sharing.
+        let second = KvStore::open(&path).unwrap();
+        assert_eq!(second.get(key).unwrap().as_deref(), Some(&b"tile"[..]));
+
+        // Reopening after every store is dropped opens it again.
+        drop((first, second));
+        let reopened = KvStore::open(&path).unwrap();
+        assert_eq!(reopened.get(key).unwrap().as_deref(), Some(&b"tile"[..]));
+    }
+}
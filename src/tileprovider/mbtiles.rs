@@ -0,0 +1,393 @@
+//! Reading and writing [MBTiles](https://github.com/mapbox/mbtiles-spec)
+//! files, sqlite databases holding the tiles of a single layer and format.
+//!
+//! Tiles are laid out like in [PMTiles](super::pmtiles) archives: the world is
+//! centered in the TMS quadtree, with tiles stored at `zoom + ZOOM_OFFSET` and
+//! their column and row shifted by half the tiles of that zoom level. Rows are
+//! flipped, so they count up to the north (`row = 2^zoom - 1 - y`).
+
+use std::{io, path::Path};
+
+use image::ImageFormat;
+use parking_lot::Mutex;
+use rusqlite::{Connection, OpenFlags, OptionalExtension, params};
+
+use super::{
+    ArchiveMetadata, TileKey, TilePos,
+    tilestore::{StoredTile, TileStore},
+};
+use crate::biomemap::MAX_ZOOM;
+
+/// Added to zoom levels to get the zoom level of a tile in the file, the same
+/// as in [PMTiles](super::pmtiles::ZOOM_OFFSET) archives.
+pub const ZOOM_OFFSET: i32 = 18;
+
+pub type Error = rusqlite::Error;
+
+/// The zoom level, column and row of pos in the file.
+///
+/// None if pos is outside the world, or at a zoom level files can't hold.
+fn tile_coords(pos: TilePos) -> Option<(i32, i64, i64)> {
+    let zoom = pos
+        .zoom
+        .checked_add(ZOOM_OFFSET)
+        .filter(|zoom| (0..=MAX_ZOOM + ZOOM_OFFSET).contains(zoom))?;
+    let side = 1_i64 << zoom;
+    let column = pos.x as i64 + side / 2;
+    let row = side - 1 - (pos.y as i64 + side / 2);
+
+    ((0..side).contains(&column) && (0..side).contains(&row)).then_some((zoom, column, row))
+}
+
+/// The inverse of [tile_coords].
+fn tile_pos(zoom: i32, column: i64, row: i64) -> Option<TilePos> {
+    let side = 1_i64.checked_shl(zoom.try_into().ok()?)?;
+
+    Some(TilePos::new(
+        zoom - ZOOM_OFFSET,
+        i32::try_from(column - side / 2).ok()?,
+        i32::try_from(side - 1 - row - side / 2).ok()?,
+    ))
+}
+
+/// An open MBTiles file.
+pub struct MbTiles {
+    connection: Mutex<Connection>,
+    format: ImageFormat,
+}
+
+impl MbTiles {
+    /// Creates a new MBTiles file at path, replacing any tiles already in it.
+    pub fn create(path: &Path, metadata: &ArchiveMetadata) -> Result<Self, Error> {
+        let connection = Connection::open(path)?;
+
+        connection.execute_batch(
+            "DROP TABLE IF EXISTS metadata;
+            DROP TABLE IF EXISTS tiles;
+            CREATE TABLE metadata (name TEXT, value TEXT);
+            CREATE TABLE tiles (
+                zoom_level INTEGER,
+                tile_column INTEGER,
+                tile_row INTEGER,
+                tile_data BLOB
+            );
+            CREATE UNIQUE INDEX tile_index ON tiles (zoom_level, tile_column, tile_row);",
+        )?;
+
+        let [min_x, min_z, max_x, max_z] = metadata.bounds;
+        let layer_type = if metadata.overlay {
+            "overlay"
+        } else {
+            "baselayer"
+        };
+
+        // The spec's `bounds` are in WGS84 coordinates, which minecraft maps
+        // don't have, so the exported area is stored under a key of our own
+        // (like in PMTiles archives).
+        for (name, value) in [
+            ("name", metadata.name.clone()),
+            ("format", metadata.format.extensions_str()[0].to_owned()),
+            ("bounds_blocks", format!("{min_x},{min_z},{max_x},{max_z}")),
+            ("minzoom", (metadata.min_zoom + ZOOM_OFFSET).to_string()),
+            ("maxzoom", (metadata.max_zoom + ZOOM_OFFSET).to_string()),
+            ("type", layer_type.to_owned()),
+        ] {
+            connection.execute(
+                "INSERT INTO metadata (name, value) VALUES (?1, ?2)",
+                params![name, value],
+            )?;
+        }
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+            format: metadata.format,
+        })
+    }
+
+    /// Opens an existing MBTiles file for reading.
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+        let format = connection
+            .query_row(
+                "SELECT value FROM metadata WHERE name = 'format'",
+                [],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?
+            .and_then(ImageFormat::from_extension)
+            // Png is the default format of the spec.
+            .unwrap_or(ImageFormat::Png);
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+            format,
+        })
+    }
+
+    /// The format of every tile in the file.
+    pub fn format(&self) -> ImageFormat {
+        self.format
+    }
+
+    /// Reads the encoded tile at pos, if the file has it.
+    ///
+    /// Files only hold tiles with a scale of 1.
+    pub fn get_tile(&self, pos: TilePos) -> Result<Option<Vec<u8>>, Error> {
+        let Some((zoom, column, row)) = tile_coords(pos).filter(|_| pos.scale == 1) else {
+            return Ok(None);
+        };
+
+        self.connection
+            .lock()
+            .query_row(
+                "SELECT tile_data FROM tiles
+                WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+                params![zoom, column, row],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    /// Inserts the encoded tiles in a single transaction, replacing tiles
+    /// already at their positions.
+    pub fn insert_many(&self, tiles: &[(TilePos, Vec<u8>)]) -> Result<(), Error> {
+        let mut connection = self.connection.lock();
+        let transaction = connection.transaction()?;
+
+        {
+            let mut insert = transaction.prepare_cached(
+                "INSERT OR REPLACE INTO tiles (zoom_level, tile_column, tile_row, tile_data)
+                VALUES (?1, ?2, ?3, ?4)",
+            )?;
+
+            for (pos, data) in tiles {
+                debug_assert_eq!(pos.scale, 1, "files only hold tiles with a scale of 1");
+
+                let (zoom, column, row) = tile_coords(*pos).ok_or_else(|| {
+                    Error::ToSqlConversionFailure(
+                        format!("tile {pos:?} is outside of the tileset").into(),
+                    )
+                })?;
+
+                insert.execute(params![zoom, column, row, data])?;
+            }
+        }
+
+        transaction.commit()
+    }
+}
+
+impl TileStore for MbTiles {
+    fn get(&self, (pos, format): TileKey) -> io::Result<Option<Vec<u8>>> {
+        if format != self.format {
+            return Ok(None);
+        }
+
+        self.get_tile(pos).map_err(io::Error::other)
+    }
+
+    fn put(&self, (pos, format): TileKey, tile: &[u8]) -> io::Result<()> {
+        if format != self.format || pos.scale != 1 || tile_coords(pos).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "mbtiles files only hold tiles of their format with a scale of 1 within the world",
+            ));
+        }
+
+        self.insert_many(&[(pos, tile.to_vec())])
+            .map_err(io::Error::other)
+    }
+
+    fn delete(&self, (pos, format): TileKey) -> io::Result<()> {
+        let Some((zoom, column, row)) =
+            tile_coords(pos).filter(|_| format == self.format && pos.scale == 1)
+        else {
+            return Ok(());
+        };
+
+        self.connection
+            .lock()
+            .execute(
+                "DELETE FROM tiles
+                WHERE zoom_level = ?1 AND tile_column = ?2 AND tile_row = ?3",
+                params![zoom, column, row],
+            )
+            .map_err(io::Error::other)?;
+
+        Ok(())
+    }
+
+    fn list(&self) -> io::Result<Vec<StoredTile>> {
+        let connection = self.connection.lock();
+        let mut select = connection
+            .prepare("SELECT zoom_level, tile_column, tile_row, length(tile_data) FROM tiles")
+            .map_err(io::Error::other)?;
+
+        let tiles = select
+            .query_map([], |row| {
+                Ok((tile_pos(row.get(0)?, row.get(1)?, row.get(2)?), row.get(3)?))
+            })
+            .map_err(io::Error::other)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(io::Error::other)?;
+
+        // Tiles which don't map to a position (eg. written by another tool)
+        // are skipped.
+        Ok(tiles
+            .into_iter()
+            .filter_map(|(pos, size)| {
+                Some(StoredTile {
+                    key: (pos?, self.format),
+                    size,
+                    modified: None,
+                })
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tileprovider::TestDir;
+
+    fn metadata(overlay: bool) -> ArchiveMetadata {
+        ArchiveMetadata {
+            name: "test".into(),
+            format: ImageFormat::Png,
+            bounds: [-512, -256, 255, 511],
+            overlay,
+            min_zoom: -1,
+            max_zoom: 1,
+        }
+    }
+
+    fn metadata_value(connection: &Connection, name: &str) -> Option<String> {
+        connection
+            .query_row(
+                "SELECT value FROM metadata WHERE name = ?1",
+                params![name],
+                |row| row.get(0),
+            )
+            .optional()
+            .unwrap()
+    }
+
+    #[test]
+    fn tiles_round_trip() {
+        let dir = TestDir::new("mbtiles");
+        let path = dir.0.join("test.mbtiles");
+        let tiles = [
+            (TilePos::new(-1, 0, 0), b"a".to_vec()),
+            (TilePos::new(0, -2, 1), b"b".to_vec()),
+            (TilePos::new(1, 3, -4), b"c".to_vec()),
+        ];
+
+        MbTiles::create(&path, &metadata(false))
+            .unwrap()
+            .insert_many(&tiles)
+            .unwrap();
+
+        let mbtiles = MbTiles::open(&path).unwrap();
+        assert_eq!(mbtiles.format(), ImageFormat::Png);
+
+        for (pos, tile) in &tiles {
+            assert_eq!(mbtiles.get_tile(*pos).unwrap().as_ref(), Some(tile));
+        }
+        assert_eq!(mbtiles.get_tile(TilePos::new(0, 0, 0)).unwrap(), None);
+        assert_eq!(
+            mbtiles
+                .get_tile(TilePos::new(0, -2, 1).with_scale(2))
+                .unwrap(),
+            None
+        );
+
+        let mut listed: Vec<_> = mbtiles
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|tile| tile.key.0)
+            .collect();
+        listed.sort_by_key(|pos| pos.zoom);
+        assert_eq!(listed, tiles.map(|(pos, _)| pos));
+    }
+
+    #[test]
+    fn rows_count_up_to_the_north() {
+        let dir = TestDir::new("mbtiles-rows");
+        let path = dir.0.join("test.mbtiles");
+        let mbtiles = MbTiles::create(&path, &metadata(false)).unwrap();
+
+        mbtiles
+            .insert_many(&[(TilePos::new(0, 5, 2), b"a".to_vec())])
+            .unwrap();
+
+        let (zoom, column, row): (i32, i32, i32) = mbtiles
+            .connection
+            .lock()
+            .query_row(
+                "SELECT zoom_level, tile_column, tile_row FROM tiles",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+
+        // Zoom 18 has 2^18 columns and rows, with the world centered in them.
+        let half = 1 << (ZOOM_OFFSET - 1);
+        assert_eq!((zoom, column, row), (ZOOM_OFFSET, half + 5, half - 3));
+    }
+
+    #[test]
+    fn tiles_are_within_the_tileset() {
+        for pos in [
+            TilePos::new(-ZOOM_OFFSET, 0, 0),
+            TilePos::new(-8, -458, 457),
+            TilePos::new(MAX_ZOOM, 29_999_999, -30_000_000),
+        ] {
+            let (zoom, column, row) = tile_coords(pos).unwrap();
+            let side = 1_i64 << zoom;
+
+            assert!((0..side).contains(&column) && (0..side).contains(&row));
+            assert_eq!(tile_pos(zoom, column, row), Some(pos));
+        }
+
+        assert_eq!(
+            tile_coords(TilePos::new(-ZOOM_OFFSET, 0, 0)),
+            Some((0, 0, 0))
+        );
+        assert_eq!(tile_coords(TilePos::new(-ZOOM_OFFSET - 1, 0, 0)), None);
+        assert_eq!(tile_coords(TilePos::new(MAX_ZOOM + 1, 0, 0)), None);
+        assert_eq!(tile_coords(TilePos::new(-ZOOM_OFFSET, 1, 0)), None);
+    }
+
+    #[test]
+    fn metadata_keeps_block_bounds_apart() {
+        let dir = TestDir::new("mbtiles-metadata");
+        let path = dir.0.join("test.mbtiles");
+
+        let mbtiles = MbTiles::create(&path, &metadata(true)).unwrap();
+        let connection = mbtiles.connection.lock();
+
+        assert_eq!(metadata_value(&connection, "bounds"), None);
+        assert_eq!(
+            metadata_value(&connection, "bounds_blocks").as_deref(),
+            Some("-512,-256,255,511")
+        );
+        assert_eq!(
+            metadata_value(&connection, "type").as_deref(),
+            Some("overlay")
+        );
+        assert_eq!(
+            metadata_value(&connection, "minzoom").as_deref(),
+            Some("17")
+        );
+        drop(connection);
+
+        let mbtiles = MbTiles::create(&path, &metadata(false)).unwrap();
+        assert_eq!(
+            metadata_value(&mbtiles.connection.lock(), "type").as_deref(),
+            Some("baselayer")
+        );
+    }
+}
@@ -0,0 +1,236 @@
+//! The in memory part of a [TileCache](super::tilecache::TileCache).
+
+use std::{
+    hash::{BuildHasher, Hash, RandomState},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use actix_web::web::Bytes;
+use lru::LruCache;
+use parking_lot::Mutex;
+
+struct Shard<K> {
+    tiles: LruCache<K, Bytes>,
+    bytes: usize,
+}
+
+/// A sharded lru cache of encoded tiles keyed by K, limited by their total
+/// size.
+///
+/// Every shard is an lru with its own lock and an equal part of the budget, so
+/// requests for tiles in different shards don't contend and inserting evicts
+/// only as many tiles of one shard as needed.
+pub struct MemCache<K> {
+    shards: Box<[Mutex<Shard<K>>]>,
+    hasher: RandomState,
+    max_shard_bytes: usize,
+    usage: AtomicUsize,
+}
+
+impl<K> MemCache<K>
+where
+    K: Hash + Eq,
+{
+    /// Creates a cache holding at most max_bytes, split between shards.
+    pub fn new(max_bytes: usize, shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1))
+                .map(|_| {
+                    Mutex::new(Shard {
+                        tiles: LruCache::unbounded(),
+                        bytes: 0,
+                    })
+                })
+                .collect(),
+            hasher: RandomState::new(),
+            max_shard_bytes: max_bytes / shards.max(1),
+            usage: AtomicUsize::new(0),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &Mutex<Shard<K>> {
+        &self.shards[self.hasher.hash_one(key) as usize % self.shards.len()]
+    }
+
+    /// Gets the tile at key, marking it as the most recently used one.
+    pub fn get(&self, key: &K) -> Option<Bytes> {
+        self.shard(key).lock().tiles.get(key).cloned()
+    }
+
+    /// Inserts the tile at key as the most recently used one, evicting the
+    /// least recently used tiles of its shard until it fits the budget.
+    ///
+    /// The inserted tile itself is never evicted, even if it is larger than
+    /// the budget of its shard.
+    pub fn insert(&self, key: K, tile: Bytes) {
+        let mut shard = self.shard(&key).lock();
+        let size = tile.len();
+
+        if let Some(old) = shard.tiles.put(key, tile) {
+            shard.bytes -= old.len();
+            self.usage.fetch_sub(old.len(), Ordering::Relaxed);
+        }
+        shard.bytes += size;
+        self.usage.fetch_add(size, Ordering::Relaxed);
+
+        while shard.bytes > self.max_shard_bytes && shard.tiles.len() > 1 {
+            let (_, evicted) = shard.tiles.pop_lru().expect("shard has more than one tile");
+
+            shard.bytes -= evicted.len();
+            self.usage.fetch_sub(evicted.len(), Ordering::Relaxed);
+        }
+    }
+
+    /// Removes the tile at key, if it is cached.
+    pub fn remove(&self, key: &K) {
+        let mut shard = self.shard(key).lock();
+
+        if let Some(old) = shard.tiles.pop(key) {
+            shard.bytes -= old.len();
+            self.usage.fetch_sub(old.len(), Ordering::Relaxed);
+        }
+    }
+
+    /// The total size in bytes of the cached tiles.
+    pub fn usage(&self) -> usize {
+        self.usage.load(Ordering::Relaxed)
+    }
+
+    /// The amount of cached tiles.
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().tiles.len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tileprovider::TilePos;
+
+    fn tile(size: usize) -> Bytes {
+        Bytes::from(vec![0; size])
+    }
+
+    fn pos(x: i32) -> TilePos {
+        TilePos::new(0, x, 0)
+    }
+
+    #[test]
+    fn evicts_least_recently_inserted() {
+        let cache = MemCache::new(30, 1);
+
+        cache.insert(pos(0), tile(10));
+        cache.insert(pos(1), tile(10));
+        cache.insert(pos(2), tile(10));
+        cache.insert(pos(3), tile(10));
+
+        assert!(cache.get(&pos(0)).is_none());
+        assert!(cache.get(&pos(1)).is_some());
+        assert!(cache.get(&pos(2)).is_some());
+        assert!(cache.get(&pos(3)).is_some());
+        assert_eq!(cache.usage(), 30);
+    }
+
+    #[test]
+    fn get_marks_as_recently_used() {
+        let cache = MemCache::new(30, 1);
+
+        cache.insert(pos(0), tile(10));
+        cache.insert(pos(1), tile(10));
+        cache.insert(pos(2), tile(10));
+        cache.get(&pos(0));
+        cache.insert(pos(3), tile(10));
+
+        assert!(cache.get(&pos(0)).is_some());
+        assert!(cache.get(&pos(1)).is_none());
+        assert!(cache.get(&pos(2)).is_some());
+        assert!(cache.get(&pos(3)).is_some());
+    }
+
+    #[test]
+    fn evicts_one_tile_at_a_time() {
+        let cache = MemCache::new(30, 1);
+
+        cache.insert(pos(0), tile(10));
+        cache.insert(pos(1), tile(10));
+        cache.insert(pos(2), tile(10));
+        cache.insert(pos(3), tile(5));
+
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.usage(), 25);
+    }
+
+    #[test]
+    fn evicts_until_large_tile_fits() {
+        let cache = MemCache::new(30, 1);
+
+        cache.insert(pos(0), tile(10));
+        cache.insert(pos(1), tile(10));
+        cache.insert(pos(2), tile(10));
+        cache.insert(pos(3), tile(25));
+
+        assert!(cache.get(&pos(0)).is_none());
+        assert!(cache.get(&pos(1)).is_none());
+        assert!(cache.get(&pos(2)).is_none());
+        assert!(cache.get(&pos(3)).is_some());
+        assert_eq!(cache.usage(), 25);
+    }
+
+    #[test]
+    fn keeps_tile_larger_than_budget() {
+        let cache = MemCache::new(30, 1);
+
+        cache.insert(pos(0), tile(10));
+        cache.insert(pos(1), tile(50));
+
+        assert!(cache.get(&pos(0)).is_none());
+        assert!(cache.get(&pos(1)).is_some());
+        assert_eq!(cache.usage(), 50);
+    }
+
+    #[test]
+    fn replacing_tile_updates_usage() {
+        let cache = MemCache::new(30, 1);
+
+        cache.insert(pos(0), tile(10));
+        cache.insert(pos(0), tile(20));
+
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.usage(), 20);
+    }
+
+    #[test]
+    fn remove_updates_usage() {
+        let cache = MemCache::new(30, 1);
+
+        cache.insert(pos(0), tile(10));
+        cache.insert(pos(1), tile(10));
+        cache.remove(&pos(0));
+        cache.remove(&pos(2));
+
+        assert!(cache.get(&pos(0)).is_none());
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.usage(), 10);
+    }
+
+    #[test]
+    fn shards_share_the_budget() {
+        let cache = MemCache::new(100, 4);
+
+        for x in 0..100 {
+            cache.insert(pos(x), tile(10));
+        }
+
+        // Every shard keeps at most 2 tiles of its 25 byte budget.
+        assert!(cache.len() <= 8);
+        assert!(cache.usage() <= 100);
+        assert_eq!(cache.usage(), cache.len() * 10);
+    }
+}
@@ -1,31 +1,266 @@
-use image::{DynamicImage, GrayImage, Luma};
+use std::fmt::Display;
 
+use cubiomes::generator::error::GeneratorError;
+use image::{DynamicImage, GenericImage, GrayImage, ImageFormat, Luma, imageops::FilterType};
+
+pub mod composite;
+pub mod diskindex;
+pub mod kvstore;
+pub mod mbtiles;
+pub mod memcache;
+pub mod pmtiles;
 pub mod tilecache;
+pub mod tilestore;
+
+/// The size in pixels of a tile at scale 1.
+pub const TILE_SIZE: u32 = 256;
 
 #[derive(Hash, PartialEq, Eq, Clone, Copy, Debug)]
 pub struct TilePos {
     pub zoom: i32,
     pub x: i32,
     pub y: i32,
+    /// Pixel ratio of the tile, eg. 2 for `@2x` (512x512) tiles. Always a
+    /// power of two.
+    pub scale: u32,
 }
 
+/// Blocks from the center of the world to the world border.
+pub const WORLD_BORDER: i32 = 30_000_000;
+
+/// A tile encoded in a specific format, as stored by the tile caches.
+pub type TileKey = (TilePos, ImageFormat);
+
 impl TilePos {
     pub fn new(zoom: i32, x: i32, y: i32) -> Self {
-        Self { zoom, x, y }
+        Self {
+            zoom,
+            x,
+            y,
+            scale: 1,
+        }
+    }
+
+    pub fn with_scale(self, scale: u32) -> Self {
+        debug_assert!(scale.is_power_of_two());
+
+        Self { scale, ..self }
+    }
+
+    /// The width and height of the tile in pixels.
+    pub fn size(&self) -> u32 {
+        TILE_SIZE * self.scale
     }
+
+    /// Whether the whole tile is beyond the [WORLD_BORDER].
+    pub fn is_outside_world(&self) -> bool {
+        let blocks_per_tile = TILE_SIZE as f64 * 2_f64.powi(-self.zoom);
+        let border = WORLD_BORDER as f64;
+        let outside = |tile: i32| {
+            let min = tile as f64 * blocks_per_tile;
+            min >= border || min + blocks_per_tile <= -border
+        };
+
+        outside(self.x) || outside(self.y)
+    }
+}
+
+/// Why a [TileProvider] has no tile at a position.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProviderError {
+    /// The layer isn't rendered at the zoom level, eg. structures when zoomed
+    /// far out.
+    ZoomOutOfRange(i32),
+    /// The tile is beyond the world border.
+    OutOfBounds(TilePos),
+    /// The provider only serves tiles which are already stored (see
+    /// [NoTiles]).
+    NoTile,
+    /// Cubiomes failed to generate the biomes of the tile.
+    GeneratorError(GeneratorError),
+    /// Cubiomes failed to approximate the surface height of the tile.
+    SurfaceError,
+    /// Reading or rendering a layer of a composite tile failed, the cause is
+    /// logged.
+    LayerError,
 }
 
-/// The image should be 256x256
+impl ProviderError {
+    /// Whether the tile doesn't exist, as opposed to failing to render.
+    pub fn is_missing(&self) -> bool {
+        matches!(
+            self,
+            ProviderError::ZoomOutOfRange(_)
+                | ProviderError::OutOfBounds(_)
+                | ProviderError::NoTile
+        )
+    }
+}
+
+impl Display for ProviderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProviderError::ZoomOutOfRange(zoom) => {
+                write!(f, "the layer is not rendered at zoom level {zoom}")
+            }
+            ProviderError::OutOfBounds(pos) => write!(
+                f,
+                "tile {}/{}/{} is beyond the world border",
+                pos.zoom, pos.x, pos.y
+            ),
+            ProviderError::NoTile => write!(f, "the layer has no tile at this position"),
+            ProviderError::GeneratorError(_) => write!(f, "generating the biomes failed"),
+            ProviderError::SurfaceError => {
+                write!(f, "approximating the surface height failed")
+            }
+            ProviderError::LayerError => write!(f, "a layer of the tile failed"),
+        }
+    }
+}
+
+impl std::error::Error for ProviderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProviderError::GeneratorError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<GeneratorError> for ProviderError {
+    fn from(value: GeneratorError) -> Self {
+        Self::GeneratorError(value)
+    }
+}
+
+/// An empty directory for the files of a test, removed again when dropped.
+#[cfg(test)]
+pub(crate) struct TestDir(pub std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("biomemap-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        Self(dir)
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Black tiles, counting how many were rendered.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct Counting(std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+#[cfg(test)]
+impl TileProvider for Counting {
+    fn get_tile(&self, pos: TilePos) -> Result<DynamicImage, ProviderError> {
+        self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        Blacktile.get_tile(pos)
+    }
+}
+
+#[cfg(test)]
+impl Counting {
+    pub fn renders(&self) -> usize {
+        self.0.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+/// The image should be [TilePos::size] pixels wide and high.
 pub trait TileProvider {
-    fn get_tile(&self, pos: TilePos) -> Option<DynamicImage>;
+    fn get_tile(&self, pos: TilePos) -> Result<DynamicImage, ProviderError>;
+}
+
+/// Renders a tile with a scale above 1 by stitching together the scale 1 tiles
+/// of the higher zoom level covering the same area, so it has their (native)
+/// resolution instead of being upscaled.
+///
+/// Parts are rendered at max_zoom at most, so scaled tiles near it are
+/// upscaled from the tiles of max_zoom instead. render is called with scale 1
+/// positions only.
+pub fn render_scaled<F>(
+    pos: TilePos,
+    max_zoom: i32,
+    render: F,
+) -> Result<DynamicImage, ProviderError>
+where
+    F: Fn(TilePos) -> Result<DynamicImage, ProviderError>,
+{
+    if pos.scale == 1 {
+        return render(pos);
+    }
+
+    let levels = pos
+        .scale
+        .ilog2()
+        .min(max_zoom.saturating_sub(pos.zoom).max(0) as u32);
+    let parts = 1 << levels;
+    let zoom = pos.zoom + levels as i32;
+    let mut tile = None;
+
+    for dx in 0..parts {
+        for dy in 0..parts {
+            let part = render(TilePos::new(zoom, pos.x * parts + dx, pos.y * parts + dy))?;
+            let tile = tile.get_or_insert_with(|| {
+                DynamicImage::new(
+                    parts as u32 * TILE_SIZE,
+                    parts as u32 * TILE_SIZE,
+                    part.color(),
+                )
+            });
+
+            tile.copy_from(&part, dx as u32 * TILE_SIZE, dy as u32 * TILE_SIZE)
+                .expect("parts are a quarter of the tile or smaller");
+        }
+    }
+
+    let tile = tile.expect("scaled tiles have at least one part");
+
+    if tile.width() == pos.size() {
+        Ok(tile)
+    } else {
+        Ok(tile.resize_exact(pos.size(), pos.size(), FilterType::Nearest))
+    }
+}
+
+/// Metadata stored in the MBTiles and PMTiles archives tiles are exported to.
+#[derive(Debug, Clone)]
+pub struct ArchiveMetadata {
+    pub name: String,
+    pub format: ImageFormat,
+    /// The exported area in block coordinates, as min_x, min_z, max_x, max_z.
+    pub bounds: [i32; 4],
+    /// Whether the layer is transparent, to be drawn on top of other layers.
+    pub overlay: bool,
+    pub min_zoom: i32,
+    pub max_zoom: i32,
+}
+
+/// A provider without any tiles, for caches which only serve the tiles already
+/// stored in an archive.
+pub struct NoTiles;
+
+impl TileProvider for NoTiles {
+    fn get_tile(&self, _pos: TilePos) -> Result<DynamicImage, ProviderError> {
+        Err(ProviderError::NoTile)
+    }
 }
 
 #[derive(Default)]
 pub struct Blacktile;
 
 impl TileProvider for Blacktile {
-    fn get_tile(&self, _: TilePos) -> Option<DynamicImage> {
-        Some(GrayImage::from_pixel(256, 256, Luma::from([0])).into())
+    fn get_tile(&self, pos: TilePos) -> Result<DynamicImage, ProviderError> {
+        Ok(GrayImage::from_pixel(pos.size(), pos.size(), Luma::from([0])).into())
     }
 }
 
@@ -34,3 +269,47 @@ impl Blacktile {
         Self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    /// Renders pos with render_scaled, returning the size of the tile and the
+    /// parts it was made of.
+    fn render(pos: TilePos, max_zoom: i32) -> (u32, Vec<TilePos>) {
+        let parts = RefCell::new(Vec::new());
+        let tile = render_scaled(pos, max_zoom, |part| {
+            parts.borrow_mut().push(part);
+            Ok(GrayImage::new(TILE_SIZE, TILE_SIZE).into())
+        })
+        .unwrap();
+
+        assert_eq!(tile.width(), tile.height());
+        (tile.width(), parts.into_inner())
+    }
+
+    #[test]
+    fn scaled_tiles_are_made_of_the_next_zoom_level() {
+        let (size, parts) = render(TilePos::new(0, 1, -1).with_scale(2), 8);
+
+        assert_eq!(size, 512);
+        assert_eq!(
+            parts,
+            [(2, -2), (2, -1), (3, -2), (3, -1)].map(|(x, y)| TilePos::new(1, x, y))
+        );
+    }
+
+    #[test]
+    fn scaled_tiles_stop_at_the_max_zoom() {
+        let (size, parts) = render(TilePos::new(7, 0, 0).with_scale(4), 8);
+        assert_eq!(size, 1024);
+        assert_eq!(parts.len(), 4);
+        assert!(parts.iter().all(|part| part.zoom == 8));
+
+        let (size, parts) = render(TilePos::new(8, 3, 5).with_scale(2), 8);
+        assert_eq!(size, 512);
+        assert_eq!(parts, [TilePos::new(8, 3, 5)]);
+    }
+}
@@ -0,0 +1,823 @@
+//! Reading and writing [PMTiles v3](https://github.com/protomaps/PMTiles/blob/main/spec/v3/spec.md)
+//! archives, single files holding the tiles of a layer which can be hosted on
+//! static storage.
+//!
+//! PMTiles tiles form a quadtree with a single tile at zoom 0, so the minecraft
+//! world is centered in it: tiles are stored at `zoom + ZOOM_OFFSET`, where the
+//! single tile covers every block a world can have, and their x and y are
+//! shifted by half the tiles of that zoom level.
+
+use std::{
+    fs::{File, remove_file},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use image::ImageFormat;
+use parking_lot::Mutex;
+use serde_json::json;
+
+use super::{
+    ArchiveMetadata, TileKey, TilePos,
+    tilestore::{StoredTile, TileStore},
+};
+use crate::biomemap::MAX_ZOOM;
+
+/// Added to zoom levels to get the zoom level of a tile in the archive.
+///
+/// A tile at zoom -18 is 2^26 blocks wide, which covers the ±30 million
+/// blocks of a world.
+pub const ZOOM_OFFSET: i32 = 18;
+
+const HEADER_LEN: usize = 127;
+
+/// The header and root directory have to fit in the first 16 KiB, so clients
+/// can fetch both with a single request.
+const MAX_ROOT_LEN: usize = 16384 - HEADER_LEN;
+
+/// Directories nested deeper than this are rejected as invalid.
+const MAX_DEPTH: usize = 4;
+
+const COMPRESSION_NONE: u8 = 1;
+const COMPRESSION_GZIP: u8 = 2;
+
+/// An entry of a directory, pointing at either a tile or a leaf directory.
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    tile_id: u64,
+    /// Offset into the tile data, or into the leaf directories if run_length
+    /// is 0.
+    offset: u64,
+    length: u32,
+    /// Amount of consecutive tile ids with the same tile data.
+    run_length: u32,
+}
+
+/// The position of pos in the archive, as its tile id.
+///
+/// None if pos is outside the world, or at a zoom level archives can't hold.
+fn tile_id(pos: TilePos) -> Option<u64> {
+    let zoom = pos
+        .zoom
+        .checked_add(ZOOM_OFFSET)
+        .filter(|zoom| (0..=MAX_ZOOM + ZOOM_OFFSET).contains(zoom))? as u32;
+    let half = (1_i64 << zoom) / 2;
+    let side = 1_u64 << zoom;
+
+    let x = u64::try_from(pos.x as i64 + half)
+        .ok()
+        .filter(|&x| x < side)?;
+    let y = u64::try_from(pos.y as i64 + half)
+        .ok()
+        .filter(|&y| y < side)?;
+
+    // Ids of every tile of the lower zoom levels come first.
+    let base = ((1_u64 << (2 * zoom)) - 1) / 3;
+
+    Some(base + hilbert_index(side, x, y))
+}
+
+/// The index of x, y on the hilbert curve filling a square with side length
+/// side.
+fn hilbert_index(side: u64, mut x: u64, mut y: u64) -> u64 {
+    let mut index = 0;
+    let mut s = side / 2;
+
+    while s > 0 {
+        let rx = (x & s > 0) as u64;
+        let ry = (y & s > 0) as u64;
+
+        index += s * s * ((3 * rx) ^ ry);
+
+        // Rotate the quadrant, so the curve continues where the last one ended.
+        if ry == 0 {
+            if rx == 1 {
+                x = side - 1 - x;
+                y = side - 1 - y;
+            }
+
+            std::mem::swap(&mut x, &mut y);
+        }
+
+        s /= 2;
+    }
+
+    index
+}
+
+/// The inverse of [tile_id].
+fn tile_pos(tile_id: u64) -> Option<TilePos> {
+    let mut zoom = 0;
+    let mut base = 0;
+
+    // Zoom levels above 31 can't be represented in the archive anyway.
+    while zoom < 32 {
+        let count = 1_u64 << (2 * zoom);
+
+        if tile_id < base + count {
+            break;
+        }
+
+        base += count;
+        zoom += 1;
+    }
+
+    let side = 1_u64 << zoom;
+    let half = side as i64 / 2;
+    let (x, y) = hilbert_position(side, tile_id.checked_sub(base)?);
+
+    Some(TilePos::new(
+        zoom - ZOOM_OFFSET,
+        i32::try_from(x as i64 - half).ok()?,
+        i32::try_from(y as i64 - half).ok()?,
+    ))
+}
+
+/// The inverse of [hilbert_index].
+fn hilbert_position(side: u64, index: u64) -> (u64, u64) {
+    let (mut x, mut y) = (0, 0);
+    let mut t = index;
+    let mut s = 1;
+
+    while s < side {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+
+            std::mem::swap(&mut x, &mut y);
+        }
+
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+
+    (x, y)
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+
+    buf.push(value as u8);
+}
+
+fn read_varint(buf: &mut &[u8]) -> io::Result<u64> {
+    let mut value = 0;
+
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf
+            .split_first()
+            .ok_or_else(|| invalid("truncated varint"))?;
+        *buf = rest;
+
+        value |= ((byte & 0x7f) as u64) << shift;
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(invalid("varint is too long"))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid pmtiles: {message}"),
+    )
+}
+
+/// Serializes and compresses a directory.
+fn serialize_directory(entries: &[Entry]) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    write_varint(&mut buf, entries.len() as u64);
+
+    let mut last_id = 0;
+    for entry in entries {
+        write_varint(&mut buf, entry.tile_id - last_id);
+        last_id = entry.tile_id;
+    }
+
+    for entry in entries {
+        write_varint(&mut buf, entry.run_length as u64);
+    }
+
+    for entry in entries {
+        write_varint(&mut buf, entry.length as u64);
+    }
+
+    for (i, entry) in entries.iter().enumerate() {
+        // Offsets directly after the previous entry are stored as 0.
+        if i > 0 && entry.offset == entries[i - 1].offset + entries[i - 1].length as u64 {
+            write_varint(&mut buf, 0);
+        } else {
+            write_varint(&mut buf, entry.offset + 1);
+        }
+    }
+
+    compress(&buf)
+}
+
+/// Decompresses and deserializes a directory.
+fn deserialize_directory(data: &[u8], compression: u8) -> io::Result<Vec<Entry>> {
+    let data = decompress(data, compression)?;
+    let mut buf = data.as_slice();
+
+    let len = read_varint(&mut buf)? as usize;
+    let mut entries = vec![
+        Entry {
+            tile_id: 0,
+            offset: 0,
+            length: 0,
+            run_length: 0,
+        };
+        len.min(buf.len())
+    ];
+
+    if entries.len() != len {
+        return Err(invalid("directory is truncated"));
+    }
+
+    let mut last_id = 0;
+    for entry in &mut entries {
+        last_id += read_varint(&mut buf)?;
+        entry.tile_id = last_id;
+    }
+
+    for entry in &mut entries {
+        entry.run_length = read_varint(&mut buf)? as u32;
+    }
+
+    for entry in &mut entries {
+        entry.length = read_varint(&mut buf)? as u32;
+    }
+
+    for i in 0..entries.len() {
+        entries[i].offset = match read_varint(&mut buf)? {
+            0 if i > 0 => entries[i - 1].offset + entries[i - 1].length as u64,
+            0 => return Err(invalid("first entry has no offset")),
+            offset => offset - 1,
+        };
+    }
+
+    Ok(entries)
+}
+
+fn compress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+fn decompress(data: &[u8], compression: u8) -> io::Result<Vec<u8>> {
+    match compression {
+        COMPRESSION_NONE => Ok(data.to_vec()),
+        COMPRESSION_GZIP => {
+            let mut buf = Vec::new();
+            GzDecoder::new(data).read_to_end(&mut buf)?;
+            Ok(buf)
+        }
+        _ => Err(invalid("unsupported compression")),
+    }
+}
+
+fn tile_type(format: ImageFormat) -> u8 {
+    match format {
+        ImageFormat::Png => 2,
+        ImageFormat::Jpeg => 3,
+        ImageFormat::WebP => 4,
+        _ => 0,
+    }
+}
+
+/// Writes a PMTiles archive.
+///
+/// Tiles can be added in any order. They are written to a temporary file
+/// first, since the directories (which come before the tile data) can only be
+/// built once every tile is known.
+pub struct PmTilesWriter {
+    path: PathBuf,
+    data: BufWriter<File>,
+    // Declared after data, so the file is closed before it is removed.
+    temp_file: TempFile,
+    data_len: u64,
+    entries: Vec<Entry>,
+}
+
+/// A file which is removed once dropped, so it doesn't outlive a failed export.
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.0);
+    }
+}
+
+impl PmTilesWriter {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".data.tmp");
+        let temp_path = PathBuf::from(temp_path);
+
+        Ok(Self {
+            path: path.to_owned(),
+            data: BufWriter::new(File::create(&temp_path)?),
+            temp_file: TempFile(temp_path),
+            data_len: 0,
+            entries: Vec::new(),
+        })
+    }
+
+    /// Adds the encoded tile at pos, which must have a scale of 1.
+    pub fn add(&mut self, pos: TilePos, tile: &[u8]) -> io::Result<()> {
+        debug_assert_eq!(pos.scale, 1, "archives only hold tiles with a scale of 1");
+
+        let tile_id = tile_id(pos).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("tile {pos:?} is outside the world"),
+            )
+        })?;
+
+        self.data.write_all(tile)?;
+        self.entries.push(Entry {
+            tile_id,
+            offset: self.data_len,
+            length: tile.len() as u32,
+            run_length: 1,
+        });
+        self.data_len += tile.len() as u64;
+
+        Ok(())
+    }
+
+    /// Writes the archive with metadata. The temporary file is removed either
+    /// way.
+    pub fn finish(mut self, metadata: &ArchiveMetadata) -> io::Result<()> {
+        self.data.flush()?;
+        self.entries.sort_unstable_by_key(|entry| entry.tile_id);
+        self.entries.dedup_by_key(|entry| entry.tile_id);
+
+        let (root, leaves) = build_directories(&self.entries)?;
+        let json = compress(
+            json!({
+                "name": metadata.name,
+                "format": metadata.format.extensions_str()[0],
+                "type": if metadata.overlay { "overlay" } else { "baselayer" },
+                "bounds_blocks": metadata.bounds,
+                "zoom_offset": ZOOM_OFFSET,
+            })
+            .to_string()
+            .as_bytes(),
+        )?;
+
+        let min_zoom = (metadata.min_zoom + ZOOM_OFFSET) as u8;
+        let max_zoom = (metadata.max_zoom + ZOOM_OFFSET) as u8;
+
+        let root_offset = HEADER_LEN as u64;
+        let json_offset = root_offset + root.len() as u64;
+        let leaves_offset = json_offset + json.len() as u64;
+        let data_offset = leaves_offset + leaves.len() as u64;
+
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(b"PMTiles");
+        header.push(3);
+        for value in [
+            root_offset,
+            root.len() as u64,
+            json_offset,
+            json.len() as u64,
+            leaves_offset,
+            leaves.len() as u64,
+            data_offset,
+            self.data_len,
+            // Addressed tiles, tile entries and tile contents.
+            self.entries.len() as u64,
+            self.entries.len() as u64,
+            self.entries.len() as u64,
+        ] {
+            header.extend_from_slice(&value.to_le_bytes());
+        }
+        // Not clustered, since the tile data is in the order tiles were added
+        // in instead of ordered by tile id.
+        header.push(0);
+        header.push(COMPRESSION_GZIP);
+        header.push(COMPRESSION_NONE);
+        header.push(tile_type(metadata.format));
+        header.push(min_zoom);
+        header.push(max_zoom);
+        // The world isn't on the globe, so its bounds are the whole mercator
+        // square.
+        for value in [-180_0000000_i32, -85_0511287, 180_0000000, 85_0511287] {
+            header.extend_from_slice(&value.to_le_bytes());
+        }
+        header.push(min_zoom);
+        header.extend_from_slice(&0_i32.to_le_bytes());
+        header.extend_from_slice(&0_i32.to_le_bytes());
+        debug_assert_eq!(header.len(), HEADER_LEN);
+
+        let mut output = BufWriter::new(File::create(&self.path)?);
+        output.write_all(&header)?;
+        output.write_all(&root)?;
+        output.write_all(&json)?;
+        output.write_all(&leaves)?;
+
+        let mut data = File::open(&self.temp_file.0)?;
+        io::copy(&mut data, &mut output)?;
+        output.flush()
+    }
+}
+
+/// Builds the root directory and leaf directories of entries, which must be
+/// sorted by tile id.
+///
+/// Leaf directories are only used if the root directory wouldn't fit otherwise.
+fn build_directories(entries: &[Entry]) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let root = serialize_directory(entries)?;
+
+    if root.len() <= MAX_ROOT_LEN {
+        return Ok((root, Vec::new()));
+    }
+
+    let mut leaf_size = 4096;
+
+    loop {
+        let mut leaves = Vec::new();
+        let mut root_entries = Vec::new();
+
+        for chunk in entries.chunks(leaf_size) {
+            let leaf = serialize_directory(chunk)?;
+
+            root_entries.push(Entry {
+                tile_id: chunk[0].tile_id,
+                offset: leaves.len() as u64,
+                length: leaf.len() as u32,
+                run_length: 0,
+            });
+            leaves.extend_from_slice(&leaf);
+        }
+
+        let root = serialize_directory(&root_entries)?;
+
+        if root.len() <= MAX_ROOT_LEN {
+            return Ok((root, leaves));
+        }
+
+        leaf_size *= 2;
+    }
+}
+
+/// An open PMTiles archive.
+pub struct PmTiles {
+    file: Mutex<File>,
+    root: Vec<Entry>,
+    leaves_offset: u64,
+    data_offset: u64,
+    compression: u8,
+    format: ImageFormat,
+}
+
+impl PmTiles {
+    /// Opens the archive at path, reading its header and root directory.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+
+        let mut header = [0; HEADER_LEN];
+        file.read_exact(&mut header)?;
+
+        if &header[..7] != b"PMTiles" || header[7] != 3 {
+            return Err(invalid("not a version 3 archive"));
+        }
+
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(header[offset..offset + 8].try_into().unwrap());
+
+        let compression = header[97];
+
+        if header[98] != COMPRESSION_NONE {
+            return Err(invalid("compressed tiles are not supported"));
+        }
+
+        let format = match header[99] {
+            2 => ImageFormat::Png,
+            3 => ImageFormat::Jpeg,
+            4 => ImageFormat::WebP,
+            _ => return Err(invalid("only png, jpg and webp tiles are supported")),
+        };
+
+        let root = read_at(&mut file, u64_at(8), u64_at(16))?;
+
+        Ok(Self {
+            root: deserialize_directory(&root, compression)?,
+            file: Mutex::new(file),
+            leaves_offset: u64_at(40),
+            data_offset: u64_at(56),
+            compression,
+            format,
+        })
+    }
+
+    /// The format of every tile in the archive.
+    pub fn format(&self) -> ImageFormat {
+        self.format
+    }
+
+    /// Reads the encoded tile at pos, if the archive has it.
+    ///
+    /// Archives only hold tiles with a scale of 1.
+    pub fn get_tile(&self, pos: TilePos) -> io::Result<Option<Vec<u8>>> {
+        let Some(tile_id) = tile_id(pos).filter(|_| pos.scale == 1) else {
+            return Ok(None);
+        };
+
+        let mut leaf;
+        let mut directory = &self.root;
+
+        for _ in 0..MAX_DEPTH {
+            let Some(entry) = find_entry(directory, tile_id) else {
+                return Ok(None);
+            };
+
+            if entry.run_length > 0 {
+                let mut file = self.file.lock();
+
+                return read_at(
+                    &mut file,
+                    self.data_offset + entry.offset,
+                    entry.length as u64,
+                )
+                .map(Some);
+            }
+
+            let data = read_at(
+                &mut self.file.lock(),
+                self.leaves_offset + entry.offset,
+                entry.length as u64,
+            )?;
+            leaf = deserialize_directory(&data, self.compression)?;
+            directory = &leaf;
+        }
+
+        Err(invalid("directories are nested too deep"))
+    }
+
+    /// Finds the tiles in directory and the leaf directories it points to.
+    fn list_directory(
+        &self,
+        directory: &[Entry],
+        depth: usize,
+        found: &mut Vec<StoredTile>,
+    ) -> io::Result<()> {
+        if depth >= MAX_DEPTH {
+            return Err(invalid("directories are nested too deep"));
+        }
+
+        for entry in directory {
+            if entry.run_length == 0 {
+                let data = read_at(
+                    &mut self.file.lock(),
+                    self.leaves_offset + entry.offset,
+                    entry.length as u64,
+                )?;
+                let leaf = deserialize_directory(&data, self.compression)?;
+                self.list_directory(&leaf, depth + 1, found)?;
+
+                continue;
+            }
+
+            for tile_id in entry.tile_id..entry.tile_id + entry.run_length as u64 {
+                let pos = tile_pos(tile_id).ok_or_else(|| invalid("tile id is out of range"))?;
+
+                found.push(StoredTile {
+                    key: (pos, self.format),
+                    size: entry.length as u64,
+                    modified: None,
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl TileStore for PmTiles {
+    fn get(&self, (pos, format): TileKey) -> io::Result<Option<Vec<u8>>> {
+        if format != self.format {
+            return Ok(None);
+        }
+
+        self.get_tile(pos)
+    }
+
+    fn put(&self, _key: TileKey, _tile: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "pmtiles archives are read only",
+        ))
+    }
+
+    fn delete(&self, _key: TileKey) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "pmtiles archives are read only",
+        ))
+    }
+
+    fn list(&self) -> io::Result<Vec<StoredTile>> {
+        let mut found = Vec::new();
+        self.list_directory(&self.root, 0, &mut found)?;
+
+        Ok(found)
+    }
+}
+
+/// The entry of directory containing tile_id, which is either the tile or the
+/// leaf directory it is in.
+fn find_entry(directory: &[Entry], tile_id: u64) -> Option<Entry> {
+    let index = directory
+        .partition_point(|entry| entry.tile_id <= tile_id)
+        .checked_sub(1)?;
+    let entry = directory[index];
+
+    // Leaf directories contain every tile up to the next entry.
+    if entry.run_length == 0 || tile_id < entry.tile_id + entry.run_length as u64 {
+        Some(entry)
+    } else {
+        None
+    }
+}
+
+fn read_at(file: &mut File, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; len as usize];
+
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buf)?;
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tileprovider::TestDir;
+
+    #[test]
+    fn varints_round_trip() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, value);
+
+            assert_eq!(read_varint(&mut buf.as_slice()).unwrap(), value);
+        }
+
+        let mut buf = Vec::new();
+        write_varint(&mut buf, 300);
+        assert_eq!(buf, [0xac, 0x02]);
+
+        assert!(read_varint(&mut [0x80].as_slice()).is_err());
+    }
+
+    #[test]
+    fn tile_ids_follow_the_hilbert_curve() {
+        let pos = |zoom, x, y| TilePos::new(zoom - ZOOM_OFFSET, x, y);
+
+        assert_eq!(tile_id(pos(0, 0, 0)), Some(0));
+        // Zoom 1 is centered on the world, so its tiles are at -1 and 0.
+        assert_eq!(tile_id(pos(1, -1, -1)), Some(1));
+        assert_eq!(tile_id(pos(1, -1, 0)), Some(2));
+        assert_eq!(tile_id(pos(1, 0, 0)), Some(3));
+        assert_eq!(tile_id(pos(1, 0, -1)), Some(4));
+        assert_eq!(tile_id(pos(2, -2, -2)), Some(5));
+
+        for pos in [
+            pos(1, 0, -1),
+            TilePos::new(0, 5, -7),
+            TilePos::new(MAX_ZOOM, 123, 456),
+        ] {
+            assert_eq!(tile_pos(tile_id(pos).unwrap()), Some(pos));
+        }
+    }
+
+    #[test]
+    fn tile_ids_reject_unsupported_positions() {
+        assert_eq!(tile_id(TilePos::new(-ZOOM_OFFSET - 1, 0, 0)), None);
+        assert_eq!(tile_id(TilePos::new(MAX_ZOOM + 1, 0, 0)), None);
+        assert_eq!(tile_id(TilePos::new(15, 0, 0)), None);
+        assert_eq!(tile_id(TilePos::new(i32::MAX, 0, 0)), None);
+        assert_eq!(tile_id(TilePos::new(-ZOOM_OFFSET, 1, 0)), None);
+    }
+
+    #[test]
+    fn written_archives_can_be_read() {
+        let dir = TestDir::new("pmtiles");
+        let path = dir.0.join("map.pmtiles");
+        let tiles: Vec<_> = (-40..40)
+            .map(|x| {
+                (
+                    TilePos::new(0, x, x / 3),
+                    vec![x as u8; 10 + x.unsigned_abs() as usize],
+                )
+            })
+            .collect();
+
+        let mut writer = PmTilesWriter::create(&path).unwrap();
+        for (pos, tile) in tiles.iter().rev() {
+            writer.add(*pos, tile).unwrap();
+        }
+        writer
+            .finish(&ArchiveMetadata {
+                name: "test".into(),
+                format: ImageFormat::Png,
+                bounds: [-40 * 256, -40 * 256, 40 * 256, 40 * 256],
+                overlay: false,
+                min_zoom: 0,
+                max_zoom: 0,
+            })
+            .unwrap();
+
+        let archive = PmTiles::open(&path).unwrap();
+        assert_eq!(archive.format(), ImageFormat::Png);
+
+        for (pos, tile) in &tiles {
+            assert_eq!(archive.get_tile(*pos).unwrap().as_ref(), Some(tile));
+        }
+        assert_eq!(archive.get_tile(TilePos::new(0, 100, 0)).unwrap(), None);
+        assert_eq!(archive.get_tile(TilePos::new(1, 0, 0)).unwrap(), None);
+        assert_eq!(archive.list().unwrap().len(), tiles.len());
+    }
+
+    fn metadata() -> ArchiveMetadata {
+        ArchiveMetadata {
+            name: "test".into(),
+            format: ImageFormat::Png,
+            bounds: [0, 0, 200, 100],
+            overlay: false,
+            min_zoom: MAX_ZOOM,
+            max_zoom: MAX_ZOOM,
+        }
+    }
+
+    #[test]
+    fn large_archives_use_leaf_directories() {
+        let dir = TestDir::new("pmtiles-leaves");
+        let path = dir.0.join("map.pmtiles");
+        let tiles: Vec<_> = (0..20_000)
+            .map(|i| {
+                (
+                    TilePos::new(MAX_ZOOM, i % 200, i / 200),
+                    vec![i as u8; 1 + i as usize % 5],
+                )
+            })
+            .collect();
+
+        // Added out of order, so the offsets of the entries don't compress
+        // well and the root directory would be far over 16 KiB.
+        let mut writer = PmTilesWriter::create(&path).unwrap();
+        for i in 0..tiles.len() {
+            let (pos, tile) = &tiles[i * 7919 % tiles.len()];
+            writer.add(*pos, tile).unwrap();
+        }
+        writer.finish(&metadata()).unwrap();
+
+        let archive = PmTiles::open(&path).unwrap();
+        assert!(archive.root.iter().all(|entry| entry.run_length == 0));
+
+        for (pos, tile) in tiles.iter().step_by(97) {
+            assert_eq!(archive.get_tile(*pos).unwrap().as_ref(), Some(tile));
+        }
+        assert_eq!(
+            archive.get_tile(TilePos::new(MAX_ZOOM, 200, 0)).unwrap(),
+            None
+        );
+        assert_eq!(archive.list().unwrap().len(), tiles.len());
+    }
+
+    #[test]
+    fn temporary_files_are_removed() {
+        let dir = TestDir::new("pmtiles-temp");
+        let path = dir.0.join("map.pmtiles");
+        let temp_path = dir.0.join("map.pmtiles.data.tmp");
+
+        let mut writer = PmTilesWriter::create(&path).unwrap();
+        writer.add(TilePos::new(0, 0, 0), &[1]).unwrap();
+        assert!(temp_path.exists());
+        writer.finish(&metadata()).unwrap();
+        assert!(!temp_path.exists());
+
+        // Abandoned, eg. since rendering a tile failed.
+        let mut writer = PmTilesWriter::create(&path).unwrap();
+        writer.add(TilePos::new(0, 0, 0), &[1]).unwrap();
+        drop(writer);
+        assert!(!temp_path.exists());
+    }
+}
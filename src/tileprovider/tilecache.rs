@@ -1,51 +1,55 @@
-use std::{
-    collections::{BTreeMap, HashMap},
-    fmt::Display,
-    fs::create_dir_all,
-    io::{Cursor, ErrorKind},
-    path::PathBuf,
-    sync::atomic::{AtomicU64, Ordering},
-    time::{SystemTime, UNIX_EPOCH},
-};
+use std::{collections::HashMap, fmt::Display, io::Cursor, sync::Arc};
 
 use actix_web::{ResponseError, http::StatusCode, web::Bytes};
-use image::ImageFormat;
-use parking_lot::RwLock;
+use image::{DynamicImage, ImageError, ImageFormat};
+use log::{debug, warn};
+use parking_lot::Mutex;
 use tokio::{
-    fs::{read, write},
     io,
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::{JoinError, spawn_blocking},
 };
 
-use super::{TilePos, TileProvider};
+use super::{
+    ProviderError, TileKey, TilePos, TileProvider,
+    diskindex::{DiskBudget, DiskIndex},
+    memcache::MemCache,
+    tilestore::TileStore,
+};
 
 #[derive(Debug)]
 pub enum Error {
-    NoTileInProvider,
+    ProviderError(ProviderError),
     WriteError(io::Error),
     ReadError(io::Error),
     CreateDirError(io::Error),
+    RenderError(JoinError),
+    EncodeError(ImageError),
+    DecodeError(ImageError),
+    UnsupportedFormat(ImageFormat),
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Error::NoTileInProvider => {
-                writeln!(
-                    f,
-                    "the source could not provide a tile for the requested position"
-                )
-            }
+            Error::ProviderError(e) => writeln!(f, "{e}"),
             Error::WriteError(_) => writeln!(
                 f,
-                "Error occured while trying to write to the underyling fs"
+                "Error occured while trying to write to the underyling store"
             ),
             Error::ReadError(_) => writeln!(
                 f,
-                "Error occured while trying to read from the underyling fs"
+                "Error occured while trying to read from the underyling store"
             ),
             Error::CreateDirError(_) => {
                 writeln!(f, "failed to create the directory to use with the cache")
             }
+            Error::RenderError(_) => writeln!(f, "rendering the tile panicked"),
+            Error::EncodeError(_) => writeln!(f, "encoding the tile failed"),
+            Error::DecodeError(_) => writeln!(f, "decoding the stored tile failed"),
+            Error::UnsupportedFormat(format) => {
+                writeln!(f, "tiles of this layer can't be served as {format:?}")
+            }
         }
     }
 }
@@ -53,10 +57,14 @@ impl Display for Error {
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
-            Error::NoTileInProvider => None,
+            Error::ProviderError(e) => Some(e),
             Error::WriteError(e) => Some(e),
             Error::ReadError(e) => Some(e),
             Error::CreateDirError(e) => Some(e),
+            Error::RenderError(e) => Some(e),
+            Error::EncodeError(e) => Some(e),
+            Error::DecodeError(e) => Some(e),
+            Error::UnsupportedFormat(_) => None,
         }
     }
 }
@@ -64,191 +72,700 @@ impl std::error::Error for Error {
 impl ResponseError for Error {
     fn status_code(&self) -> actix_web::http::StatusCode {
         match self {
-            Error::NoTileInProvider => StatusCode::NOT_FOUND,
+            Error::ProviderError(ProviderError::ZoomOutOfRange(_)) => StatusCode::BAD_REQUEST,
+            Error::ProviderError(ProviderError::OutOfBounds(_) | ProviderError::NoTile) => {
+                StatusCode::NOT_FOUND
+            }
+            Error::ProviderError(
+                ProviderError::GeneratorError(_)
+                | ProviderError::SurfaceError
+                | ProviderError::LayerError,
+            ) => StatusCode::INTERNAL_SERVER_ERROR,
             Error::WriteError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Error::ReadError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Error::CreateDirError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::RenderError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::EncodeError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::DecodeError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::UnsupportedFormat(_) => StatusCode::NOT_FOUND,
         }
     }
 }
 
-pub(crate) struct CachedTile {
-    last_access: AtomicU64,
-    data: Bytes,
+/// Renders tiles on tokio's blocking threads, so rendering doesn't stall the
+/// async workers serving other requests.
+///
+/// The pool is shared by every [TileCache], limiting how many tiles are
+/// rendered at once in total.
+#[derive(Clone)]
+pub struct RenderPool {
+    permits: Arc<Semaphore>,
+    concurrency: usize,
 }
 
-impl From<Bytes> for CachedTile {
-    fn from(data: Bytes) -> Self {
-        Self::new(data)
-    }
-}
-
-impl CachedTile {
-    pub(crate) fn new(data: Bytes) -> Self {
+impl RenderPool {
+    pub fn new(concurrency: usize) -> Self {
         Self {
-            last_access: AtomicU64::new(
-                SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .expect("System time is set before EPOCH")
-                    .as_secs(),
-            ),
-            data,
+            permits: Arc::new(Semaphore::new(concurrency)),
+            concurrency,
         }
     }
 
-    pub(crate) fn get(&self) -> &Bytes {
-        self.update_access_time();
-        self.get_without_updating_time()
+    /// The amount of tiles rendered at once.
+    pub fn concurrency(&self) -> usize {
+        self.concurrency
     }
 
-    fn update_access_time(&self) {
-        self.last_access.store(
-            SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("Systtem time is set before EPOCH")
-                .as_secs(),
-            Ordering::Relaxed,
-        );
+    /// Runs f on a blocking thread once a permit is available.
+    pub async fn run<F, T>(&self, f: F) -> Result<T, JoinError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.run_weighted(1, f).await
+    }
+
+    /// Runs f on a blocking thread once weight permits are available, for work
+    /// as expensive as rendering weight tiles.
+    pub async fn run_weighted<F, T>(&self, weight: usize, f: F) -> Result<T, JoinError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        Self::run_with(self.permit(weight).await, f).await
+    }
+
+    /// Waits for weight permits of the pool.
+    ///
+    /// The weight is capped at the size of the pool, so heavy work still runs
+    /// once the pool is idle.
+    pub async fn permit(&self, weight: usize) -> OwnedSemaphorePermit {
+        let weight = weight.clamp(1, self.concurrency.max(1)) as u32;
+
+        self.permits
+            .clone()
+            .acquire_many_owned(weight)
+            .await
+            .expect("the semaphore is never closed")
+    }
+
+    /// Runs f on a blocking thread with an already acquired permit.
+    pub async fn run_with<F, T>(permit: OwnedSemaphorePermit, f: F) -> Result<T, JoinError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        // The permit is moved into the task so it is held until rendering is
+        // done, even if the request is cancelled.
+        spawn_blocking(move || {
+            let _permit = permit;
+            f()
+        })
+        .await
     }
+}
 
-    pub(crate) fn get_without_updating_time(&self) -> &Bytes {
-        &self.data
+/// Blocking access to the tiles of a [TileCache], for providers drawing the
+/// tiles of other layers (see
+/// [CompositeTile](super::composite::CompositeTile)).
+pub trait CachedLayer: Send + Sync {
+    /// Gets the tile at pos from the memory cache or the store, rendering and
+    /// storing it on the calling thread if it is in neither.
+    ///
+    /// This should only be called while rendering, which already holds a
+    /// permit of the render pool.
+    fn get_tile_blocking(&self, pos: TilePos) -> Result<DynamicImage, Error>;
+}
+
+/// Encodes tile as format.
+fn encode(mut tile: DynamicImage, format: ImageFormat) -> Result<Vec<u8>, Error> {
+    // Jpeg can't store an alpha channel.
+    if format == ImageFormat::Jpeg {
+        tile = tile.to_rgb8().into();
     }
+
+    let mut buf = Cursor::new(Vec::new());
+    tile.write_to(&mut buf, format)
+        .map_err(Error::EncodeError)?;
+    Ok(buf.into_inner())
 }
 
+/// Removes the tiles evicted from a disk index from store.
+fn remove_evicted(store: &dyn TileStore, evicted: Vec<TileKey>) {
+    for (pos, format) in evicted {
+        debug!("evicting tile {pos:?} as {format:?} from the store");
+
+        if let Err(e) = store.delete((pos, format)) {
+            warn!("failed to evict tile {pos:?} from the store: {e}");
+        }
+    }
+}
+
+/// Permits of the render pool needed to render the tile at pos.
+fn render_weight(pos: TilePos) -> usize {
+    // Scaled tiles are stitched together from scale² tiles.
+    (pos.scale * pos.scale) as usize
+}
+
+/// Claim on rendering a tile, held by whoever renders it.
+type Claim = Arc<tokio::sync::Mutex<()>>;
+
+/// Amount of independently locked shards of the memory cache.
+const MEMCACHE_SHARDS: usize = 16;
+
 pub struct TileCache<Source>
 where
     Source: TileProvider,
 {
-    source: Source,
-    format: ImageFormat,
-    base_path: PathBuf,
-    // The amount of tiles we will hold in the cache.
-    max_capacity: usize,
-    memcache: RwLock<HashMap<TilePos, CachedTile>>,
+    source: Arc<Source>,
+    render_pool: RenderPool,
+    // The formats tiles can be served as, the first one is the default.
+    formats: Vec<ImageFormat>,
+    store: Arc<dyn TileStore>,
+    // None if the disk budget is unlimited, so nothing has to be tracked.
+    disk_index: Option<Mutex<DiskIndex>>,
+    memcache: MemCache<TileKey>,
+    // Tiles currently being rendered, so concurrent requests for the same tile
+    // wait for a single render instead of each doing their own. Claims are
+    // only taken while holding a permit of the render pool, so whoever holds
+    // one never waits for the pool and layers rendering for a composite can
+    // wait for them without deadlocking.
+    in_flight: Mutex<HashMap<TileKey, Claim>>,
 }
 
 impl<S> TileCache<S>
 where
-    S: TileProvider,
+    S: TileProvider + Send + Sync + 'static,
 {
-    pub fn new<T>(
+    /// Creates a cache serving tiles of source in formats, the first of which
+    /// is the default.
+    ///
+    /// Rendered tiles are kept in store, which is trimmed to disk_budget by
+    /// evicting the least recently used tiles.
+    pub fn new(
         source: S,
-        max_capacity: usize,
-        format: ImageFormat,
-        base_path: T,
-    ) -> Result<Self, Error>
-    where
-        T: Into<PathBuf>,
-    {
-        let base_path = base_path.into();
-        create_dir_all(&base_path).map_err(Error::CreateDirError)?;
+        max_memory_bytes: usize,
+        formats: Vec<ImageFormat>,
+        store: Arc<dyn TileStore>,
+        render_pool: RenderPool,
+        disk_budget: DiskBudget,
+    ) -> Result<Self, Error> {
+        assert!(
+            !formats.is_empty(),
+            "a tile cache needs at least one format"
+        );
+
+        let disk_index = if disk_budget.is_unlimited() {
+            None
+        } else {
+            let mut index = DiskIndex::new(disk_budget);
+            let mut tiles = store.list().map_err(Error::ReadError)?;
+            tiles.retain(|tile| formats.contains(&tile.key.1));
+
+            for key in index.load(tiles) {
+                store.delete(key).map_err(Error::WriteError)?;
+            }
+
+            Some(Mutex::new(index))
+        };
 
         Ok(Self {
-            source,
-            format,
-            base_path,
-            max_capacity,
-            memcache: RwLock::new(HashMap::new()),
+            source: Arc::new(source),
+            render_pool,
+            formats,
+            store,
+            disk_index,
+            memcache: MemCache::new(max_memory_bytes, MEMCACHE_SHARDS),
+            in_flight: Mutex::new(HashMap::new()),
         })
     }
 
-    pub async fn get_cached_tile(&self, pos: TilePos) -> Result<Bytes, Error> {
-        let cur_cap;
-        {
-            let memcache = self.memcache.read();
+    /// Gets the tile at pos encoded as format, which must be one of the formats
+    /// of the cache.
+    pub async fn get_cached_tile(&self, pos: TilePos, format: ImageFormat) -> Result<Bytes, Error> {
+        let key = (pos, format);
 
-            if let Some(tile) = memcache.get(&pos) {
-                return Ok(tile.get().clone());
-            }
+        if !self.supports(format) {
+            return Err(Error::UnsupportedFormat(format));
+        }
 
-            cur_cap = memcache.len()
+        if let Some(tile) = self.memcache.get(&key) {
+            self.touch_in_store(key, tile.len()).await;
+            return Ok(tile);
         }
 
-        if cur_cap >= self.max_capacity {
-            self.cleanup().await;
+        let tile: Bytes = self.read_or_gen_tile(pos, format).await?.into();
+        self.memcache.insert(key, tile.clone());
+
+        Ok(tile)
+    }
+
+    /// Renders the tile at pos to the store, unless it is already there.
+    ///
+    /// Unlike [TileCache::get_cached_tile] the tile isn't kept in memory.
+    /// Returns if the tile had to be rendered.
+    pub async fn prerender(&self, pos: TilePos, format: ImageFormat) -> Result<bool, Error> {
+        if !self.supports(format) {
+            return Err(Error::UnsupportedFormat(format));
         }
 
-        let val: Bytes = self.read_or_gen_tile_fs(pos).await?.into();
+        let key = (pos, format);
 
+        if self
+            .with_store(move |store| store.exists(key))
+            .await
+            .map_err(Error::ReadError)?
         {
-            let mut memcache = self.memcache.write();
-            memcache.insert(pos, val.clone().into());
+            return Ok(false);
+        }
+
+        let permit = self.render_pool.permit(render_weight(pos)).await;
+        self.gen_and_store_tile(pos, format, permit).await?;
+
+        Ok(true)
+    }
+
+    /// Gets the tile at pos encoded as format from the store, rendering it if
+    /// it isn't there.
+    ///
+    /// Unlike [TileCache::get_cached_tile] the tile isn't kept in memory.
+    pub async fn get_stored_tile(
+        &self,
+        pos: TilePos,
+        format: ImageFormat,
+    ) -> Result<Vec<u8>, Error> {
+        if !self.supports(format) {
+            return Err(Error::UnsupportedFormat(format));
+        }
+
+        self.read_or_gen_tile(pos, format).await
+    }
 
-            Ok(val)
+    /// Whether the tile at pos is in memory or stored, without rendering it.
+    pub async fn is_cached(&self, pos: TilePos, format: ImageFormat) -> Result<bool, Error> {
+        let key = (pos, format);
+
+        if self.memcache.get(&key).is_some() {
+            return Ok(true);
         }
+
+        self.with_store(move |store| store.exists(key))
+            .await
+            .map_err(Error::ReadError)
     }
 
-    pub fn format(&self) -> &ImageFormat {
-        &self.format
+    /// The provider rendering the tiles of the cache.
+    pub fn source(&self) -> &S {
+        &self.source
     }
 
-    /// Cleans up self (deallocates old data to gain back memory)
-    ///
-    /// Removes 1/3 of the oldest (by last access) memory cache entries.
+    /// The format tiles are served as if the client has no preference.
+    pub fn default_format(&self) -> ImageFormat {
+        self.formats[0]
+    }
+
+    pub fn formats(&self) -> &[ImageFormat] {
+        &self.formats
+    }
+
+    pub fn supports(&self, format: ImageFormat) -> bool {
+        self.formats.contains(&format)
+    }
+
+    /// The total size in bytes of the tiles in the memory cache.
+    pub fn memory_usage(&self) -> usize {
+        self.memcache.usage()
+    }
+
+    async fn generate_tile(
+        &self,
+        pos: TilePos,
+        format: ImageFormat,
+        permit: OwnedSemaphorePermit,
+    ) -> Result<Vec<u8>, Error> {
+        debug!("rendering tile {pos:?} as {format:?}");
+
+        let source = self.source.clone();
+
+        RenderPool::run_with(permit, move || {
+            encode(source.get_tile(pos).map_err(Error::ProviderError)?, format)
+        })
+        .await
+        .map_err(Error::RenderError)?
+    }
+
+    /// Gets the tile at pos from the store, or renders it once even if it is
+    /// requested concurrently.
+    async fn read_or_gen_tile(&self, pos: TilePos, format: ImageFormat) -> Result<Vec<u8>, Error> {
+        let key = (pos, format);
+
+        if let Some(tile) = self.read_stored(key).await? {
+            return Ok(tile);
+        }
+
+        // Taken before the claim, see in_flight.
+        let permit = self.render_pool.permit(render_weight(pos)).await;
+        let claim = self.claim(key);
+
+        let tile = async {
+            let _rendering = claim.lock().await;
+
+            // Another caller may have rendered the tile while we waited.
+            match self.read_stored(key).await? {
+                Some(tile) => Ok(tile),
+                None => self.gen_and_store_tile(pos, format, permit).await,
+            }
+        }
+        .await;
+
+        self.release(key, &claim);
+
+        tile
+    }
+
+    /// Gets the tile at key from the store, marking it as used.
+    async fn read_stored(&self, key: TileKey) -> Result<Option<Vec<u8>>, Error> {
+        let tile = self
+            .with_store(move |store| store.get(key))
+            .await
+            .map_err(Error::ReadError)?;
+
+        if let Some(tile) = &tile {
+            self.touch_in_store(key, tile.len()).await;
+        }
+
+        Ok(tile)
+    }
+
+    /// The claim on rendering the tile at key, shared by everyone rendering it
+    /// at the moment.
+    fn claim(&self, key: TileKey) -> Claim {
+        self.in_flight.lock().entry(key).or_default().clone()
+    }
+
+    /// Forgets claim once nobody else is waiting for it.
+    fn release(&self, key: TileKey, claim: &Claim) {
+        let mut in_flight = self.in_flight.lock();
+
+        if in_flight
+            .get(&key)
+            .is_some_and(|other| Arc::ptr_eq(other, claim))
+            && Arc::strong_count(claim) == 2
+        {
+            in_flight.remove(&key);
+        }
+    }
+
+    async fn gen_and_store_tile(
+        &self,
+        pos: TilePos,
+        format: ImageFormat,
+        permit: OwnedSemaphorePermit,
+    ) -> Result<Vec<u8>, Error> {
+        let key = (pos, format);
+        let img = self.generate_tile(pos, format, permit).await?;
+        let tile = img.clone();
+
+        self.with_store(move |store| store.put(key, &tile))
+            .await
+            .map_err(Error::WriteError)?;
+        self.touch_in_store(key, img.len()).await;
+
+        Ok(img)
+    }
+
+    /// Marks the tile at key as used in the disk index and removes the tiles
+    /// evicted to stay within the disk budget.
+    async fn touch_in_store(&self, key: TileKey, size: usize) {
+        let evicted = self.touch(key, size);
+
+        if !evicted.is_empty() {
+            let _ = self
+                .with_store(move |store| {
+                    remove_evicted(store, evicted);
+                    Ok(())
+                })
+                .await;
+        }
+    }
+
+    /// Marks the tile at key as used in the disk index, returning the tiles
+    /// which have to be removed from the store.
     ///
-    /// TODO:
-    /// Check if skiping atomic access makes this faster (benchmark??)
-    /// (needs unsafe so didnt do yet)
-    async fn cleanup(&self) {
-        let mut map = self.memcache.write();
+    /// Evicted tiles are also dropped from the memory cache, so every tile
+    /// served from memory is still in the store and touching it keeps it
+    /// there.
+    fn touch(&self, key: TileKey, size: usize) -> Vec<TileKey> {
+        let Some(disk_index) = &self.disk_index else {
+            return Vec::new();
+        };
+
+        let evicted = disk_index.lock().touch(key, size as u64);
+
+        for key in &evicted {
+            self.memcache.remove(key);
+        }
 
-        let data: BTreeMap<u64, &TilePos> = map
+        evicted
+    }
+
+    /// Runs f with the store on a blocking thread.
+    async fn with_store<F, T>(&self, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&dyn TileStore) -> io::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let store = self.store.clone();
+
+        spawn_blocking(move || f(store.as_ref()))
+            .await
+            .expect("accessing the tile store panicked")
+    }
+}
+
+impl<S> CachedLayer for TileCache<S>
+where
+    S: TileProvider + Send + Sync + 'static,
+{
+    fn get_tile_blocking(&self, pos: TilePos) -> Result<DynamicImage, Error> {
+        // Preferably lossless, so the layer isn't degraded by being encoded
+        // again as part of another tile.
+        let format = self
+            .formats
             .iter()
-            .map(|(pos, tile)| (tile.last_access.load(Ordering::Relaxed), pos))
+            .copied()
+            .find(|&format| format != ImageFormat::Jpeg)
+            .unwrap_or(self.default_format());
+        let key = (pos, format);
+
+        let tile = match self.memcache.get(&key) {
+            Some(tile) => tile,
+            None => {
+                let tile = match self.store.get(key).map_err(Error::ReadError)? {
+                    Some(tile) => tile,
+                    None => {
+                        // The calling render already holds a permit, so the
+                        // claim is taken the same way as by get_cached_tile.
+                        let claim = self.claim(key);
+                        let tile = (|| {
+                            let _rendering = claim.blocking_lock();
+
+                            if let Some(tile) = self.store.get(key).map_err(Error::ReadError)? {
+                                return Ok(tile);
+                            }
+
+                            debug!("rendering tile {pos:?} as {format:?} for another layer");
+
+                            let tile = encode(
+                                self.source.get_tile(pos).map_err(Error::ProviderError)?,
+                                format,
+                            )?;
+                            self.store.put(key, &tile).map_err(Error::WriteError)?;
+                            Ok(tile)
+                        })();
+
+                        self.release(key, &claim);
+                        tile?
+                    }
+                };
+
+                let tile = Bytes::from(tile);
+                self.memcache.insert(key, tile.clone());
+                tile
+            }
+        };
+
+        remove_evicted(self.store.as_ref(), self.touch(key, tile.len()));
+
+        image::load_from_memory_with_format(&tile, format).map_err(Error::DecodeError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cubiomes::generator::error::GeneratorError;
+
+    use super::*;
+    use crate::tileprovider::{Counting, tilestore::MemoryStore};
+
+    fn cache(
+        source: Counting,
+        store: Arc<dyn TileStore>,
+        budget: DiskBudget,
+    ) -> TileCache<Counting> {
+        TileCache::new(
+            source,
+            1024 * 1024,
+            vec![ImageFormat::Png],
+            store,
+            RenderPool::new(1),
+            budget,
+        )
+        .unwrap()
+    }
+
+    fn pos(x: i32) -> TilePos {
+        TilePos::new(0, x, 0)
+    }
+
+    #[actix_web::test]
+    async fn rendered_tiles_are_stored() {
+        let source = Counting::default();
+        let store = Arc::new(MemoryStore::new());
+        let cache = cache(source.clone(), store.clone(), DiskBudget::UNLIMITED);
+
+        let tile = cache
+            .get_cached_tile(pos(0), ImageFormat::Png)
+            .await
+            .unwrap();
+
+        assert_eq!(source.renders(), 1);
+        assert_eq!(
+            store.get((pos(0), ImageFormat::Png)).unwrap().as_deref(),
+            Some(&tile[..])
+        );
+    }
+
+    #[actix_web::test]
+    async fn stored_tiles_are_not_rendered_again() {
+        let source = Counting::default();
+        let store: Arc<dyn TileStore> = Arc::new(MemoryStore::new());
+
+        cache(source.clone(), store.clone(), DiskBudget::UNLIMITED)
+            .get_cached_tile(pos(0), ImageFormat::Png)
+            .await
+            .unwrap();
+        cache(source.clone(), store, DiskBudget::UNLIMITED)
+            .get_cached_tile(pos(0), ImageFormat::Png)
+            .await
+            .unwrap();
+
+        assert_eq!(source.renders(), 1);
+    }
+
+    #[actix_web::test]
+    async fn concurrent_requests_render_once() {
+        let source = Counting::default();
+        let cache = Arc::new(
+            TileCache::new(
+                source.clone(),
+                1024 * 1024,
+                vec![ImageFormat::Png],
+                Arc::new(MemoryStore::new()),
+                RenderPool::new(4),
+                DiskBudget::UNLIMITED,
+            )
+            .unwrap(),
+        );
+
+        let requests: Vec<_> = (0..8)
+            .map(|_| {
+                let cache = cache.clone();
+                actix_web::rt::spawn(async move {
+                    cache.get_cached_tile(pos(0), ImageFormat::Png).await
+                })
+            })
             .collect();
 
-        let len = data.len();
-
-        // Since we take less tan len elements, operation will never panic
-        let Some(yield_until) = data.into_keys().nth(len - len / 3) else {
-            // If for some reason all tiles are returned just clear the whole cahe.
-            //
-            // This is such a edge case I'm not gonna deal with it and probably means
-            // incorrect usage anyways, since the cache size must be stupidly low and usage
-            // stupidly hight to generate enough tiles at once. (and if you manage to create
-            // say 5000 tiles in under one second you probably dont care about memory
-            // caching anyways)
-            map.clear();
-            map.shrink_to_fit();
-            return;
-        };
+        for request in requests {
+            request.await.unwrap().unwrap();
+        }
 
-        map.retain(|_, v| yield_until < v.last_access.load(Ordering::Relaxed));
-        map.shrink_to_fit();
+        assert_eq!(source.renders(), 1);
+        assert!(cache.in_flight.lock().is_empty());
     }
 
-    fn generate_tile(&self, pos: TilePos) -> Result<Vec<u8>, Error> {
-        let mut buf = Cursor::new(Vec::new());
-        self.source
-            .get_tile(pos)
-            .ok_or(Error::NoTileInProvider)?
-            .write_to(&mut buf, self.format)
-            .unwrap_or_else(|_| panic!("Writing tile {pos:?} failed"));
-        Ok(buf.into_inner())
+    #[actix_web::test]
+    async fn prerender_skips_stored_tiles() {
+        let cache = cache(
+            Counting::default(),
+            Arc::new(MemoryStore::new()),
+            DiskBudget::UNLIMITED,
+        );
+
+        assert!(cache.prerender(pos(0), ImageFormat::Png).await.unwrap());
+        assert!(!cache.prerender(pos(0), ImageFormat::Png).await.unwrap());
     }
 
-    async fn read_or_gen_tile_fs(&self, pos: TilePos) -> Result<Vec<u8>, Error> {
-        let dir = self.base_path.join(format!("{}/{}/", pos.zoom, pos.x));
+    #[actix_web::test]
+    async fn unsupported_formats_are_rejected() {
+        let source = Counting::default();
+        let cache = cache(
+            source.clone(),
+            Arc::new(MemoryStore::new()),
+            DiskBudget::UNLIMITED,
+        );
+
+        assert!(matches!(
+            cache.get_cached_tile(pos(0), ImageFormat::WebP).await,
+            Err(Error::UnsupportedFormat(ImageFormat::WebP))
+        ));
+        assert_eq!(source.renders(), 0);
+    }
 
-        // We ignore this error, since the directory might already exist
-        let _ = create_dir_all(&dir);
+    /// Fails every tile with its error.
+    struct Failing(ProviderError);
 
-        let path = format!("{}{}.{}", dir.to_str().unwrap(), pos.y, {
-            self.format.extensions_str()[0]
-        });
+    impl TileProvider for Failing {
+        fn get_tile(&self, _pos: TilePos) -> Result<DynamicImage, ProviderError> {
+            Err(self.0.clone())
+        }
+    }
 
-        match read(&path).await {
-            Ok(buf) => Ok(buf),
-            Err(e) => {
-                if e.kind() == ErrorKind::NotFound {
-                    let img = self.generate_tile(pos)?;
-                    write(path, &img).await.map_err(Error::WriteError)?;
-                    Ok(img)
-                } else {
-                    Err(Error::ReadError(e))
-                }
-            }
+    #[actix_web::test]
+    async fn provider_errors_have_distinct_statuses() {
+        let status = async |error| {
+            TileCache::new(
+                Failing(error),
+                1024 * 1024,
+                vec![ImageFormat::Png],
+                Arc::new(MemoryStore::new()),
+                RenderPool::new(1),
+                DiskBudget::UNLIMITED,
+            )
+            .unwrap()
+            .get_cached_tile(pos(0), ImageFormat::Png)
+            .await
+            .unwrap_err()
+            .status_code()
+        };
+
+        assert_eq!(
+            status(ProviderError::ZoomOutOfRange(-9)).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(ProviderError::OutOfBounds(pos(0))).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(ProviderError::GeneratorError(
+                GeneratorError::GetBiomeAtFailure
+            ))
+            .await,
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[actix_web::test]
+    async fn disk_budget_evicts_from_the_store() {
+        let store = Arc::new(MemoryStore::new());
+        let cache = cache(
+            Counting::default(),
+            store.clone(),
+            DiskBudget {
+                max_bytes: None,
+                max_tiles: Some(2),
+                pinned_zoom: -1,
+            },
+        );
+
+        for x in 0..3 {
+            cache.prerender(pos(x), ImageFormat::Png).await.unwrap();
         }
+
+        assert!(!store.exists((pos(0), ImageFormat::Png)).unwrap());
+        assert!(store.exists((pos(1), ImageFormat::Png)).unwrap());
+        assert!(store.exists((pos(2), ImageFormat::Png)).unwrap());
     }
 }
@@ -0,0 +1,469 @@
+//! Where a [TileCache](super::tilecache::TileCache) stores its rendered tiles.
+//!
+//! Stores are blocking, the tile cache calls them on tokio's blocking threads.
+
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fs::{DirEntry, create_dir_all, metadata, read, read_dir, remove_file, rename, write},
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+
+use image::ImageFormat;
+use log::warn;
+use parking_lot::Mutex;
+
+use super::{TileKey, TilePos, kvstore::KvStore};
+
+/// A tile in a [TileStore], as listed by [TileStore::list].
+#[derive(Debug, Clone, Copy)]
+pub struct StoredTile {
+    pub key: TileKey,
+    /// The size of the encoded tile in bytes.
+    pub size: u64,
+    /// When the tile was written, if the store keeps track of it.
+    pub modified: Option<SystemTime>,
+}
+
+/// Storage for encoded tiles, keyed by their position and format.
+pub trait TileStore: Send + Sync {
+    /// Reads the tile at key, or None if it isn't stored.
+    fn get(&self, key: TileKey) -> io::Result<Option<Vec<u8>>>;
+
+    /// Stores tile at key, replacing the tile already there.
+    fn put(&self, key: TileKey, tile: &[u8]) -> io::Result<()>;
+
+    /// Removes the tile at key, if it is stored.
+    fn delete(&self, key: TileKey) -> io::Result<()>;
+
+    /// Every stored tile.
+    fn list(&self) -> io::Result<Vec<StoredTile>>;
+
+    fn exists(&self, key: TileKey) -> io::Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+}
+
+/// The kinds of stores tile caches can be configured to use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileStoreKind {
+    /// A [FsStore] in the directory of the layer.
+    Fs,
+    /// A [KvStore] in a `.redb` file named after the directory of the layer.
+    Kv,
+    /// A [MemoryStore], which loses every tile on restart.
+    Memory,
+}
+
+impl TileStoreKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "fs" => Some(Self::Fs),
+            "kv" => Some(Self::Kv),
+            "memory" => Some(Self::Memory),
+            _ => None,
+        }
+    }
+
+    /// Opens a store of this kind for the layer stored at path.
+    pub fn open(self, path: &Path) -> io::Result<Arc<dyn TileStore>> {
+        Ok(match self {
+            Self::Fs => Arc::new(FsStore::new(path)?),
+            Self::Kv => Arc::new(KvStore::open(&path.with_extension("redb"))?),
+            Self::Memory => Arc::new(MemoryStore::new()),
+        })
+    }
+}
+
+/// Tiles kept in a hashmap, mostly for tests.
+#[derive(Default)]
+pub struct MemoryStore {
+    tiles: Mutex<HashMap<TileKey, Vec<u8>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl TileStore for MemoryStore {
+    fn get(&self, key: TileKey) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.tiles.lock().get(&key).cloned())
+    }
+
+    fn put(&self, key: TileKey, tile: &[u8]) -> io::Result<()> {
+        self.tiles.lock().insert(key, tile.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, key: TileKey) -> io::Result<()> {
+        self.tiles.lock().remove(&key);
+        Ok(())
+    }
+
+    fn list(&self) -> io::Result<Vec<StoredTile>> {
+        Ok(self
+            .tiles
+            .lock()
+            .iter()
+            .map(|(&key, tile)| StoredTile {
+                key,
+                size: tile.len() as u64,
+                modified: None,
+            })
+            .collect())
+    }
+
+    fn exists(&self, key: TileKey) -> io::Result<bool> {
+        Ok(self.tiles.lock().contains_key(&key))
+    }
+}
+
+/// Tiles stored as files in base_path, as `{ext}/{zoom}/{x}/{y}.{ext}`.
+///
+/// Tiles with a scale above 1 are named `{y}@{scale}x.{ext}`. Files are written
+/// atomically, so readers never see a partially written tile.
+pub struct FsStore {
+    base_path: PathBuf,
+}
+
+impl FsStore {
+    /// Creates the store, creating base_path if it doesn't exist.
+    pub fn new(base_path: &Path) -> io::Result<Self> {
+        create_dir_all(base_path)?;
+
+        if has_old_layout(base_path)? {
+            warn!(
+                "{} has tiles stored by an older version as {{z}}/{{x}}/{{y}}.png, they are no \
+                 longer used and can be deleted",
+                base_path.display()
+            );
+        }
+
+        Ok(Self {
+            base_path: base_path.to_owned(),
+        })
+    }
+
+    /// The path of the tile at key.
+    fn tile_path(&self, (pos, format): TileKey) -> PathBuf {
+        let ext = format.extensions_str()[0];
+        let scale = match pos.scale {
+            1 => String::new(),
+            scale => format!("@{scale}x"),
+        };
+
+        self.base_path
+            .join(ext)
+            .join(pos.zoom.to_string())
+            .join(pos.x.to_string())
+            .join(format!("{}{scale}.{ext}", pos.y))
+    }
+}
+
+/// Whether dir has zoom level directories of the layout before tiles were
+/// stored by format.
+fn has_old_layout(dir: &Path) -> io::Result<bool> {
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+
+        if entry.file_type()?.is_dir()
+            && entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.parse::<i32>().is_ok())
+        {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+impl TileStore for FsStore {
+    fn get(&self, key: TileKey) -> io::Result<Option<Vec<u8>>> {
+        match read(self.tile_path(key)) {
+            // Empty files are left behind by crashes of older versions, which
+            // didn't write tiles atomically.
+            Ok(buf) if buf.is_empty() => Ok(None),
+            Ok(buf) => Ok(Some(buf)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn put(&self, key: TileKey, tile: &[u8]) -> io::Result<()> {
+        let path = self.tile_path(key);
+
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir)?;
+        }
+
+        write_atomic(&path, tile)
+    }
+
+    fn delete(&self, key: TileKey) -> io::Result<()> {
+        match remove_file(self.tile_path(key)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn list(&self) -> io::Result<Vec<StoredTile>> {
+        let mut found = Vec::new();
+
+        for format_dir in read_dir(&self.base_path)? {
+            let format_dir = format_dir?;
+            let Some(format) = format_dir
+                .file_name()
+                .to_str()
+                .and_then(ImageFormat::from_extension)
+            else {
+                continue;
+            };
+
+            if format_dir.file_type()?.is_dir() {
+                find_tiles(&format_dir.path(), format, &mut found)?;
+            }
+        }
+
+        Ok(found)
+    }
+
+    fn exists(&self, key: TileKey) -> io::Result<bool> {
+        match metadata(self.tile_path(key)) {
+            Ok(metadata) => Ok(metadata.len() > 0),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Finds the `{zoom}/{x}/{y}.{ext}` tiles of format in dir.
+fn find_tiles(dir: &Path, format: ImageFormat, found: &mut Vec<StoredTile>) -> io::Result<()> {
+    let extension = format!(".{}", format.extensions_str()[0]);
+
+    for zoom_dir in read_dir(dir)? {
+        let zoom_dir = zoom_dir?;
+        let Some(zoom) = parse_name(&zoom_dir.file_name()) else {
+            continue;
+        };
+
+        for x_dir in read_dir(zoom_dir.path())? {
+            let x_dir = x_dir?;
+            let Some(x) = parse_name(&x_dir.file_name()) else {
+                continue;
+            };
+
+            for tile in read_dir(x_dir.path())? {
+                let tile = tile?;
+                let Some((y, scale)) = tile
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| name.strip_suffix(&extension))
+                    .and_then(parse_tile_name)
+                else {
+                    continue;
+                };
+                let metadata = tile.metadata()?;
+
+                found.push(StoredTile {
+                    key: (TilePos::new(zoom, x, y).with_scale(scale), format),
+                    size: metadata.len(),
+                    modified: metadata.modified().ok(),
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Parses a directory name of the form `{number}`.
+fn parse_name(name: &OsStr) -> Option<i32> {
+    name.to_str()?.parse().ok()
+}
+
+/// Parses a tile name without extension, of the form `{y}` or `{y}@{scale}x`.
+pub(super) fn parse_tile_name(name: &str) -> Option<(i32, u32)> {
+    match name.split_once('@') {
+        Some((y, scale)) => Some((
+            y.parse().ok()?,
+            scale
+                .strip_suffix('x')?
+                .parse()
+                .ok()
+                .filter(|scale: &u32| scale.is_power_of_two())?,
+        )),
+        None => Some((name.parse().ok()?, 1)),
+    }
+}
+
+/// Writes to a temporary file next to path and renames it over path, so
+/// readers never see a partially written tile.
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(format!(
+        ".{}-{}{TEMP_FILE_SUFFIX}",
+        process::id(),
+        TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    if let Err(e) = write(&temp_path, data) {
+        let _ = remove_file(&temp_path);
+        return Err(e);
+    }
+
+    if let Err(e) = rename(&temp_path, path) {
+        let _ = remove_file(&temp_path);
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+const TEMP_FILE_SUFFIX: &str = ".tmp";
+
+/// Temporary files younger than this might still be written to, so they are
+/// not removed by [remove_corrupt_tiles].
+const TEMP_FILE_MAX_AGE: Duration = Duration::from_secs(60);
+
+/// Walks dir and removes every tile which is empty, or can't be decoded if
+/// decode is set, along with temporary files left behind by interrupted writes.
+///
+/// Removed tiles are rendered again when next requested. This is blocking, and
+/// with decode reads every tile, so it should be run on a blocking thread.
+/// Files which are moved or removed while walking are skipped. Returns the
+/// amount of removed files.
+pub fn remove_corrupt_tiles(dir: &Path, decode: bool) -> io::Result<usize> {
+    let mut removed = 0;
+
+    for entry in read_dir(dir)? {
+        match check_entry(entry, decode) {
+            Ok(count) => removed += count,
+            // Renamed or evicted by a concurrent write.
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Removes entry if it's corrupt, or the corrupt tiles in it if it's a
+/// directory. Returns the amount of removed files.
+fn check_entry(entry: io::Result<DirEntry>, decode: bool) -> io::Result<usize> {
+    let entry = entry?;
+    let path = entry.path();
+    let metadata = entry.metadata()?;
+
+    if metadata.is_dir() {
+        return remove_corrupt_tiles(&path, decode);
+    }
+
+    let is_corrupt = if path.to_string_lossy().ends_with(TEMP_FILE_SUFFIX) {
+        metadata
+            .modified()?
+            .elapsed()
+            .is_ok_and(|age| age > TEMP_FILE_MAX_AGE)
+    } else if let Ok(format) = ImageFormat::from_path(&path) {
+        metadata.len() == 0
+            || decode
+                && read(&path)
+                    .map(|buf| image::load_from_memory_with_format(&buf, format).is_err())?
+    } else {
+        false
+    };
+
+    if !is_corrupt {
+        return Ok(0);
+    }
+
+    warn!("removing corrupt tile {}", path.display());
+    remove_file(&path)?;
+
+    Ok(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tileprovider::TestDir;
+
+    #[test]
+    fn integrity_check_only_decodes_if_asked() {
+        let dir = TestDir::new("integrity-check");
+        let tiles = dir.0.join("png/0/0");
+        create_dir_all(&tiles).unwrap();
+        write(tiles.join("0.png"), b"").unwrap();
+        write(tiles.join("1.png"), b"not a png").unwrap();
+
+        assert_eq!(remove_corrupt_tiles(&dir.0, false).unwrap(), 1);
+        assert!(tiles.join("1.png").exists());
+
+        assert_eq!(remove_corrupt_tiles(&dir.0, true).unwrap(), 1);
+        assert!(!tiles.join("1.png").exists());
+    }
+
+    #[test]
+    fn fs_store_writes_atomically() {
+        let dir = TestDir::new("fs-store");
+        let store = FsStore::new(&dir.0).unwrap();
+        let key = (TilePos::new(0, 1, -2).with_scale(2), ImageFormat::Png);
+
+        store.put(key, b"old").unwrap();
+        store.put(key, b"new").unwrap();
+
+        assert_eq!(store.get(key).unwrap().as_deref(), Some(&b"new"[..]));
+        assert!(dir.0.join("png/0/1/-2@2x.png").exists());
+
+        // Nothing but the tile is left in its directory.
+        let files: Vec<_> = read_dir(dir.0.join("png/0/1"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(files, ["-2@2x.png"]);
+
+        let listed = store.list().unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].key, key);
+    }
+
+    #[test]
+    fn fs_store_treats_empty_files_as_missing() {
+        let dir = TestDir::new("fs-store-empty");
+        let store = FsStore::new(&dir.0).unwrap();
+        let key = (TilePos::new(0, 0, 0), ImageFormat::Png);
+
+        create_dir_all(dir.0.join("png/0/0")).unwrap();
+        write(dir.0.join("png/0/0/0.png"), b"").unwrap();
+
+        assert_eq!(store.get(key).unwrap(), None);
+        assert!(!store.exists(key).unwrap());
+    }
+
+    #[test]
+    fn old_layouts_are_detected() {
+        let dir = TestDir::new("fs-store-old-layout");
+        let store = FsStore::new(&dir.0).unwrap();
+
+        store
+            .put((TilePos::new(-3, 0, 0), ImageFormat::Png), b"new")
+            .unwrap();
+        assert!(!has_old_layout(&dir.0).unwrap());
+
+        create_dir_all(dir.0.join("-3/0")).unwrap();
+        write(dir.0.join("-3/0/0.png"), b"old").unwrap();
+        assert!(has_old_layout(&dir.0).unwrap());
+    }
+}
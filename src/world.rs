@@ -0,0 +1,773 @@
+//! Registry of the worlds served by the tileserver.
+//!
+//! Every world has a generator and [CachePool] per dimension and its own
+//! [TileCache]s, which store their tiles under `<tile_dir>/<world name>/`. The
+//! nether and end layers are in the `the_nether/` and `the_end/`
+//! subdirectories, the structure overlays in `structures/` and `landmarks/` and
+//! the composites of the config in `composite/<name>-<hash of the layers>/`.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+};
+
+use actix_web::web::Bytes;
+use cubiomes::{
+    enums::{Dimension, MCVersion},
+    generator::{Generator, GeneratorFlags},
+};
+use image::ImageFormat;
+use log::warn;
+use lru::LruCache;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::{sync::OnceCell, task::spawn_blocking};
+
+use crate::{
+    biomemap::{CachePool, ContourLines, NetherBiomeTile, ShadedBiomeTile, UnshadedBiomeTile},
+    config::{Config, WorldConfig},
+    structures::{LandmarkOverlay, StructureFinder, StructureOverlay},
+    tileprovider::{
+        NoTiles, TilePos, TileProvider,
+        composite::CompositeTile,
+        diskindex::DiskBudget,
+        mbtiles::MbTiles,
+        pmtiles::PmTiles,
+        tilecache::{self, CachedLayer, RenderPool, TileCache},
+        tilestore::TileStore,
+    },
+};
+
+/// The layers of a dimension with a surface (the overworld and the end).
+pub struct SurfaceLayers {
+    pub shaded: Arc<TileCache<ShadedBiomeTile<'static>>>,
+    pub unshaded: Arc<TileCache<UnshadedBiomeTile<'static>>>,
+    pub contours: Arc<TileCache<ContourLines<'static>>>,
+}
+
+impl SurfaceLayers {
+    fn new(
+        cache_pool: CachePool<'static>,
+        dir: &Path,
+        memory_cache_bytes: usize,
+        config: &Config,
+        render_pool: &RenderPool,
+    ) -> Result<Self, tilecache::Error> {
+        Ok(Self {
+            shaded: Arc::new(TileCache::new(
+                ShadedBiomeTile::from(cache_pool.clone()),
+                memory_cache_bytes,
+                config.base_formats(),
+                open_store(config, &dir.join("shaded"))?,
+                render_pool.clone(),
+                config.disk_budget,
+            )?),
+            unshaded: Arc::new(TileCache::new(
+                UnshadedBiomeTile::from(cache_pool.clone()),
+                memory_cache_bytes,
+                config.base_formats(),
+                open_store(config, &dir.join("unshaded"))?,
+                render_pool.clone(),
+                config.disk_budget,
+            )?),
+            contours: Arc::new(TileCache::new(
+                ContourLines::from(cache_pool),
+                memory_cache_bytes,
+                config.overlay_formats(),
+                open_store(config, &dir.join("contour"))?,
+                render_pool.clone(),
+                config.disk_budget,
+            )?),
+        })
+    }
+
+    /// The layer named like in tile urls, without the dimension.
+    pub fn layer(&self, name: &str) -> Option<Layer<'_>> {
+        match name {
+            "biomemap" => Some(Layer::Unshaded(&self.unshaded)),
+            "biomemap_shaded" => Some(Layer::Shaded(&self.shaded)),
+            "contours" => Some(Layer::Contours(&self.contours)),
+            _ => None,
+        }
+    }
+
+    /// The total size in bytes of the tiles in the memory caches.
+    pub fn memory_usage(&self) -> usize {
+        self.shaded.memory_usage() + self.unshaded.memory_usage() + self.contours.memory_usage()
+    }
+}
+
+/// The tile cache of any layer of a world, for code which handles every layer
+/// the same way.
+#[derive(Clone, Copy)]
+pub enum Layer<'a> {
+    Shaded(&'a Arc<TileCache<ShadedBiomeTile<'static>>>),
+    Unshaded(&'a Arc<TileCache<UnshadedBiomeTile<'static>>>),
+    Contours(&'a Arc<TileCache<ContourLines<'static>>>),
+    Nether(&'a Arc<TileCache<NetherBiomeTile<'static>>>),
+    Structures(&'a Arc<TileCache<StructureOverlay>>),
+    Landmarks(&'a Arc<TileCache<LandmarkOverlay>>),
+    Composite(&'a Arc<TileCache<CompositeTile>>),
+}
+
+impl Layer<'_> {
+    /// See [TileCache::get_cached_tile].
+    pub async fn get_cached_tile(
+        &self,
+        pos: TilePos,
+        format: ImageFormat,
+    ) -> Result<Bytes, tilecache::Error> {
+        match self {
+            Layer::Shaded(cache) => cache.get_cached_tile(pos, format).await,
+            Layer::Unshaded(cache) => cache.get_cached_tile(pos, format).await,
+            Layer::Contours(cache) => cache.get_cached_tile(pos, format).await,
+            Layer::Nether(cache) => cache.get_cached_tile(pos, format).await,
+            Layer::Structures(cache) => cache.get_cached_tile(pos, format).await,
+            Layer::Landmarks(cache) => cache.get_cached_tile(pos, format).await,
+            Layer::Composite(cache) => cache.get_cached_tile(pos, format).await,
+        }
+    }
+
+    /// Like [Layer::get_cached_tile], but the future doesn't borrow the layer,
+    /// so it can be spawned as a task of its own.
+    pub fn fetch_tile(&self, pos: TilePos, format: ImageFormat) -> TileFuture {
+        match self {
+            Layer::Shaded(cache) => fetch_tile(cache, pos, format),
+            Layer::Unshaded(cache) => fetch_tile(cache, pos, format),
+            Layer::Contours(cache) => fetch_tile(cache, pos, format),
+            Layer::Nether(cache) => fetch_tile(cache, pos, format),
+            Layer::Structures(cache) => fetch_tile(cache, pos, format),
+            Layer::Landmarks(cache) => fetch_tile(cache, pos, format),
+            Layer::Composite(cache) => fetch_tile(cache, pos, format),
+        }
+    }
+
+    /// See [TileCache::prerender].
+    pub async fn prerender(
+        &self,
+        pos: TilePos,
+        format: ImageFormat,
+    ) -> Result<bool, tilecache::Error> {
+        match self {
+            Layer::Shaded(cache) => cache.prerender(pos, format).await,
+            Layer::Unshaded(cache) => cache.prerender(pos, format).await,
+            Layer::Contours(cache) => cache.prerender(pos, format).await,
+            Layer::Nether(cache) => cache.prerender(pos, format).await,
+            Layer::Structures(cache) => cache.prerender(pos, format).await,
+            Layer::Landmarks(cache) => cache.prerender(pos, format).await,
+            Layer::Composite(cache) => cache.prerender(pos, format).await,
+        }
+    }
+
+    /// See [TileCache::get_stored_tile].
+    pub async fn get_stored_tile(
+        &self,
+        pos: TilePos,
+        format: ImageFormat,
+    ) -> Result<Vec<u8>, tilecache::Error> {
+        match self {
+            Layer::Shaded(cache) => cache.get_stored_tile(pos, format).await,
+            Layer::Unshaded(cache) => cache.get_stored_tile(pos, format).await,
+            Layer::Contours(cache) => cache.get_stored_tile(pos, format).await,
+            Layer::Nether(cache) => cache.get_stored_tile(pos, format).await,
+            Layer::Structures(cache) => cache.get_stored_tile(pos, format).await,
+            Layer::Landmarks(cache) => cache.get_stored_tile(pos, format).await,
+            Layer::Composite(cache) => cache.get_stored_tile(pos, format).await,
+        }
+    }
+
+    /// Whether the tiles of the layer are transparent, to be drawn on top of
+    /// other layers.
+    pub fn is_overlay(&self) -> bool {
+        match self {
+            Layer::Shaded(_) | Layer::Unshaded(_) | Layer::Nether(_) => false,
+            Layer::Contours(_) | Layer::Structures(_) | Layer::Landmarks(_) => true,
+            Layer::Composite(cache) => cache.source().is_overlay(),
+        }
+    }
+
+    pub fn default_format(&self) -> ImageFormat {
+        match self {
+            Layer::Shaded(cache) => cache.default_format(),
+            Layer::Unshaded(cache) => cache.default_format(),
+            Layer::Contours(cache) => cache.default_format(),
+            Layer::Nether(cache) => cache.default_format(),
+            Layer::Structures(cache) => cache.default_format(),
+            Layer::Landmarks(cache) => cache.default_format(),
+            Layer::Composite(cache) => cache.default_format(),
+        }
+    }
+
+    /// The cache of the layer, for drawing its tiles into other layers.
+    pub fn cached(&self) -> Arc<dyn CachedLayer> {
+        match self {
+            Layer::Shaded(cache) => Arc::clone(cache) as _,
+            Layer::Unshaded(cache) => Arc::clone(cache) as _,
+            Layer::Contours(cache) => Arc::clone(cache) as _,
+            Layer::Nether(cache) => Arc::clone(cache) as _,
+            Layer::Structures(cache) => Arc::clone(cache) as _,
+            Layer::Landmarks(cache) => Arc::clone(cache) as _,
+            Layer::Composite(cache) => Arc::clone(cache) as _,
+        }
+    }
+
+    pub fn supports(&self, format: ImageFormat) -> bool {
+        match self {
+            Layer::Shaded(cache) => cache.supports(format),
+            Layer::Unshaded(cache) => cache.supports(format),
+            Layer::Contours(cache) => cache.supports(format),
+            Layer::Nether(cache) => cache.supports(format),
+            Layer::Structures(cache) => cache.supports(format),
+            Layer::Landmarks(cache) => cache.supports(format),
+            Layer::Composite(cache) => cache.supports(format),
+        }
+    }
+}
+
+/// A tile of a layer, see [Layer::fetch_tile].
+pub type TileFuture = Pin<Box<dyn Future<Output = Result<Bytes, tilecache::Error>> + Send>>;
+
+fn fetch_tile<S>(cache: &Arc<TileCache<S>>, pos: TilePos, format: ImageFormat) -> TileFuture
+where
+    S: TileProvider + Send + Sync + 'static,
+{
+    let cache = cache.clone();
+
+    Box::pin(async move { cache.get_cached_tile(pos, format).await })
+}
+
+pub struct World {
+    name: String,
+    seed: i64,
+    mc_version: MCVersion,
+    pub overworld: SurfaceLayers,
+    /// Nether biome layers keyed by the y level they are rendered at.
+    ///
+    /// Empty for versions before 1.16, which have no nether biomes.
+    pub nether: BTreeMap<i32, Arc<TileCache<NetherBiomeTile<'static>>>>,
+    /// None for versions before 1.9, which have no outer end islands.
+    pub end: Option<SurfaceLayers>,
+    /// Structure icons of the overworld.
+    pub structures: Arc<TileCache<StructureOverlay>>,
+    /// The world spawn and strongholds of the overworld.
+    pub landmarks: Arc<TileCache<LandmarkOverlay>>,
+    /// The composites of the config keyed by their name, except for those
+    /// with layers the world doesn't have.
+    pub composites: BTreeMap<String, Arc<TileCache<CompositeTile>>>,
+    structure_finder: Arc<StructureFinder>,
+    // The generators of the dimensions, for lookups which aren't tiles. Only
+    // set for dimensions which have layers.
+    overworld_pool: CachePool<'static>,
+    nether_pool: Option<CachePool<'static>>,
+    end_pool: Option<CachePool<'static>>,
+}
+
+/// Metadata of a world, as sent to the frontend.
+#[derive(Serialize, Debug, Clone)]
+pub struct WorldInfo {
+    pub name: String,
+    /// A string, since javascript numbers can't hold every i64.
+    pub seed: String,
+    pub mc_version: String,
+    pub nether_y_levels: Vec<i32>,
+    pub has_end: bool,
+    pub composites: Vec<String>,
+}
+
+impl World {
+    /// Creates the world and its tile caches.
+    ///
+    /// The generators are leaked, since worlds live until the server shuts
+    /// down.
+    pub fn new(
+        world: &WorldConfig,
+        config: &Config,
+        render_pool: &RenderPool,
+    ) -> Result<Self, tilecache::Error> {
+        let cache_pool = |dimension| {
+            CachePool::new(Box::leak(Box::new(Generator::new(
+                world.mc_version,
+                world.seed,
+                dimension,
+                GeneratorFlags::empty(),
+            ))))
+        };
+        let tile_dir = config.tile_dir.join(&world.name);
+
+        let nether_pool =
+            (world.mc_version >= MCVersion::MC_1_16).then(|| cache_pool(Dimension::DIM_NETHER));
+        let end_pool =
+            (world.mc_version >= MCVersion::MC_1_9).then(|| cache_pool(Dimension::DIM_END));
+        let overworld_pool = cache_pool(Dimension::DIM_OVERWORLD);
+
+        let nether = if let Some(nether_pool) = &nether_pool {
+            world
+                .nether_y_levels
+                .iter()
+                .map(|&y_level| {
+                    Ok((
+                        y_level,
+                        Arc::new(TileCache::new(
+                            NetherBiomeTile::new(nether_pool.clone(), y_level),
+                            config.memory_cache_bytes,
+                            config.base_formats(),
+                            open_store(config, &tile_dir.join(format!("the_nether/y{y_level}")))?,
+                            render_pool.clone(),
+                            config.disk_budget,
+                        )?),
+                    ))
+                })
+                .collect::<Result<_, _>>()?
+        } else {
+            BTreeMap::new()
+        };
+
+        let end = if let Some(end_pool) = &end_pool {
+            Some(SurfaceLayers::new(
+                end_pool.clone(),
+                &tile_dir.join("the_end"),
+                config.memory_cache_bytes,
+                config,
+                render_pool,
+            )?)
+        } else {
+            None
+        };
+
+        let structure_finder = Arc::new(StructureFinder::new(world.mc_version, world.seed));
+
+        let mut world = Self {
+            name: world.name.clone(),
+            seed: world.seed,
+            mc_version: world.mc_version,
+            overworld: SurfaceLayers::new(
+                overworld_pool.clone(),
+                &tile_dir,
+                config.memory_cache_bytes,
+                config,
+                render_pool,
+            )?,
+            nether,
+            end,
+            structures: Arc::new(TileCache::new(
+                StructureOverlay(structure_finder.clone()),
+                config.memory_cache_bytes,
+                config.overlay_formats(),
+                open_store(config, &tile_dir.join("structures"))?,
+                render_pool.clone(),
+                config.disk_budget,
+            )?),
+            landmarks: Arc::new(TileCache::new(
+                LandmarkOverlay(structure_finder.clone()),
+                config.memory_cache_bytes,
+                config.overlay_formats(),
+                open_store(config, &tile_dir.join("landmarks"))?,
+                render_pool.clone(),
+                config.disk_budget,
+            )?),
+            composites: BTreeMap::new(),
+            structure_finder,
+            overworld_pool,
+            nether_pool,
+            end_pool,
+        };
+
+        // Composites can be made of other composites, so they are built once
+        // all their layers are.
+        let mut pending: Vec<_> = config.composites.iter().collect();
+
+        while !pending.is_empty() {
+            let before = pending.len();
+            let mut unresolved = Vec::new();
+
+            for (name, layer_names) in pending {
+                let Some(layers) = layer_names
+                    .iter()
+                    .map(|layer| world.layer(layer))
+                    .collect::<Option<Vec<_>>>()
+                else {
+                    unresolved.push((name, layer_names));
+                    continue;
+                };
+
+                // Composites are only transparent if their bottom layer is.
+                let overlay = layers[0].is_overlay();
+                let formats = if overlay {
+                    config.overlay_formats()
+                } else {
+                    config.base_formats()
+                };
+                let store_dir = tile_dir
+                    .join("composite")
+                    .join(format!("{name}-{:016x}", layers_hash(layer_names)));
+
+                let cache = Arc::new(TileCache::new(
+                    CompositeTile::new(layers.iter().map(Layer::cached).collect(), overlay),
+                    config.memory_cache_bytes,
+                    formats,
+                    open_store(config, &store_dir)?,
+                    render_pool.clone(),
+                    config.disk_budget,
+                )?);
+                world.composites.insert(name.clone(), cache);
+            }
+
+            if unresolved.len() == before {
+                for (name, _) in unresolved {
+                    warn!("world {} lacks layers of composite {name}", world.name);
+                }
+
+                break;
+            }
+
+            pending = unresolved;
+        }
+
+        Ok(world)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn seed(&self) -> i64 {
+        self.seed
+    }
+
+    pub fn mc_version(&self) -> MCVersion {
+        self.mc_version
+    }
+
+    /// The generator of dimension, if the world has layers of it.
+    pub fn cache_pool(&self, dimension: Dimension) -> Option<&CachePool<'static>> {
+        match dimension {
+            Dimension::DIM_OVERWORLD => Some(&self.overworld_pool),
+            Dimension::DIM_NETHER => self.nether_pool.as_ref(),
+            Dimension::DIM_END => self.end_pool.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn structure_finder(&self) -> &Arc<StructureFinder> {
+        &self.structure_finder
+    }
+
+    pub fn info(&self) -> WorldInfo {
+        WorldInfo {
+            name: self.name.clone(),
+            seed: self.seed.to_string(),
+            mc_version: self.mc_version.to_string(),
+            nether_y_levels: self.nether.keys().copied().collect(),
+            has_end: self.end.is_some(),
+            composites: self.composites.keys().cloned().collect(),
+        }
+    }
+
+    /// The layer named like in tile urls, eg. `biomemap`, `structures`,
+    /// `the_end/contours`, `the_nether/64/biomemap` or `composite/<name>`.
+    pub fn layer(&self, name: &str) -> Option<Layer<'_>> {
+        match name.split('/').collect::<Vec<_>>()[..] {
+            ["the_nether", y_level, "biomemap"] => {
+                self.nether.get(&y_level.parse().ok()?).map(Layer::Nether)
+            }
+            ["the_end", layer] => self.end.as_ref()?.layer(layer),
+            ["structures"] => Some(Layer::Structures(&self.structures)),
+            ["landmarks"] => Some(Layer::Landmarks(&self.landmarks)),
+            ["composite", name] => self.composites.get(name).map(Layer::Composite),
+            [layer] => self.overworld.layer(layer),
+            _ => None,
+        }
+    }
+
+    /// The total size in bytes of the tiles in the memory caches of every
+    /// dimension.
+    pub fn memory_usage(&self) -> usize {
+        self.overworld.memory_usage()
+            + self
+                .nether
+                .values()
+                .map(|cache| cache.memory_usage())
+                .sum::<usize>()
+            + self.end.as_ref().map_or(0, SurfaceLayers::memory_usage)
+            + self.structures.memory_usage()
+            + self.landmarks.memory_usage()
+            + self
+                .composites
+                .values()
+                .map(|cache| cache.memory_usage())
+                .sum::<usize>()
+    }
+}
+
+/// A hash of the layers of a composite, which is stable across versions so
+/// composites are stored apart from the tiles of their older layers.
+fn layers_hash(layers: &[String]) -> u64 {
+    // 64 bit FNV-1a
+    layers
+        .join("+")
+        .bytes()
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+/// All worlds of the server keyed by their name.
+pub struct WorldRegistry {
+    worlds: BTreeMap<String, World>,
+}
+
+impl WorldRegistry {
+    /// Creates every world defined in config.
+    pub fn new(config: &Config, render_pool: &RenderPool) -> Result<Self, tilecache::Error> {
+        let worlds = config
+            .worlds
+            .iter()
+            .map(|world| Ok((world.name.clone(), World::new(world, config, render_pool)?)))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { worlds })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&World> {
+        self.worlds.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &World> {
+        self.worlds.values()
+    }
+}
+
+/// Layers served straight from the MBTiles files of the config, keyed by their
+/// name.
+pub struct MbTilesRegistry {
+    layers: BTreeMap<String, TileCache<NoTiles>>,
+}
+
+impl MbTilesRegistry {
+    /// Opens every MBTiles file defined in config.
+    pub fn new(config: &Config, render_pool: &RenderPool) -> Result<Self, tilecache::Error> {
+        let layers = config
+            .mbtiles
+            .iter()
+            .map(|(name, path)| {
+                let mbtiles = MbTiles::open(path)
+                    .map_err(|e| tilecache::Error::ReadError(io::Error::other(e)))?;
+
+                Ok((
+                    name.clone(),
+                    TileCache::new(
+                        NoTiles,
+                        config.memory_cache_bytes,
+                        vec![mbtiles.format()],
+                        Arc::new(mbtiles),
+                        render_pool.clone(),
+                        DiskBudget::UNLIMITED,
+                    )?,
+                ))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { layers })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&TileCache<NoTiles>> {
+        self.layers.get(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.layers.keys().map(String::as_str)
+    }
+}
+
+/// A PMTiles archive of the config.
+pub struct PmTilesArchive {
+    pub path: PathBuf,
+    pub tiles: TileCache<NoTiles>,
+}
+
+/// The PMTiles archives of the config, keyed by their name.
+pub struct PmTilesRegistry {
+    archives: BTreeMap<String, PmTilesArchive>,
+}
+
+impl PmTilesRegistry {
+    /// Opens every PMTiles archive defined in config.
+    pub fn new(config: &Config, render_pool: &RenderPool) -> Result<Self, tilecache::Error> {
+        let archives = config
+            .pmtiles
+            .iter()
+            .map(|(name, path)| {
+                let pmtiles = PmTiles::open(path).map_err(tilecache::Error::ReadError)?;
+
+                Ok((
+                    name.clone(),
+                    PmTilesArchive {
+                        path: path.clone(),
+                        tiles: TileCache::new(
+                            NoTiles,
+                            config.memory_cache_bytes,
+                            vec![pmtiles.format()],
+                            Arc::new(pmtiles),
+                            render_pool.clone(),
+                            DiskBudget::UNLIMITED,
+                        )?,
+                    },
+                ))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { archives })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&PmTilesArchive> {
+        self.archives.get(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.archives.keys().map(String::as_str)
+    }
+}
+
+/// An overworld created on demand for a seed which isn't configured.
+///
+/// Unlike [World] this owns its generator, so it is freed once evicted from
+/// [SeedWorlds] and no longer used by any request.
+pub struct SeedWorld {
+    layers: SurfaceLayers,
+}
+
+impl SeedWorld {
+    fn new(
+        seed: i64,
+        mc_version: MCVersion,
+        dir: &Path,
+        memory_cache_bytes: usize,
+        config: &Config,
+        render_pool: &RenderPool,
+    ) -> Result<Self, tilecache::Error> {
+        let cache_pool = CachePool::new_owned(Generator::new(
+            mc_version,
+            seed,
+            Dimension::DIM_OVERWORLD,
+            GeneratorFlags::empty(),
+        ));
+
+        Ok(Self {
+            layers: SurfaceLayers::new(cache_pool, dir, memory_cache_bytes, config, render_pool)?,
+        })
+    }
+
+    pub fn layers(&self) -> &SurfaceLayers {
+        &self.layers
+    }
+}
+
+/// A seed world which is created once it is first requested.
+type SeedWorldCell = OnceCell<Arc<SeedWorld>>;
+
+/// A bounded lru of worlds for arbitrary seeds, created as they are requested.
+///
+/// Tiles are stored under `<tile_dir>/seeds/<mc version>/<seed>/`.
+pub struct SeedWorlds {
+    // Worlds are created in their cell outside of the lock, so a seed which
+    // is being created doesn't block lookups of other seeds.
+    live: Mutex<LruCache<(i64, MCVersion), Arc<SeedWorldCell>>>,
+    tile_dir: PathBuf,
+    // Split between all live seeds, so memory use doesn't grow with
+    // max_live_seeds.
+    memory_cache_bytes: usize,
+    config: Config,
+    render_pool: RenderPool,
+}
+
+impl SeedWorlds {
+    pub fn new(config: &Config, render_pool: &RenderPool) -> Self {
+        Self {
+            live: Mutex::new(LruCache::new(
+                NonZeroUsize::new(config.max_live_seeds).expect("max_live_seeds is validated"),
+            )),
+            tile_dir: config.tile_dir.join("seeds"),
+            memory_cache_bytes: (config.memory_cache_bytes / config.max_live_seeds).max(1),
+            config: config.clone(),
+            render_pool: render_pool.clone(),
+        }
+    }
+
+    /// Gets the world of seed, creating it on a blocking thread (and evicting
+    /// the least recently used one) if it isn't live.
+    ///
+    /// Concurrent requests for a seed which isn't live wait for a single
+    /// world to be created.
+    pub async fn get(
+        &self,
+        seed: i64,
+        mc_version: MCVersion,
+    ) -> Result<Arc<SeedWorld>, tilecache::Error> {
+        let cell = self
+            .live
+            .lock()
+            .get_or_insert((seed, mc_version), Default::default)
+            .clone();
+
+        cell.get_or_try_init(|| async {
+            let dir = self
+                .tile_dir
+                .join(mc_version.to_string())
+                .join(seed.to_string());
+            let memory_cache_bytes = self.memory_cache_bytes;
+            let config = self.config.clone();
+            let render_pool = self.render_pool.clone();
+
+            spawn_blocking(move || {
+                SeedWorld::new(
+                    seed,
+                    mc_version,
+                    &dir,
+                    memory_cache_bytes,
+                    &config,
+                    &render_pool,
+                )
+            })
+            .await
+            .expect("creating the seed world panicked")
+            .map(Arc::new)
+        })
+        .await
+        .cloned()
+    }
+
+    /// The total size in bytes of the tiles in the memory caches of the live
+    /// seeds.
+    pub fn memory_usage(&self) -> usize {
+        self.live
+            .lock()
+            .iter()
+            .filter_map(|(_, world)| world.get())
+            .map(|world| world.layers.memory_usage())
+            .sum()
+    }
+}
+
+/// Opens the store of the layer in dir, of the kind set in config.
+fn open_store(config: &Config, dir: &Path) -> Result<Arc<dyn TileStore>, tilecache::Error> {
+    config
+        .tile_store
+        .open(dir)
+        .map_err(tilecache::Error::CreateDirError)
+}
+
+/// Parses a seed the same way minecraft does.
+///
+/// Numbers are used as is, anything else is hashed with java's
+/// `String.hashCode`.
+pub fn parse_seed(seed: &str) -> i64 {
+    seed.parse().unwrap_or_else(|_| {
+        seed.encode_utf16().fold(0_i32, |hash, c| {
+            hash.wrapping_mul(31).wrapping_add(c as i32)
+        }) as i64
+    })
+}